@@ -1,6 +1,7 @@
 // Copyright 2019-2020 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
+use core::convert::TryFrom;
 use num_enum::TryFromPrimitive;
 use crate::{hal, dap};
 
@@ -19,6 +20,92 @@ pub enum Mode {
     FPGA = 2,
 }
 
+/// Interpretation of data received on the SPI bulk endpoint
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u16)]
+pub enum Framing {
+    /// Data is transmitted directly and the received data returned
+    Raw = 0,
+    /// Data is a sequence of `BatchOp`s to execute
+    Batch = 1,
+}
+
+/// Operations which may be sent in a batch packet when framing is `Batch`.
+///
+/// Each operation is an opcode followed by its arguments. Any data received
+/// by `Transfer` and `Read` operations is returned in a single reply packet,
+/// so the total received length of each batch packet must not exceed 64 bytes.
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+enum BatchOp {
+    /// Assert CS. No arguments.
+    Select = 1,
+    /// Deassert CS. No arguments.
+    Unselect = 2,
+    /// Exchange data. Arguments: length, then `length` bytes to transmit.
+    Transfer = 3,
+    /// Clock out a fill byte. Arguments: length, fill byte.
+    Read = 4,
+    /// Busy-wait. Arguments: delay in microseconds as u16 LE.
+    Delay = 5,
+    /// Change SPI mode. Arguments: `Mode`, which must be Flash or FPGA.
+    SetMode = 6,
+    /// Load data into the page buffer. Arguments: offset, length, then data.
+    PageLoad = 7,
+    /// Program the page buffer to flash, then wait for completion.
+    /// Arguments: 24-bit address (BE), length (where 0 means 256).
+    /// Replies with a `FlashStatus` byte.
+    PageProgram = 8,
+    /// Wait for the flash busy flag to clear. No arguments.
+    /// Replies with a `FlashStatus` byte.
+    WaitBusy = 9,
+    /// Read a region of flash and compute its CRC-32.
+    /// Arguments: 24-bit address (BE), 24-bit length (BE).
+    /// Replies with the CRC-32 as u32 LE.
+    CRC32 = 10,
+}
+
+/// Result of an on-device flash operation
+#[derive(Copy, Clone)]
+#[repr(u8)]
+enum FlashStatus {
+    Ok = 0,
+    Timeout = 1,
+}
+
+/// SPI flash commands used by on-device flash operations
+#[derive(Copy, Clone)]
+#[repr(u8)]
+enum FlashCommand {
+    WriteEnable = 0x06,
+    ReadStatusRegister1 = 0x05,
+    PageProgram = 0x02,
+    FastRead = 0x0B,
+}
+
+/// Optional firmware features, reported to the host as a bitmask
+/// in response to a GetCapabilities request.
+#[derive(Copy, Clone)]
+#[repr(u16)]
+pub enum Capability {
+    SPIRead = 1 << 0,
+    Batch = 1 << 1,
+    PageProgram = 1 << 2,
+    CRC32 = 1 << 3,
+}
+
+/// All capabilities supported by this firmware
+const CAPABILITIES: u16 =
+    Capability::SPIRead as u16 | Capability::Batch as u16 |
+    Capability::PageProgram as u16 | Capability::CRC32 as u16;
+
+/// Number of 10µs status polls to wait for a page program to complete
+const PAGE_PROGRAM_POLLS: u32 = 2_000;
+
+/// Number of 10µs status polls for each WaitBusy operation, which is kept
+/// well below the host's USB timeout so the host may simply retry
+const WAIT_BUSY_POLLS: u32 = 5_000;
+
 #[derive(Copy, Clone)]
 pub enum Request {
     SetCS(PinState),
@@ -26,10 +113,14 @@ pub enum Request {
     SetTPwr(PinState),
     SetLED(PinState),
     SetMode(Mode),
+    SetFraming(Framing),
     GetTPwr,
+    GetCapabilities,
     Bootload,
     Suspend,
+    USBReset,
     SPITransmit(([u8; 64], usize)),
+    SPIRead((u32, u8)),
     DAP1Command(([u8; 64], usize)),
     DAP2Command(([u8; 64], usize)),
 }
@@ -41,18 +132,32 @@ pub struct App<'a> {
     dma: &'a hal::dma::DMA,
     pins: &'a hal::gpio::Pins<'a>,
     spi: &'a hal::spi::SPI,
+    crc: &'a hal::crc::CRC,
     usb: &'a mut hal::usb::USB,
     dap: &'a mut dap::DAP<'a>,
+
+    // Number of bytes left to clock out for the current streaming read
+    spi_read_remaining: u32,
+    spi_read_fill: u8,
+
+    framing: Framing,
+
+    // Page data for on-device flash programming
+    page_buf: [u8; 256],
 }
 
 impl<'a> App<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(flash: &'a hal::flash::Flash, rcc: &'a hal::rcc::RCC,
                nvic: &'a hal::nvic::NVIC, dma: &'a hal::dma::DMA,
                pins: &'a hal::gpio::Pins<'a>, spi: &'a hal::spi::SPI,
-               usb: &'a mut hal::usb::USB, dap: &'a mut dap::DAP<'a>) -> Self
+               crc: &'a hal::crc::CRC, usb: &'a mut hal::usb::USB,
+               dap: &'a mut dap::DAP<'a>) -> Self
     {
         App {
-            flash, rcc, nvic, dma, pins, spi, usb, dap,
+            flash, rcc, nvic, dma, pins, spi, crc, usb, dap,
+            spi_read_remaining: 0, spi_read_fill: 0, framing: Framing::Raw,
+            page_buf: [0u8; 256],
         }
     }
 
@@ -78,6 +183,10 @@ impl<'a> App<'a> {
                 self.process_request(req);
             }
             self.nvic.unpend_usb();
+        } else if self.spi_read_remaining > 0 && !self.usb.spi_data_is_busy() {
+            // Clock out the next packet of a streaming read once
+            // the SPI endpoint has finished sending the previous one.
+            self.spi_read_next();
         } else if self.dap.is_swo_streaming() && !self.usb.dap2_swo_is_busy() {
             // Poll for new UART data when streaming is enabled and
             // the SWO endpoint is ready to transmit more data.
@@ -90,36 +199,249 @@ impl<'a> App<'a> {
         }
     }
 
+    fn set_mode(&mut self, mode: Mode) {
+        // Changing mode cancels any streaming read in progress.
+        self.spi_read_remaining = 0;
+        match mode {
+            Mode::HighImpedance => {
+                self.pins.high_impedance_mode();
+                self.usb.spi_data_disable();
+                self.usb.dap_enable();
+                self.spi.disable();
+            },
+            Mode::Flash => {
+                self.pins.flash_mode();
+                self.usb.spi_data_enable();
+                self.usb.dap_disable();
+                self.spi.setup_spi();
+            },
+            Mode::FPGA => {
+                self.pins.fpga_mode();
+                self.usb.spi_data_enable();
+                self.usb.dap_disable();
+                self.spi.setup_spi();
+            },
+        }
+    }
+
+    /// Execute each operation in the batch packet `data`, writing any
+    /// received data to `rxdata`.
+    ///
+    /// Returns the number of bytes written to `rxdata`, and any mode
+    /// change requested. Processing stops at the first malformed operation,
+    /// so the host will see a short reply. A mode change also ends the
+    /// packet, and must only be applied once the reply has been queued, as
+    /// it re-enables the SPI endpoint and reconfigures the SPI peripheral.
+    fn process_batch(&mut self, data: &[u8], rxdata: &mut [u8; 64]) -> (usize, Option<Mode>) {
+        let mut idx = 0;
+        let mut n = 0;
+        let mut mode = None;
+        while idx < data.len() {
+            let op = match BatchOp::try_from(data[idx]) {
+                Ok(op) => op,
+                Err(_) => break,
+            };
+            let args = &data[idx+1..];
+            idx += 1;
+            match op {
+                BatchOp::Select => { self.pins.cs.set_low(); },
+                BatchOp::Unselect => { self.pins.cs.set_high(); },
+                BatchOp::Transfer => {
+                    let len = match args.first() {
+                        Some(&len) => len as usize,
+                        None => break,
+                    };
+                    if args.len() < 1 + len || n + len > rxdata.len() {
+                        break;
+                    }
+                    let mut buf = [0u8; 64];
+                    self.spi.exchange(&self.dma, &args[1..1+len], &mut buf);
+                    rxdata[n..n+len].copy_from_slice(&buf[..len]);
+                    n += len;
+                    idx += 1 + len;
+                },
+                BatchOp::Read => {
+                    if args.len() < 2 {
+                        break;
+                    }
+                    let (len, fill) = (args[0] as usize, args[1]);
+                    if n + len > rxdata.len() {
+                        break;
+                    }
+                    let txdata = [fill; 64];
+                    let mut buf = [0u8; 64];
+                    self.spi.exchange(&self.dma, &txdata[..len], &mut buf);
+                    rxdata[n..n+len].copy_from_slice(&buf[..len]);
+                    n += len;
+                    idx += 2;
+                },
+                BatchOp::Delay => {
+                    if args.len() < 2 {
+                        break;
+                    }
+                    let us = u16::from_le_bytes([args[0], args[1]]) as u32;
+                    // System clock is 48MHz
+                    cortex_m::asm::delay(us * 48);
+                    idx += 2;
+                },
+                BatchOp::SetMode => {
+                    // Only allow modes which keep the SPI endpoint enabled.
+                    // The change is applied after replying to this packet.
+                    match args.first().map(|&m| Mode::try_from(m as u16)) {
+                        Some(Ok(new @ Mode::Flash)) | Some(Ok(new @ Mode::FPGA)) =>
+                            mode = Some(new),
+                        _ => (),
+                    }
+                    break;
+                },
+                BatchOp::PageLoad => {
+                    if args.len() < 2 {
+                        break;
+                    }
+                    let (offset, len) = (args[0] as usize, args[1] as usize);
+                    if args.len() < 2 + len || offset + len > self.page_buf.len() {
+                        break;
+                    }
+                    self.page_buf[offset..offset+len].copy_from_slice(&args[2..2+len]);
+                    idx += 2 + len;
+                },
+                BatchOp::PageProgram => {
+                    if args.len() < 4 || n + 1 > rxdata.len() {
+                        break;
+                    }
+                    let address = [args[0], args[1], args[2]];
+                    let len = if args[3] == 0 { 256 } else { args[3] as usize };
+                    rxdata[n] = self.flash_page_program(address, len) as u8;
+                    n += 1;
+                    idx += 4;
+                },
+                BatchOp::WaitBusy => {
+                    if n + 1 > rxdata.len() {
+                        break;
+                    }
+                    rxdata[n] = self.flash_wait_busy(WAIT_BUSY_POLLS) as u8;
+                    n += 1;
+                },
+                BatchOp::CRC32 => {
+                    if args.len() < 6 || n + 4 > rxdata.len() {
+                        break;
+                    }
+                    let address = [args[0], args[1], args[2]];
+                    let len = u32::from_be_bytes([0, args[3], args[4], args[5]]);
+                    let crc = self.flash_crc32(address, len);
+                    rxdata[n..n+4].copy_from_slice(&crc.to_le_bytes());
+                    n += 4;
+                    idx += 6;
+                },
+            }
+        }
+        (n, mode)
+    }
+
+    /// Program `len` bytes of the page buffer to the flash at `address`,
+    /// by enabling writes, sending the page program command, and polling
+    /// the status register until the operation completes.
+    fn flash_page_program(&self, address: [u8; 3], len: usize) -> FlashStatus {
+        let mut rxdata = [0u8; 64];
+        self.flash_command(&[FlashCommand::WriteEnable as u8]);
+        let header = [FlashCommand::PageProgram as u8, address[0], address[1], address[2]];
+        self.pins.cs.set_low();
+        self.spi.exchange(&self.dma, &header, &mut rxdata);
+        for chunk in self.page_buf[..len].chunks(64) {
+            self.spi.exchange(&self.dma, chunk, &mut rxdata);
+        }
+        self.pins.cs.set_high();
+        self.flash_wait_busy(PAGE_PROGRAM_POLLS)
+    }
+
+    /// Read `len` bytes from the flash starting at `address`,
+    /// returning their CRC-32 as computed by the hardware CRC unit.
+    fn flash_crc32(&self, address: [u8; 3], len: u32) -> u32 {
+        let mut rxdata = [0u8; 64];
+        let txdata = [0u8; 64];
+        let header = [FlashCommand::FastRead as u8, address[0], address[1], address[2], 0];
+        self.crc.reset();
+        self.pins.cs.set_low();
+        self.spi.exchange(&self.dma, &header, &mut rxdata);
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let n = usize::min(remaining, 64);
+            self.spi.exchange(&self.dma, &txdata[..n], &mut rxdata);
+            self.crc.update(&rxdata[..n]);
+            remaining -= n;
+        }
+        self.pins.cs.set_high();
+        self.crc.finish()
+    }
+
+    /// Poll the flash status register every 10µs until the busy bit
+    /// is clear, giving up after `polls` attempts.
+    fn flash_wait_busy(&self, polls: u32) -> FlashStatus {
+        let mut rxdata = [0u8; 64];
+        for _ in 0..polls {
+            let n = self.flash_command_rx(
+                &[FlashCommand::ReadStatusRegister1 as u8, 0], &mut rxdata);
+            if rxdata[n - 1] & 1 == 0 {
+                return FlashStatus::Ok;
+            }
+            cortex_m::asm::delay(10 * 48);
+        }
+        FlashStatus::Timeout
+    }
+
+    /// Send a single flash command, ignoring any received data
+    fn flash_command(&self, txdata: &[u8]) {
+        let mut rxdata = [0u8; 64];
+        self.flash_command_rx(txdata, &mut rxdata);
+    }
+
+    /// Send a single flash command of up to 64 bytes with CS asserted,
+    /// returning the number of bytes received into `rxdata`.
+    fn flash_command_rx(&self, txdata: &[u8], rxdata: &mut [u8; 64]) -> usize {
+        self.pins.cs.set_low();
+        self.spi.exchange(&self.dma, txdata, rxdata);
+        self.pins.cs.set_high();
+        txdata.len()
+    }
+
+    /// Exchange and transmit the next packet of a streaming SPI read.
+    fn spi_read_next(&mut self) {
+        let n = u32::min(self.spi_read_remaining, 64) as usize;
+        let txdata = [self.spi_read_fill; 64];
+        let mut rxdata = [0u8; 64];
+        self.spi.exchange(&self.dma, &txdata[..n], &mut rxdata);
+        self.usb.spi_data_reply(&rxdata[..n]);
+        self.spi_read_remaining -= n as u32;
+    }
+
     fn process_request(&mut self, req: Request) {
         match req {
             Request::SetCS(state) => self.pins.cs.set_state(state),
             Request::SetFPGA(state) => self.pins.fpga_rst.set_state(state),
             Request::SetTPwr(state) => self.pins.tpwr_en.set_state(state),
             Request::SetLED(state) => self.pins.led.set_state(state),
-            Request::SetMode(mode) => match mode {
-                Mode::HighImpedance => {
-                    self.pins.high_impedance_mode();
-                    self.usb.spi_data_disable();
-                    self.usb.dap_enable();
-                    self.spi.disable();
-                },
-                Mode::Flash => {
-                    self.pins.flash_mode();
-                    self.usb.spi_data_enable();
-                    self.usb.dap_disable();
-                    self.spi.setup_spi();
+            Request::SetMode(mode) => self.set_mode(mode),
+            Request::SetFraming(framing) => self.framing = framing,
+            Request::SPITransmit((txdata, n)) => match self.framing {
+                Framing::Raw => {
+                    let mut rxdata = [0u8; 64];
+                    self.spi.exchange(&self.dma, &txdata[..n], &mut rxdata);
+                    self.usb.spi_data_reply(&rxdata[..n]);
                 },
-                Mode::FPGA => {
-                    self.pins.fpga_mode();
-                    self.usb.spi_data_enable();
-                    self.usb.dap_disable();
-                    self.spi.setup_spi();
+                Framing::Batch => {
+                    let mut rxdata = [0u8; 64];
+                    let (n, mode) = self.process_batch(&txdata[..n], &mut rxdata);
+                    self.usb.spi_data_reply(&rxdata[..n]);
+                    if let Some(mode) = mode {
+                        self.set_mode(mode);
+                    }
                 },
             },
-            Request::SPITransmit((txdata, n)) => {
-                let mut rxdata = [0u8; 64];
-                self.spi.exchange(&self.dma, &txdata[..n], &mut rxdata);
-                self.usb.spi_data_reply(&rxdata[..n]);
+            Request::SPIRead((count, fill)) => {
+                // Start streaming `count` bytes, which are clocked out
+                // and transmitted from `poll()` as the endpoint frees up.
+                self.spi_read_remaining = count;
+                self.spi_read_fill = fill;
             },
             Request::DAP1Command((report, n)) => {
                 let response = self.dap.process_command(&report[..n]);
@@ -134,8 +456,15 @@ impl<'a> App<'a> {
                 }
             },
             Request::GetTPwr => self.usb.tpwr_reply(self.pins.tpwr_det.get_state()),
+            Request::GetCapabilities => self.usb.capabilities_reply(CAPABILITIES),
             Request::Bootload => hal::bootload::bootload(),
+            Request::USBReset => {
+                // The host has reset or reconfigured the device, so stop
+                // clocking out any streaming read it has abandoned.
+                self.spi_read_remaining = 0;
+            },
             Request::Suspend => {
+                self.spi_read_remaining = 0;
                 self.pins.high_impedance_mode();
                 self.pins.led.set_low();
                 self.pins.tpwr_en.set_low();
@@ -0,0 +1,44 @@
+// Copyright 2020 Adam Greig
+// Dual licensed under the Apache 2.0 and MIT licenses.
+
+use stm32ral::crc;
+use stm32ral::{read_reg, write_reg};
+
+/// Hardware CRC unit, configured to compute the standard (zlib) CRC-32
+pub struct CRC {
+    crc: crc::Instance,
+}
+
+impl CRC {
+    pub fn new(crc: crc::Instance) -> Self {
+        CRC { crc }
+    }
+
+    /// Start a new CRC-32 computation.
+    ///
+    /// The STM32F042 has the fixed CRC-32 polynomial, so we only need to
+    /// reflect the input bytes and output word to match the usual CRC-32.
+    pub fn reset(&self) {
+        write_reg!(crc, self.crc, INIT, 0xFFFF_FFFF);
+        write_reg!(crc, self.crc, CR, REV_OUT: Reversed, REV_IN: Byte,
+                                      POLYSIZE: Polysize32, RESET: Reset);
+    }
+
+    /// Feed `data` into the current CRC computation
+    pub fn update(&self, data: &[u8]) {
+        for byte in data.iter() {
+            self.write_dr_u8(*byte);
+        }
+    }
+
+    /// Return the final CRC-32 value of all data since `reset()`
+    pub fn finish(&self) -> u32 {
+        read_reg!(crc, self.crc, DR) ^ 0xFFFF_FFFF
+    }
+
+    /// Perform an 8-bit write to DR, which feeds a single byte to the CRC unit
+    #[inline(always)]
+    fn write_dr_u8(&self, data: u8) {
+        unsafe { core::ptr::write_volatile(&self.crc.DR as *const _ as *mut u8, data) };
+    }
+}
@@ -7,6 +7,7 @@ pub mod nvic;
 pub mod gpio;
 pub mod dma;
 pub mod spi;
+pub mod crc;
 pub mod usb;
 pub mod uart;
 pub mod bootload;
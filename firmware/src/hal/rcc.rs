@@ -26,7 +26,8 @@ impl RCC {
         while read_reg!(rcc, self.rcc, CFGR, SWS != HSI48) {}
 
         // Enable peripheral clocks
-        modify_reg!(rcc, self.rcc, AHBENR, IOPAEN: Enabled, IOPBEN: Enabled, DMAEN: Enabled);
+        modify_reg!(rcc, self.rcc, AHBENR, IOPAEN: Enabled, IOPBEN: Enabled, DMAEN: Enabled,
+                                           CRCEN: Enabled);
         modify_reg!(rcc, self.rcc, APB1ENR, CRSEN: Enabled, USBEN: Enabled, USART2EN: Enabled);
         modify_reg!(rcc, self.rcc, APB2ENR, SPI1EN: Enabled);
 
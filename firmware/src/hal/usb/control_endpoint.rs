@@ -11,7 +11,7 @@ use super::packets::{*, ToBytes};
 use super::buffers::*;
 use super::descriptors::*;
 
-use crate::app::{PinState, Mode, Request};
+use crate::app::{PinState, Mode, Framing, Request};
 use crate::hal::unique_id::get_hex_id;
 
 /// USB handling code for control endpoint
@@ -442,6 +442,20 @@ impl ControlEndpoint {
                 None
             },
 
+            Ok(VendorRequest::SetFraming) => {
+                match Framing::try_from(setup.wValue) {
+                    Ok(framing) => {
+                        self.pending_request = Some(
+                            USBStackRequest::AppRequest(Request::SetFraming(framing)));
+                        self.transmit_ack(usb);
+                    },
+                    _ => {
+                        self.stall(usb);
+                    },
+                }
+                None
+            },
+
             Ok(VendorRequest::SetTPwr) => {
                 match PinState::try_from(setup.wValue) {
                     Ok(ps) => {
@@ -484,6 +498,23 @@ impl ControlEndpoint {
                 None
             },
 
+            Ok(VendorRequest::GetCapabilities) => {
+                // As for GetTPwr, the application replies with its
+                // capabilities by calling `capabilities_reply()`.
+                Some(USBStackRequest::AppRequest(Request::GetCapabilities))
+            },
+
+            Ok(VendorRequest::SPIRead) => {
+                // wValue holds the low 16 bits of the byte count,
+                // wIndex holds the top 8 bits of the count and the fill byte.
+                let [count_hi, fill] = setup.wIndex.to_le_bytes();
+                let count = (setup.wValue as u32) | ((count_hi as u32) << 16);
+                self.pending_request = Some(
+                    USBStackRequest::AppRequest(Request::SPIRead((count, fill))));
+                self.transmit_ack(usb);
+                None
+            },
+
             Ok(VendorRequest::GetOSFeature) => {
                 match OSFeatureDescriptorType::try_from(setup.wIndex) {
                     Ok(OSFeatureDescriptorType::CompatibleID) => {
@@ -542,14 +573,14 @@ impl Endpoint for ControlEndpoint {
         self.btable.write(&self.epbuf);
     }
 
-    fn reset_endpoint(&self, usb: &usb::Instance) {
+    fn reset_endpoint(&mut self, usb: &usb::Instance) {
         let (stat_tx, stat_rx) = read_reg!(usb, usb, EP0R, STAT_TX, STAT_RX);
         write_reg!(usb, usb, EP0R,
                    CTR_RX: 0, EP_TYPE: Control, EP_KIND: 0, CTR_TX: 0, EA: 0,
                    STAT_TX: stat_nak(stat_tx), STAT_RX: stat_valid(stat_rx));
     }
 
-    fn configure_endpoint(&self, _usb: &usb::Instance) {
+    fn configure_endpoint(&mut self, _usb: &usb::Instance) {
         // No operation required, as the control endpoint configuration
         // is identical to its reset state.
     }
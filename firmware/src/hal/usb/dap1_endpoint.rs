@@ -48,13 +48,13 @@ impl Endpoint for DAP1Endpoint {
         self.btable.write(&self.epbuf);
     }
 
-    fn reset_endpoint(&self, usb: &usb::Instance) {
+    fn reset_endpoint(&mut self, usb: &usb::Instance) {
         let (stat_tx, stat_rx) = read_reg!(usb, usb, EP2R, STAT_TX, STAT_RX);
         write_reg!(usb, usb, EP2R,
                    STAT_TX: stat_disabled(stat_tx), STAT_RX: stat_disabled(stat_rx));
     }
 
-    fn configure_endpoint(&self, usb: &usb::Instance) {
+    fn configure_endpoint(&mut self, usb: &usb::Instance) {
         // Set up EP2R to be a bidirectional interrupt endpoint,
         // with STAT_TX to nak and STAT_RX to valid,
         // and DTOG_TX and DTOG_RX both set to 0.
@@ -37,8 +37,8 @@ pub struct USB {
 trait Endpoint {
     fn new(epbuf: &'static mut EPBuf, btable: &'static mut BTableRow) -> Self;
     fn write_btable(&mut self);
-    fn reset_endpoint(&self, usb: &usb::Instance);
-    fn configure_endpoint(&self, usb: &usb::Instance);
+    fn reset_endpoint(&mut self, usb: &usb::Instance);
+    fn configure_endpoint(&mut self, usb: &usb::Instance);
     fn process_transfer(&mut self, usb: &usb::Instance) -> Option<USBStackRequest>;
     fn transmit_slice(&mut self, usb: &usb::Instance, data: &[u8]);
     fn rx_valid(&mut self, usb: &usb::Instance);
@@ -101,6 +101,10 @@ impl USB {
 
             // Clear RESET flag
             write_reg!(usb, self.usb, ISTR, CTR: 1, SUSP: 1, WKUP: 1, RESET: 0);
+
+            // Let the application know so it can abandon any
+            // transfers still in progress
+            return Some(Request::USBReset);
         }
 
         // Handle wakeup detection
@@ -147,7 +151,7 @@ impl USB {
                 },
                 Some(USBStackRequest::SetConfiguration) => {
                     self.set_configuration();
-                    None
+                    Some(Request::USBReset)
                 },
                 Some(USBStackRequest::AppRequest(req)) => {
                     Some(req)
@@ -174,6 +178,17 @@ impl USB {
         self.ctl_endpoint.transmit_slice(&self.usb, &data[..]);
     }
 
+    /// Transmit the firmware capabilities in response to a recent GetCapabilities request
+    pub fn capabilities_reply(&mut self, capabilities: u16) {
+        let data = capabilities.to_le_bytes();
+        self.ctl_endpoint.transmit_slice(&self.usb, &data[..]);
+    }
+
+    /// Check if SPI endpoint is currently busy transmitting data
+    pub fn spi_data_is_busy(&self) -> bool {
+        self.spi_endpoint.is_busy()
+    }
+
     /// Transmit a given slice of data out the bulk endpoint
     pub fn spi_data_reply(&mut self, data: &[u8]) {
         self.spi_endpoint.transmit_slice(&self.usb, data);
@@ -254,7 +269,7 @@ impl USB {
     /// Put device into USB_RESET state
     ///
     /// Respond to address 0 on EP0 only
-    fn usb_reset(&self) {
+    fn usb_reset(&mut self) {
         // Ensure peripheral will not respond while we set up endpoints
         write_reg!(usb, self.usb, DADDR, EF: Disabled);
 
@@ -305,7 +320,7 @@ impl USB {
     /// EP0: Bidirectional control (default, left unchanged)
     /// EP1: Bidirectional bulk (SPI transactions)
     /// EP2: Bidirectional interrupt (CMSIS-DAP HID)
-    fn set_configuration(&self) {
+    fn set_configuration(&mut self) {
         // Configure our known endpoints
         self.ctl_endpoint.configure_endpoint(&self.usb);
         self.spi_endpoint.configure_endpoint(&self.usb);
@@ -42,6 +42,9 @@ pub enum VendorRequest {
     GetTPwr = 5,
     SetLED = 6,
     Bootload = 7,
+    GetCapabilities = 8,
+    SPIRead = 9,
+    SetFraming = 10,
     GetOSFeature = b'A',
 }
 
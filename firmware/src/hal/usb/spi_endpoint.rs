@@ -13,6 +13,8 @@ use crate::app::Request;
 pub(super) struct SPIEndpoint {
     epbuf: &'static mut EPBuf,
     btable: &'static mut BTableRow,
+    tx_busy: bool,
+    rx_enabled: bool,
 }
 
 impl SPIEndpoint {
@@ -21,8 +23,10 @@ impl SPIEndpoint {
         let mut data = [0u8; 64];
         let n = self.epbuf.read_rx(&self.btable, &mut data);
 
-        // Indicate we're ready to receive again
-        self.rx_valid(usb);
+        // The peripheral has set STAT_RX to NAK, and reception is only
+        // resumed once the reply to this packet has been sent, so a reply
+        // still waiting to be collected is never overwritten. Any further
+        // packets the host queues are held off by the NAK until then.
 
         // Return received data to the application
         Some(USBStackRequest::AppRequest(Request::SPITransmit((data, n))))
@@ -34,24 +38,42 @@ impl SPIEndpoint {
         write_reg!(usb, usb, EP1R, CTR_RX: 1, EP_TYPE: ep_type, CTR_TX: 1, EA: ea,
                    STAT_TX: stat_valid(stat_tx));
     }
+
+    /// Indicate we're ready to receive a packet
+    fn set_stat_rx_valid(&self, usb: &usb::Instance) {
+        let (stat_rx, ep_type, ea) = read_reg!(usb, usb, EP1R, STAT_RX, EP_TYPE, EA);
+        write_reg!(usb, usb, EP1R, CTR_RX: 1, EP_TYPE: ep_type, CTR_TX: 1, EA: ea,
+                   STAT_RX: stat_valid(stat_rx));
+    }
+
+    /// Returns true if this endpoint is still busy with a transmission.
+    pub fn is_busy(&self) -> bool {
+        self.tx_busy
+    }
 }
 
 impl Endpoint for SPIEndpoint {
     fn new(epbuf: &'static mut EPBuf, btable: &'static mut BTableRow) -> Self {
-        SPIEndpoint { epbuf, btable }
+        SPIEndpoint { epbuf, btable, tx_busy: false, rx_enabled: false }
     }
 
     fn write_btable(&mut self) {
         self.btable.write(&self.epbuf);
     }
 
-    fn reset_endpoint(&self, usb: &usb::Instance) {
+    fn reset_endpoint(&mut self, usb: &usb::Instance) {
+        // Any transmission in progress is abandoned by the reset
+        self.tx_busy = false;
+        self.rx_enabled = false;
         let (stat_tx, stat_rx) = read_reg!(usb, usb, EP1R, STAT_TX, STAT_RX);
         write_reg!(usb, usb, EP1R,
                    STAT_TX: stat_disabled(stat_tx), STAT_RX: stat_disabled(stat_rx));
     }
 
-    fn configure_endpoint(&self, usb: &usb::Instance) {
+    fn configure_endpoint(&mut self, usb: &usb::Instance) {
+        self.tx_busy = false;
+        self.rx_enabled = false;
+
         // Set up EP1R to be a bidirectional bulk endpoint,
         // with TX set to NAK, RX set to STALL, and both DTOG set to 0.
         let (stat_tx, stat_rx, dtog_rx, dtog_tx) =
@@ -68,11 +90,18 @@ impl Endpoint for SPIEndpoint {
         let (ctr_tx, ctr_rx, ep_type, ea) =
             read_reg!(usb, usb, EP1R, CTR_TX, CTR_RX, EP_TYPE, EA);
         if ctr_tx == 1 {
-            // We don't need to take any action on transmission completion.
+            // Record that the endpoint is free to transmit again, which
+            // is used to pace streaming reads.
+            self.tx_busy = false;
 
             // Clear CTR_TX
             write_reg!(usb, usb, EP1R,
                        CTR_RX: 1, EP_TYPE: ep_type, CTR_TX: 0, EA: ea);
+
+            // The reply has been collected, so accept the next packet.
+            if self.rx_enabled {
+                self.set_stat_rx_valid(usb);
+            }
         } else if ctr_rx == 1 {
             // Handle receiving a new data buffer, which is a request to
             // transmit that data and respond with the same amount of data
@@ -92,17 +121,22 @@ impl Endpoint for SPIEndpoint {
         self.epbuf.write_tx(data);
         self.btable.tx_count(data.len());
         self.tx_valid(usb);
+        self.tx_busy = true;
     }
 
-    /// Resume reception of new SPI data packets
+    /// Resume reception of new SPI data packets.
+    ///
+    /// If a reply is still being sent, reception resumes once it completes.
     fn rx_valid(&mut self, usb: &usb::Instance) {
-        let (stat_rx, ep_type, ea) = read_reg!(usb, usb, EP1R, STAT_RX, EP_TYPE, EA);
-        write_reg!(usb, usb, EP1R, CTR_RX: 1, EP_TYPE: ep_type, CTR_TX: 1, EA: ea,
-                   STAT_RX: stat_valid(stat_rx));
+        self.rx_enabled = true;
+        if !self.tx_busy {
+            self.set_stat_rx_valid(usb);
+        }
     }
 
     /// Cancel reception of new SPI data packets
     fn rx_stall(&mut self, usb: &usb::Instance) {
+        self.rx_enabled = false;
         let (stat_rx, ep_type, ea) = read_reg!(usb, usb, EP1R, STAT_RX, EP_TYPE, EA);
         write_reg!(usb, usb, EP1R, CTR_RX: 1, EP_TYPE: ep_type, CTR_TX: 1, EA: ea,
                    STAT_RX: stat_stall(stat_rx));
@@ -37,13 +37,17 @@ impl Endpoint for SWOEndpoint {
         self.btable.write(&self.epbuf);
     }
 
-    fn reset_endpoint(&self, usb: &usb::Instance) {
+    fn reset_endpoint(&mut self, usb: &usb::Instance) {
+        // Any transmission in progress is abandoned by the reset
+        self.tx_busy = false;
         let (stat_tx, stat_rx) = read_reg!(usb, usb, EP4R, STAT_TX, STAT_RX);
         write_reg!(usb, usb, EP4R,
                    STAT_TX: stat_disabled(stat_tx), STAT_RX: stat_disabled(stat_rx));
     }
 
-    fn configure_endpoint(&self, usb: &usb::Instance) {
+    fn configure_endpoint(&mut self, usb: &usb::Instance) {
+        self.tx_busy = false;
+
         // Set up EP4R to be a unidirectional bulk IN endpoint,
         // with STAT_TX to nak and STAT_RX to disabled,
         // and DTOG_TX and DTOG_RX both set to 0.
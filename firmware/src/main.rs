@@ -40,6 +40,7 @@ fn main() -> ! {
     let gpioa = hal::gpio::GPIO::new(stm32ral::gpio::GPIOA::take().unwrap());
     let gpiob = hal::gpio::GPIO::new(stm32ral::gpio::GPIOB::take().unwrap());
     let spi = hal::spi::SPI::new(stm32ral::spi::SPI1::take().unwrap());
+    let crc = hal::crc::CRC::new(stm32ral::crc::CRC::take().unwrap());
     let mut uart = hal::uart::UART::new(stm32ral::usart::USART2::take().unwrap(), &dma);
     let mut usb = hal::usb::USB::new(stm32ral::usb::USB::take().unwrap());
 
@@ -75,7 +76,7 @@ fn main() -> ! {
 
     // Create App instance with the HAL instances
     let mut app = app::App::new(
-        &flash, &rcc, &nvic, &dma, &pins, &spi, &mut usb, &mut dap);
+        &flash, &rcc, &nvic, &dma, &pins, &spi, &crc, &mut usb, &mut dap);
 
     // Initialise application, including system peripherals
     app.setup();
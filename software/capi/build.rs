@@ -0,0 +1,11 @@
+fn main() {
+    // Generate the C API header into OUT_DIR; tests check include/ffp.h matches it
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let config = cbindgen::Config::from_file("cbindgen.toml").expect("Error reading cbindgen.toml");
+    cbindgen::generate_with_config(&crate_dir, config)
+        .expect("Error generating C API header")
+        .write_to_file(out_dir.join("ffp.h"));
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
@@ -0,0 +1,128 @@
+/* C API for the ffp library. Generated by cbindgen, do not edit. */
+
+#ifndef FFP_H
+#define FFP_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of an ffp C API call
+typedef enum FFPStatus {
+  // Success
+  FFP_STATUS_OK = 0,
+  // Unspecified error
+  FFP_STATUS_ERROR = -1,
+  // No FFP device was found, or it was ambiguous
+  FFP_STATUS_DEVICE_ERROR = -2,
+  // Error communicating with the FFP over USB
+  FFP_STATUS_USB_ERROR = -3,
+  // Operation not supported by the FFP firmware
+  FFP_STATUS_FIRMWARE_ERROR = -4,
+  // Flash did not respond or rejected an operation
+  FFP_STATUS_FLASH_ERROR = -5,
+  // Device contents did not match the expected data
+  FFP_STATUS_VERIFY_ERROR = -6,
+  // A NULL pointer or invalid string was passed
+  FFP_STATUS_INVALID_ARGUMENT = -7,
+} FFPStatus;
+
+// Opaque handle to an open FFP
+typedef struct FFPProgrammer FFPProgrammer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Open an attached FFP.
+//
+// If `serial` is NULL, opens the only attached FFP; otherwise opens the FFP
+// with that serial number. On success, `*handle` is set to a new handle
+// which must be released with `ffp_close`.
+//
+// # Safety
+// `serial` must be NULL or a valid NUL-terminated string, and `handle`
+// must be a valid pointer.
+enum FFPStatus ffp_open(const char *serial, struct FFPProgrammer **handle);
+
+// Open a simulated FFP with an erased flash of `flash_size` bytes, for testing.
+//
+// `flash_size` must be a power of two between 64KB and 16MB.
+//
+// # Safety
+// `handle` must be a valid pointer.
+enum FFPStatus ffp_open_simulated(size_t flash_size, struct FFPProgrammer **handle);
+
+// Close a handle opened by `ffp_open`. Passing NULL is allowed.
+//
+// # Safety
+// `handle` must be NULL or a handle from `ffp_open` which has not been closed.
+void ffp_close(struct FFPProgrammer *handle);
+
+// Get a description of the last error on this thread.
+//
+// The returned string is valid until the next ffp call on this thread.
+const char *ffp_last_error(void);
+
+// Read `length` bytes from flash starting at `address` into `data`.
+//
+// # Safety
+// `handle` must be a valid handle and `data` must point to `length` writable bytes.
+enum FFPStatus ffp_flash_read(struct FFPProgrammer *handle,
+                              uint32_t address,
+                              uint8_t *data,
+                              size_t length);
+
+// Erase and program `length` bytes of `data` to flash starting at `address`.
+//
+// If `verify` is true, the programmed data is read back and checked.
+//
+// # Safety
+// `handle` must be a valid handle and `data` must point to `length` readable bytes.
+enum FFPStatus ffp_flash_program(struct FFPProgrammer *handle,
+                                 uint32_t address,
+                                 const uint8_t *data,
+                                 size_t length,
+                                 bool verify);
+
+// Erase the entire flash.
+//
+// # Safety
+// `handle` must be a valid handle.
+enum FFPStatus ffp_flash_erase(struct FFPProgrammer *handle);
+
+// Erase `length` bytes of flash starting at `address`, which must be
+// aligned to erase blocks unless `round_outward` is true.
+//
+// # Safety
+// `handle` must be a valid handle.
+enum FFPStatus ffp_flash_erase_range(struct FFPProgrammer *handle,
+                                     uint32_t address,
+                                     size_t length,
+                                     bool round_outward);
+
+// Program the FPGA with the `length` byte bitstream in `data`.
+//
+// # Safety
+// `handle` must be a valid handle and `data` must point to `length` readable bytes.
+enum FFPStatus ffp_fpga_program(struct FFPProgrammer *handle, const uint8_t *data, size_t length);
+
+// Reset the FPGA, causing it to reload its configuration from flash.
+//
+// # Safety
+// `handle` must be a valid handle.
+enum FFPStatus ffp_fpga_reset(struct FFPProgrammer *handle);
+
+// Enable or disable the FFP's target power switch.
+//
+// # Safety
+// `handle` must be a valid handle.
+enum FFPStatus ffp_set_power(struct FFPProgrammer *handle, bool on);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* FFP_H */
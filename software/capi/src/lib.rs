@@ -0,0 +1,316 @@
+//! C API, for using the ffp library from other languages.
+//!
+//! All functions return an `FFPStatus`, with `FFP_STATUS_OK` on success.
+//! After a failure, `ffp_last_error` returns a description of the error.
+//! The `ffp.h` header in `include` is generated by cbindgen, and checked
+//! against the build's own copy by the tests.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use ffp::{FFPError, Programmer, Flash, FPGA, SimulatedFFP, ErrorKind, error_kind, error_message};
+
+/// Result of an ffp C API call
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FFPStatus {
+    /// Success
+    Ok = 0,
+    /// Unspecified error
+    Error = -1,
+    /// No FFP device was found, or it was ambiguous
+    DeviceError = -2,
+    /// Error communicating with the FFP over USB
+    USBError = -3,
+    /// Operation not supported by the FFP firmware
+    FirmwareError = -4,
+    /// Flash did not respond or rejected an operation
+    FlashError = -5,
+    /// Device contents did not match the expected data
+    VerifyError = -6,
+    /// A NULL pointer or invalid string was passed
+    InvalidArgument = -7,
+}
+
+/// Opaque handle to an open FFP
+pub struct FFPProgrammer {
+    programmer: Programmer,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+/// Classify an error, recording its description for `ffp_last_error`
+fn status(error: failure::Error) -> FFPStatus {
+    set_last_error(error_message(&error));
+    match error_kind(&error) {
+        ErrorKind::Device => FFPStatus::DeviceError,
+        ErrorKind::USB => FFPStatus::USBError,
+        ErrorKind::Firmware => FFPStatus::FirmwareError,
+        ErrorKind::Flash => FFPStatus::FlashError,
+        ErrorKind::Verify => FFPStatus::VerifyError,
+        ErrorKind::Other => FFPStatus::Error,
+    }
+}
+
+/// Run `f`, converting errors and panics into an `FFPStatus`
+fn call(f: impl FnOnce() -> ffp::Result<FFPStatus>) -> FFPStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(error)) => status(error),
+        Err(_) => {
+            set_last_error("Internal error (panic) in ffp library".to_string());
+            FFPStatus::Error
+        },
+    }
+}
+
+fn invalid_argument(name: &str) -> FFPStatus {
+    set_last_error(format!("Invalid argument: {}", name));
+    FFPStatus::InvalidArgument
+}
+
+/// Convert a C buffer into a slice, allowing NULL when `length` is 0
+unsafe fn buffer<'a>(data: *const u8, length: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), length) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(data, length)),
+    }
+}
+
+/// Hold the FPGA in reset and wake the flash, as the CLI does before any flash command
+fn flash(programmer: &Programmer) -> ffp::Result<Flash<'_>> {
+    let flash = Flash::new(programmer);
+    flash.read_id()?;
+    Ok(flash)
+}
+
+/// Open an attached FFP.
+///
+/// If `serial` is NULL, opens the only attached FFP; otherwise opens the FFP
+/// with that serial number. On success, `*handle` is set to a new handle
+/// which must be released with `ffp_close`.
+///
+/// # Safety
+/// `serial` must be NULL or a valid NUL-terminated string, and `handle`
+/// must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_open(serial: *const c_char, handle: *mut *mut FFPProgrammer)
+    -> FFPStatus
+{
+    if handle.is_null() {
+        return invalid_argument("handle");
+    }
+    *handle = ptr::null_mut();
+    let serial = match serial.is_null() {
+        true => None,
+        false => match CStr::from_ptr(serial).to_str() {
+            Ok(serial) => Some(serial),
+            Err(_) => return invalid_argument("serial"),
+        },
+    };
+    call(|| {
+        let context = rusb::Context::new().map_err(FFPError::USBError)?;
+        let programmer = match serial {
+            Some(serial) => Programmer::by_serial(&context, serial)?,
+            None => Programmer::find(&context)?,
+        };
+        *handle = Box::into_raw(Box::new(FFPProgrammer { programmer }));
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Open a simulated FFP with an erased flash of `flash_size` bytes, for testing.
+///
+/// `flash_size` must be a power of two between 64KB and 16MB.
+///
+/// # Safety
+/// `handle` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_open_simulated(flash_size: usize, handle: *mut *mut FFPProgrammer)
+    -> FFPStatus
+{
+    if handle.is_null() {
+        return invalid_argument("handle");
+    }
+    *handle = ptr::null_mut();
+    if !flash_size.is_power_of_two() || !(1 << 16..=1 << 24).contains(&flash_size) {
+        return invalid_argument("flash_size");
+    }
+    call(|| {
+        let sim = SimulatedFFP::new(flash_size);
+        sim.set_time_scale(0.0);
+        let programmer = Programmer::from_transport(Box::new(sim))?;
+        *handle = Box::into_raw(Box::new(FFPProgrammer { programmer }));
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Close a handle opened by `ffp_open`. Passing NULL is allowed.
+///
+/// # Safety
+/// `handle` must be NULL or a handle from `ffp_open` which has not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_close(handle: *mut FFPProgrammer) {
+    if !handle.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle))));
+    }
+}
+
+/// Get a description of the last error on this thread.
+///
+/// The returned string is valid until the next ffp call on this thread.
+#[no_mangle]
+pub extern "C" fn ffp_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Read `length` bytes from flash starting at `address` into `data`.
+///
+/// # Safety
+/// `handle` must be a valid handle and `data` must point to `length` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_flash_read(handle: *mut FFPProgrammer, address: u32,
+                                        data: *mut u8, length: usize) -> FFPStatus
+{
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    if data.is_null() && length > 0 {
+        return invalid_argument("data");
+    }
+    call(|| {
+        let rx = flash(&handle.programmer)?.read(address, length)?;
+        ptr::copy_nonoverlapping(rx.as_ptr(), data, length);
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Erase and program `length` bytes of `data` to flash starting at `address`.
+///
+/// If `verify` is true, the programmed data is read back and checked.
+///
+/// # Safety
+/// `handle` must be a valid handle and `data` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_flash_program(handle: *mut FFPProgrammer, address: u32,
+                                           data: *const u8, length: usize, verify: bool)
+    -> FFPStatus
+{
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    let data = match buffer(data, length) {
+        Some(data) => data,
+        None => return invalid_argument("data"),
+    };
+    call(|| {
+        flash(&handle.programmer)?.program(address, data, verify)?;
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Erase the entire flash.
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_flash_erase(handle: *mut FFPProgrammer) -> FFPStatus {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    call(|| {
+        flash(&handle.programmer)?.erase()?;
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Erase `length` bytes of flash starting at `address`, which must be
+/// aligned to erase blocks unless `round_outward` is true.
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_flash_erase_range(handle: *mut FFPProgrammer, address: u32,
+                                               length: usize, round_outward: bool)
+    -> FFPStatus
+{
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    call(|| {
+        flash(&handle.programmer)?.erase_range(address, length, round_outward)?;
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Program the FPGA with the `length` byte bitstream in `data`.
+///
+/// # Safety
+/// `handle` must be a valid handle and `data` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_fpga_program(handle: *mut FFPProgrammer, data: *const u8,
+                                          length: usize) -> FFPStatus
+{
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    let data = match buffer(data, length) {
+        Some(data) => data,
+        None => return invalid_argument("data"),
+    };
+    call(|| {
+        FPGA::new(&handle.programmer).program(data)?;
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Reset the FPGA, causing it to reload its configuration from flash.
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_fpga_reset(handle: *mut FFPProgrammer) -> FFPStatus {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    call(|| {
+        FPGA::new(&handle.programmer).reset()?;
+        Ok(FFPStatus::Ok)
+    })
+}
+
+/// Enable or disable the FFP's target power switch.
+///
+/// # Safety
+/// `handle` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn ffp_set_power(handle: *mut FFPProgrammer, on: bool) -> FFPStatus {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return invalid_argument("handle"),
+    };
+    call(|| {
+        match on {
+            true => handle.programmer.power_on()?,
+            false => handle.programmer.power_off()?,
+        }
+        Ok(FFPStatus::Ok)
+    })
+}
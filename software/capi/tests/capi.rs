@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Check the checked-in header matches the one generated by the build
+#[test]
+fn header_up_to_date() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let generated = std::fs::read_to_string(Path::new(env!("OUT_DIR")).join("ffp.h")).unwrap();
+    let checked_in = std::fs::read_to_string(manifest_dir.join("include/ffp.h")).unwrap();
+    assert!(generated == checked_in,
+            "include/ffp.h is out of date, regenerate it with \
+             `cbindgen --config cbindgen.toml --output include/ffp.h`");
+}
+
+/// Compile the C API test program against the ffp_capi shared library and run it
+#[test]
+fn c_program() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // Integration tests are built in target/<profile>/deps, next to the library
+    let lib_dir = std::env::current_exe().unwrap().parent().unwrap().to_owned();
+    let out_dir = std::env::temp_dir().join(format!("ffp-capi-{}", std::process::id()));
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let exe = out_dir.join("test");
+
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(manifest_dir.join("tests/capi/test.c"))
+        .arg("-I").arg(manifest_dir.join("include"))
+        .arg("-L").arg(&lib_dir)
+        .arg("-lffp_capi")
+        .arg("-o").arg(&exe)
+        .status().expect("Error running C compiler");
+    assert!(status.success());
+
+    let status = Command::new(&exe)
+        .env("LD_LIBRARY_PATH", &lib_dir)
+        .env("DYLD_LIBRARY_PATH", &lib_dir)
+        .status().unwrap();
+    assert!(status.success());
+    std::fs::remove_dir_all(&out_dir).unwrap();
+}
@@ -0,0 +1,52 @@
+/* Exercise the ffp C API against a simulated FFP. */
+
+#include <stdio.h>
+#include <string.h>
+#include "ffp.h"
+
+#define CHECK(call) do { \
+    enum FFPStatus status = (call); \
+    if (status != FFP_STATUS_OK) { \
+        fprintf(stderr, "%s failed (%d): %s\n", #call, status, ffp_last_error()); \
+        return 1; \
+    } \
+} while (0)
+
+int main(void) {
+    FFPProgrammer *ffp;
+    uint8_t data[5000], readback[5000];
+    uint8_t bitstream[] = { 0xFF, 0x00, 0x00, 0xFF, 0x7E, 0xAA, 0x99, 0x7E, 0x01, 0x02 };
+    size_t i;
+
+    for (i = 0; i < sizeof(data); i++) {
+        data[i] = (uint8_t)(i * 7);
+    }
+
+    CHECK(ffp_open_simulated(1 << 20, &ffp));
+    CHECK(ffp_set_power(ffp, true));
+    CHECK(ffp_flash_program(ffp, 0x1000, data, sizeof(data), true));
+    CHECK(ffp_flash_read(ffp, 0x1000, readback, sizeof(readback)));
+    if (memcmp(data, readback, sizeof(data)) != 0) {
+        fprintf(stderr, "Flash readback did not match\n");
+        return 1;
+    }
+    CHECK(ffp_flash_erase_range(ffp, 0x1000, 8192, false));
+    CHECK(ffp_fpga_program(ffp, bitstream, sizeof(bitstream)));
+
+    if (ffp_flash_erase_range(ffp, 100, 100, false) != FFP_STATUS_FLASH_ERROR) {
+        fprintf(stderr, "Unaligned erase did not fail\n");
+        return 1;
+    }
+    if (strlen(ffp_last_error()) == 0) {
+        fprintf(stderr, "No error message\n");
+        return 1;
+    }
+    if (ffp_flash_read(NULL, 0, readback, 1) != FFP_STATUS_INVALID_ARGUMENT) {
+        fprintf(stderr, "NULL handle was accepted\n");
+        return 1;
+    }
+
+    ffp_close(ffp);
+    printf("C API test passed\n");
+    return 0;
+}
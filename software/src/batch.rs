@@ -0,0 +1,285 @@
+use crate::programmer::Mode;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+enum Opcode {
+    Select = 1,
+    Unselect = 2,
+    Transfer = 3,
+    Read = 4,
+    Delay = 5,
+    SetMode = 6,
+    PageLoad = 7,
+    PageProgram = 8,
+    WaitBusy = 9,
+    CRC32 = 10,
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    Select,
+    Unselect,
+    Transfer(Vec<u8>),
+    Read(usize, u8),
+    Delay(u16),
+    SetMode(Mode),
+    PageLoad(Vec<u8>),
+    PageProgram(u32, usize),
+    WaitBusy,
+    CRC32(u32, usize),
+}
+
+/// Sequence of SPI operations for the FFP to execute without host round trips.
+///
+/// Batches are only supported by firmware with `Capability::Batch`, and are
+/// run using `Programmer::batch`, which returns all data received during
+/// `transfer` and `read` operations in order.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+impl Batch {
+    const PACKET_SIZE: usize = 64;
+
+    /// Create a new empty `Batch`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set SPI pins to flash mode.
+    ///
+    /// The FFP changes mode after replying to the packet containing this
+    /// operation, so any following operations are sent in a new packet.
+    /// The operation is left out if the FFP is already in flash mode.
+    pub fn flash_mode(mut self) -> Self {
+        self.ops.push(Op::SetMode(Mode::Flash));
+        self
+    }
+
+    /// Set SPI pins to FPGA mode, as for `flash_mode`
+    pub fn fpga_mode(mut self) -> Self {
+        self.ops.push(Op::SetMode(Mode::FPGA));
+        self
+    }
+
+    /// Assert SPI CS
+    pub fn select(mut self) -> Self {
+        self.ops.push(Op::Select);
+        self
+    }
+
+    /// Deassert SPI CS
+    pub fn unselect(mut self) -> Self {
+        self.ops.push(Op::Unselect);
+        self
+    }
+
+    /// Transmit `data`, receiving the same number of bytes
+    pub fn transfer(mut self, data: &[u8]) -> Self {
+        if !data.is_empty() {
+            self.ops.push(Op::Transfer(data.to_vec()));
+        }
+        self
+    }
+
+    /// Receive `nbytes` while transmitting `fill`
+    pub fn read(mut self, nbytes: usize, fill: u8) -> Self {
+        if nbytes > 0 {
+            self.ops.push(Op::Read(nbytes, fill));
+        }
+        self
+    }
+
+    /// Wait for `us` microseconds
+    pub fn delay_us(mut self, us: u16) -> Self {
+        self.ops.push(Op::Delay(us));
+        self
+    }
+
+    /// Load `data` into the FFP's page buffer, then program it to the
+    /// attached flash at `address` and wait for programming to complete.
+    ///
+    /// Requires firmware with `Capability::PageProgram`. Receives one status
+    /// byte, which is 0 on success or 1 if the flash did not become ready.
+    pub fn page_program(mut self, address: u32, data: &[u8]) -> Self {
+        assert!(!data.is_empty(), "Cannot program 0 bytes of data");
+        assert!(data.len() <= 256, "Cannot program more than 256 bytes per page");
+        self.ops.push(Op::PageLoad(data.to_vec()));
+        self.ops.push(Op::PageProgram(address, data.len()));
+        self
+    }
+
+    /// Wait for the attached flash to no longer be busy.
+    ///
+    /// Requires firmware with `Capability::PageProgram`. Receives one status
+    /// byte, which is 0 if the flash is ready or 1 if it is still busy
+    /// after around 50ms, in which case the operation should be repeated.
+    pub fn wait_busy(mut self) -> Self {
+        self.ops.push(Op::WaitBusy);
+        self
+    }
+
+    /// Read `length` bytes of the attached flash starting at `address`,
+    /// and compute their CRC-32 on the FFP.
+    ///
+    /// Requires firmware with `Capability::CRC32`. Receives the CRC-32 as
+    /// four bytes in little-endian order.
+    pub fn crc32(mut self, address: u32, length: usize) -> Self {
+        assert!(length < (1 << 24), "Cannot compute CRC of more than 16MB at once");
+        self.ops.push(Op::CRC32(address, length));
+        self
+    }
+
+    /// Modes set by the batch, in order
+    pub(crate) fn modes(&self) -> impl Iterator<Item = Mode> + '_ {
+        self.ops.iter().filter_map(|op| match op {
+            Op::SetMode(mode) => Some(*mode),
+            _ => None,
+        })
+    }
+
+    /// Encode the batch into packets for the FFP's bulk endpoint, starting
+    /// with the FFP in `mode`.
+    ///
+    /// Long transfers and reads are split over as many packets as required,
+    /// and CS state persists across packets. Mode changes to the mode the
+    /// FFP is already in are left out.
+    pub(crate) fn packets(&self, mode: Mode) -> Vec<Packet> {
+        let mut encoder = Encoder {
+            packets: Vec::new(), packet: Vec::new(), rx: 0, packet_mode: None, mode,
+        };
+        for op in self.ops.iter() {
+            match op {
+                Op::Select => encoder.push(&[Opcode::Select as u8], 0),
+                Op::Unselect => encoder.push(&[Opcode::Unselect as u8], 0),
+                Op::Delay(us) => {
+                    let [lo, hi] = us.to_le_bytes();
+                    encoder.push(&[Opcode::Delay as u8, lo, hi], 0);
+                },
+                Op::SetMode(mode) if *mode != encoder.mode => {
+                    encoder.push(&[Opcode::SetMode as u8, *mode as u8], 0);
+                    encoder.packet_mode = Some(*mode);
+                    encoder.mode = *mode;
+                    encoder.flush();
+                },
+                Op::SetMode(_) => (),
+                Op::Transfer(data) => {
+                    let mut data = &data[..];
+                    while !data.is_empty() {
+                        let space = usize::min(encoder.space(2), encoder.rx_space(2));
+                        let n = usize::min(space, data.len());
+                        if n == 0 {
+                            encoder.flush();
+                            continue;
+                        }
+                        let mut op = vec![Opcode::Transfer as u8, n as u8];
+                        op.extend(&data[..n]);
+                        encoder.push(&op, n);
+                        data = &data[n..];
+                    }
+                },
+                Op::PageLoad(data) => {
+                    let mut offset = 0;
+                    while offset < data.len() {
+                        let n = usize::min(encoder.space(3), data.len() - offset);
+                        if n == 0 {
+                            encoder.flush();
+                            continue;
+                        }
+                        let mut op = vec![Opcode::PageLoad as u8, offset as u8, n as u8];
+                        op.extend(&data[offset..offset+n]);
+                        encoder.push(&op, 0);
+                        offset += n;
+                    }
+                },
+                Op::PageProgram(address, len) => {
+                    let [_, a2, a1, a0] = address.to_be_bytes();
+                    encoder.push(&[Opcode::PageProgram as u8, a2, a1, a0, *len as u8], 1);
+                },
+                Op::WaitBusy => encoder.push(&[Opcode::WaitBusy as u8], 1),
+                Op::CRC32(address, length) => {
+                    let [_, a2, a1, a0] = address.to_be_bytes();
+                    let [_, l2, l1, l0] = (*length as u32).to_be_bytes();
+                    encoder.push(&[Opcode::CRC32 as u8, a2, a1, a0, l2, l1, l0], 4);
+                },
+                Op::Read(nbytes, fill) => {
+                    let mut remaining = *nbytes;
+                    while remaining > 0 {
+                        let n = usize::min(encoder.rx_space(3), remaining);
+                        if n == 0 {
+                            encoder.flush();
+                            continue;
+                        }
+                        encoder.push(&[Opcode::Read as u8, n as u8, *fill], n);
+                        remaining -= n;
+                    }
+                },
+            }
+        }
+        encoder.finish()
+    }
+}
+
+/// One packet of an encoded `Batch`
+pub(crate) struct Packet {
+    pub(crate) data: Vec<u8>,
+    /// Number of bytes the FFP will send in reply
+    pub(crate) rx: usize,
+    /// Mode the FFP changes to after replying, if any
+    pub(crate) mode: Option<Mode>,
+}
+
+/// Accumulates encoded operations into packets
+struct Encoder {
+    packets: Vec<Packet>,
+    packet: Vec<u8>,
+    rx: usize,
+    packet_mode: Option<Mode>,
+    /// Mode the FFP will be in once all packets so far are sent
+    mode: Mode,
+}
+
+impl Encoder {
+    /// Number of data bytes which fit in the current packet after
+    /// an operation header of `header` bytes.
+    fn space(&self, header: usize) -> usize {
+        Batch::PACKET_SIZE.saturating_sub(self.packet.len() + header)
+    }
+
+    /// Number of bytes which may still be received by the current packet,
+    /// or 0 if an operation of `header` bytes does not fit.
+    fn rx_space(&self, header: usize) -> usize {
+        if self.packet.len() + header > Batch::PACKET_SIZE {
+            0
+        } else {
+            Batch::PACKET_SIZE - self.rx
+        }
+    }
+
+    /// Append encoded operation `op`, which will receive `rx` bytes
+    fn push(&mut self, op: &[u8], rx: usize) {
+        if self.packet.len() + op.len() > Batch::PACKET_SIZE
+           || self.rx + rx > Batch::PACKET_SIZE
+        {
+            self.flush();
+        }
+        self.packet.extend(op);
+        self.rx += rx;
+    }
+
+    /// Complete the current packet and start a new one
+    fn flush(&mut self) {
+        if !self.packet.is_empty() {
+            let data = std::mem::take(&mut self.packet);
+            let mode = self.packet_mode.take();
+            self.packets.push(Packet { data, rx: self.rx, mode });
+            self.rx = 0;
+        }
+    }
+
+    fn finish(mut self) -> Vec<Packet> {
+        self.flush();
+        self.packets
+    }
+}
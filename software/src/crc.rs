@@ -0,0 +1,30 @@
+/// Lookup table for the reflected CRC-32 polynomial 0xEDB88320
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Compute the standard (zlib) CRC-32 of `data`, matching the FFP firmware.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Update a running CRC-32 with `data`, without any initial or final inversion.
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        (crc >> 8) ^ TABLE[((crc ^ *byte as u32) & 0xFF) as usize]
+    })
+}
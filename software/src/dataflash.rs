@@ -0,0 +1,391 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::{Programmer, Flash, FFPError, Result, MemoryDevice, Mismatch};
+use crate::memory;
+
+#[derive(Copy, Clone, Debug)]
+#[allow(unused)]
+#[repr(u8)]
+enum Command {
+    ContinuousArrayRead = 0x0B,
+    ReadStatusRegister = 0xD7,
+    ReadJEDECID = 0x9F,
+    Buffer1Write = 0x84,
+    Buffer1ToMainMemoryWithErase = 0x83,
+    MainMemoryToBuffer1 = 0x53,
+    PageErase = 0x81,
+    BlockErase = 0x50,
+    ChipErase = 0xC7,
+    Configure = 0x3D,
+    DeepPowerDown = 0xB9,
+    ResumeFromDeepPowerDown = 0xAB,
+}
+
+/// Bits in the status register
+mod status {
+    pub const READY: u8 = 1 << 7;
+    pub const BINARY_PAGES: u8 = 1 << 0;
+}
+
+/// Family code in the top three bits of the first device ID byte
+const FAMILY_DATAFLASH: u8 = 0b001;
+
+/// Bytes following the chip erase command
+const CHIP_ERASE_SEQUENCE: [u8; 3] = [0x94, 0x80, 0x9A];
+
+/// Bytes following the configure command to select power-of-2 page sizes
+const BINARY_PAGES_SEQUENCE: [u8; 3] = [0x2A, 0x80, 0xA6];
+
+/// A supported AT45DB part
+struct Part {
+    density: u8,
+    name: &'static str,
+    pages: usize,
+    /// Page size in the default DataFlash addressing mode
+    page_size: usize,
+}
+
+const PARTS: &[Part] = &[
+    Part { density: 0x02, name: "AT45DB011", pages: 512, page_size: 264 },
+    Part { density: 0x03, name: "AT45DB021", pages: 1024, page_size: 264 },
+    Part { density: 0x04, name: "AT45DB041", pages: 2048, page_size: 264 },
+    Part { density: 0x05, name: "AT45DB081", pages: 4096, page_size: 264 },
+    Part { density: 0x06, name: "AT45DB161", pages: 4096, page_size: 528 },
+    Part { density: 0x07, name: "AT45DB321", pages: 8192, page_size: 528 },
+    Part { density: 0x08, name: "AT45DB641", pages: 8192, page_size: 1056 },
+];
+
+#[derive(Copy, Clone, Debug)]
+pub struct DataFlashID {
+    manufacturer_id: u8,
+    device_id: u16,
+    name: &'static str,
+    page_size: usize,
+}
+
+impl std::fmt::Display for DataFlashID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Manufacturer {:02X}, Device {:04X} ({}, {} byte pages)",
+               self.manufacturer_id, self.device_id, self.name, self.page_size)
+    }
+}
+
+/// Adesto/Atmel AT45DB DataFlash manager
+///
+/// DataFlash pages are either the default DataFlash size (264, 528, or
+/// 1056 bytes) or the power-of-2 size (256, 512, or 1024 bytes), as
+/// reported by the status register. Addresses used by `read`, `program`,
+/// and `erase_range` are linear byte addresses over pages of whichever
+/// size is configured, so the capacity depends on the page size.
+pub struct DataFlash<'a> {
+    programmer: &'a Programmer,
+    id: DataFlashID,
+    part: &'static Part,
+    page_size: usize,
+    retries: usize,
+}
+
+impl<'a> DataFlash<'a> {
+    /// JEDEC manufacturer ID used by Atmel and Adesto
+    pub const MANUFACTURER_ID: u8 = 0x1F;
+
+    /// Number of pages erased by a block erase
+    const BLOCK_PAGES: usize = 8;
+
+    /// Maximum times to wait for each operation to finish, well beyond
+    /// the datasheet maximums of common parts
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const CHIP_ERASE_TIMEOUT: Duration = Duration::from_secs(600);
+
+    /// Check the JEDEC ID of the attached flash using the given `Programmer`,
+    /// returning a new `DataFlash` if it is a supported AT45DB part.
+    pub fn detect(programmer: &'a Programmer) -> Result<Option<Self>> {
+        programmer.reset()?;
+        memory::exchange(programmer, &[Command::ResumeFromDeepPowerDown as u8], 0)?;
+        let jedec = memory::exchange(programmer, &[Command::ReadJEDECID as u8], 3)?;
+        if jedec[0] != Self::MANUFACTURER_ID || jedec[1] >> 5 != FAMILY_DATAFLASH {
+            return Ok(None);
+        }
+        let part = match PARTS.iter().find(|p| p.density == jedec[1] & 0x1F) {
+            Some(part) => part,
+            None => return Ok(None),
+        };
+        let mut dataflash = DataFlash {
+            programmer,
+            id: DataFlashID {
+                manufacturer_id: jedec[0],
+                device_id: u16::from_be_bytes([jedec[1], jedec[2]]),
+                name: part.name,
+                page_size: part.page_size,
+            },
+            part,
+            page_size: part.page_size,
+            retries: 0,
+        };
+        dataflash.update_page_size()?;
+        Ok(Some(dataflash))
+    }
+
+    /// Set how many times `program` re-programs pages which fail
+    /// verification before giving up. Defaults to 0.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Get the attached DataFlash manufacturer and device IDs
+    pub fn id(&self) -> DataFlashID {
+        self.id
+    }
+
+    /// Get the currently configured page size in bytes
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Number of bytes in the device with the currently configured page size
+    pub fn capacity(&self) -> usize {
+        self.part.pages * self.page_size
+    }
+
+    /// Read `length` bytes of data starting at `address`
+    pub fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        self.check_range(address, length)?;
+        let mut tx = vec![Command::ContinuousArrayRead as u8];
+        tx.extend(&self.page_address(address)[..]);
+        tx.push(0);
+        memory::read_after(self.programmer, &tx, length)
+    }
+
+    /// Program `data` starting at `address`.
+    ///
+    /// Each page is loaded into the SRAM buffer then programmed with the
+    /// built-in erase, so no separate erase is required. Partial pages first
+    /// have their existing contents copied into the buffer.
+    ///
+    /// If `verify` is true, also read back the programmed data. Pages which
+    /// do not match are re-programmed up to the number of times set by
+    /// `set_retries`, after which FFPError::VerifyFailed is returned with
+    /// the number of mismatching pages.
+    pub fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        self.check_range(address, data.len())?;
+        let mut pages = self.pages(address, data);
+        let mut retries = 0;
+        loop {
+            for (page, column, page_data) in pages.iter() {
+                self.program_page(*page, *column, page_data)?;
+            }
+            if !verify {
+                return Ok(());
+            }
+
+            let programmed = self.read(address, data.len())?;
+            pages.retain(|(page, column, page_data)| {
+                let offset = page * self.page_size + column - address as usize;
+                programmed[offset..offset+page_data.len()] != **page_data
+            });
+            if pages.is_empty() {
+                return Ok(());
+            } else if retries == self.retries {
+                Err(FFPError::VerifyFailed { count: pages.len() })?;
+            }
+            retries += 1;
+        }
+    }
+
+    /// Compare the DataFlash contents starting at `address` with `data`,
+    /// returning a list of all ranges which did not match.
+    pub fn verify(&self, address: u32, data: &[u8]) -> Result<Vec<Mismatch>> {
+        let programmed = self.read(address, data.len())?;
+        Ok(Flash::compare(address, data, &programmed))
+    }
+
+    /// Check that all bytes in `range` are erased (0xFF).
+    ///
+    /// Returns a list of all ranges which were not blank.
+    pub fn blank_check(&self, range: Range<u32>) -> Result<Vec<Mismatch>> {
+        let length = range.end.saturating_sub(range.start) as usize;
+        self.verify(range.start, &vec![0xFF; length])
+    }
+
+    /// Erase entire DataFlash chip
+    pub fn erase(&self) -> Result<()> {
+        self.exchange(Command::ChipErase, &CHIP_ERASE_SEQUENCE, 0)?;
+        self.wait_while_busy(Self::CHIP_ERASE_TIMEOUT)
+    }
+
+    /// Erase `length` bytes starting at `address`.
+    ///
+    /// DataFlash can only be erased in whole pages. If `round_outward`
+    /// is true, the range is extended to the enclosing pages, otherwise
+    /// FFPError::UnalignedErase is returned unless it is already aligned.
+    /// Aligned groups of 8 pages are erased with a single block erase.
+    pub fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()> {
+        self.erase_range_interruptible(address, length, round_outward, &AtomicBool::new(false))
+    }
+
+    /// Erase `length` bytes starting at `address`, stopping early if
+    /// `interrupt` is set, for example by a Ctrl-C handler.
+    ///
+    /// The erase in progress when `interrupt` is set is always allowed to
+    /// finish, and then FFPError::EraseInterrupted is returned with the
+    /// address erased up to. Otherwise behaves as `erase_range`.
+    pub fn erase_range_interruptible(&self, address: u32, length: usize, round_outward: bool,
+                                     interrupt: &AtomicBool) -> Result<()>
+    {
+        self.check_range(address, length)?;
+        let start = address as usize;
+        let end = start + length;
+        let aligned = start.is_multiple_of(self.page_size) && end.is_multiple_of(self.page_size);
+        if !aligned && !round_outward {
+            Err(FFPError::UnalignedErase { address, length })?;
+        }
+        let mut page = start / self.page_size;
+        let end_page = end.div_ceil(self.page_size);
+        while page < end_page {
+            let address = self.page_address((page * self.page_size) as u32);
+            let command = if page.is_multiple_of(Self::BLOCK_PAGES)
+                             && end_page - page >= Self::BLOCK_PAGES
+            {
+                page += Self::BLOCK_PAGES;
+                Command::BlockErase
+            } else {
+                page += 1;
+                Command::PageErase
+            };
+            self.exchange(command, &address, 0)?;
+            self.wait_while_busy(Self::TIMEOUT)?;
+            if page < end_page && interrupt.load(Ordering::SeqCst) {
+                Err(FFPError::EraseInterrupted { address: (page * self.page_size) as u32 })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Permanently configure the DataFlash to use power-of-2 page sizes.
+    ///
+    /// On many parts this is one-time programmable and cannot be undone.
+    /// The device must be power cycled before the new page size takes effect.
+    pub fn configure_binary_pages(&mut self) -> Result<()> {
+        self.exchange(Command::Configure, &BINARY_PAGES_SEQUENCE, 0)?;
+        self.wait_while_busy(Self::TIMEOUT)?;
+        self.update_page_size()
+    }
+
+    /// Read the configured page size from the status register
+    fn update_page_size(&mut self) -> Result<()> {
+        self.page_size = if self.read_status()? & status::BINARY_PAGES != 0 {
+            1 << (usize::BITS - 1 - self.part.page_size.leading_zeros())
+        } else {
+            self.part.page_size
+        };
+        self.id.page_size = self.page_size;
+        Ok(())
+    }
+
+    /// Split `data` starting at `address` into the page number, starting
+    /// column, and data for each page it covers
+    fn pages<'d>(&self, address: u32, data: &'d [u8]) -> Vec<(usize, usize, &'d [u8])> {
+        let mut pages = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let page_address = address as usize + offset;
+            let column = page_address % self.page_size;
+            let n = usize::min(self.page_size - column, data.len() - offset);
+            pages.push((page_address / self.page_size, column, &data[offset..offset+n]));
+            offset += n;
+        }
+        pages
+    }
+
+    /// Load `data` into the page buffer at `column` and program it to `page`
+    fn program_page(&self, page: usize, column: usize, data: &[u8]) -> Result<()> {
+        let page_address = self.page_address((page * self.page_size) as u32);
+        if data.len() < self.page_size {
+            // Preserve the rest of the page by copying it into the buffer first
+            self.exchange(Command::MainMemoryToBuffer1, &page_address, 0)?;
+            self.wait_while_busy(Self::TIMEOUT)?;
+        }
+        let mut tx = (column as u32).to_be_bytes()[1..].to_vec();
+        tx.extend(data);
+        self.exchange(Command::Buffer1Write, &tx, 0)?;
+        self.exchange(Command::Buffer1ToMainMemoryWithErase, &page_address, 0)?;
+        self.wait_while_busy(Self::TIMEOUT)
+    }
+
+    /// Convert a linear byte address into the three address bytes sent to
+    /// the device, where the page number is shifted above the bits needed
+    /// to address a byte within a page.
+    fn page_address(&self, address: u32) -> [u8; 3] {
+        let page_size = self.page_size as u32;
+        let shift = u32::BITS - (page_size - 1).leading_zeros();
+        let device_address = ((address / page_size) << shift) | (address % page_size);
+        let bytes = device_address.to_be_bytes();
+        [bytes[1], bytes[2], bytes[3]]
+    }
+
+    fn check_range(&self, address: u32, length: usize) -> Result<()> {
+        if address as usize + length > self.capacity() {
+            Err(FFPError::AddressOutOfRange { address, length })?;
+        }
+        Ok(())
+    }
+
+    fn read_status(&self) -> Result<u8> {
+        self.exchange(Command::ReadStatusRegister, &[], 1).map(|data| data[0])
+    }
+
+    /// Wait for the DataFlash to become ready, returning `FlashTimeout`
+    /// if it is still busy after `timeout`
+    fn wait_while_busy(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.read_status()? & status::READY == 0 {
+            if Instant::now() > deadline {
+                Err(FFPError::FlashTimeout)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn exchange(&self, command: Command, data: &[u8], nbytes: usize) -> Result<Vec<u8>> {
+        let mut tx = vec![command as u8];
+        tx.extend(data);
+        memory::exchange(self.programmer, &tx, nbytes)
+    }
+}
+
+impl<'a> MemoryDevice for DataFlash<'a> {
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        DataFlash::read(self, address, length)
+    }
+
+    fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        DataFlash::program(self, address, data, verify)
+    }
+
+    fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()> {
+        DataFlash::erase_range(self, address, length, round_outward)
+    }
+
+    fn erase_range_interruptible(&self, address: u32, length: usize, round_outward: bool,
+                                 interrupt: &AtomicBool) -> Result<()>
+    {
+        DataFlash::erase_range_interruptible(self, address, length, round_outward, interrupt)
+    }
+
+    fn erase(&self) -> Result<()> {
+        DataFlash::erase(self)
+    }
+
+    fn verify(&self, address: u32, data: &[u8]) -> Result<Vec<Mismatch>> {
+        DataFlash::verify(self, address, data)
+    }
+
+    fn blank_check(&self, range: Range<u32>) -> Result<Vec<Mismatch>> {
+        DataFlash::blank_check(self, range)
+    }
+
+    fn capacity(&self) -> Result<usize> {
+        Ok(DataFlash::capacity(self))
+    }
+}
@@ -1,5 +1,11 @@
-use std::convert::TryInto;
-use crate::{Programmer, FFPError, Result};
+use std::cell::Cell;
+use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::{Programmer, Capability, Batch, FFPError, Result, MemoryDevice, crc32};
+use crate::memory;
+use crate::quirks::{Quirks, UniqueID, ResetSequence, Suspend};
 
 #[derive(Copy, Clone, Debug)]
 #[allow(unused)]
@@ -15,8 +21,6 @@ enum Command {
     BlockErase32KB = 0x52,
     BlockErase64KB = 0xD8,
     ChipErase = 0xC7,
-    ProgramSuspend = 0x75,
-    ProgramResume = 0x7A,
     PowerDown = 0xB9,
     ReadData = 0x03,
     FastRead = 0x0B,
@@ -27,41 +31,217 @@ enum Command {
     ReadSFDPRegister = 0x5A,
     EnableReset = 0x66,
     Reset = 0x99,
+    LegacyReset = 0xF0,
+    ResetQuadIO = 0xF5,
+    ExitQPI = 0xFF,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Step of the recovery sequence after which the flash responded
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecoveryStep {
+    /// The flash responded without any recovery
+    NotNeeded,
+    /// Clocking out 0xFF to reset continuous-read (XIP) mode bits
+    ModeBitReset,
+    /// Sending the QPI exit commands 0xFF and 0xF5
+    ExitQPI,
+    /// Sending the standard software reset
+    Reset,
+}
+
+impl std::fmt::Display for RecoveryStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RecoveryStep::NotNeeded => write!(f, "without recovery"),
+            RecoveryStep::ModeBitReset => write!(f, "after continuous-read mode bit reset"),
+            RecoveryStep::ExitQPI => write!(f, "after QPI exit"),
+            RecoveryStep::Reset => write!(f, "after software reset"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct FlashID {
     manufacturer_id: u8,
     device_id: u8,
-    unique_id: u64,
+    unique_id: Option<Vec<u8>>,
+    recovery: RecoveryStep,
+}
+
+impl FlashID {
+    /// JEDEC manufacturer ID
+    pub fn manufacturer_id(&self) -> u8 {
+        self.manufacturer_id
+    }
+
+    /// Manufacturer-specific device ID
+    pub fn device_id(&self) -> u8 {
+        self.device_id
+    }
+
+    /// Factory-programmed unique ID, if the flash has one
+    pub fn unique_id(&self) -> Option<&[u8]> {
+        self.unique_id.as_deref()
+    }
+
+    /// Recovery step after which the flash responded to its JEDEC ID command
+    pub fn recovery(&self) -> RecoveryStep {
+        self.recovery
+    }
 }
 
 impl std::fmt::Display for FlashID {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Manufacturer {:02X}, Device {:02X}, Unique ID {:016X}",
-               self.manufacturer_id, self.device_id, self.unique_id)
+        write!(f, "Manufacturer {:02X}, Device {:02X}, ", self.manufacturer_id, self.device_id)?;
+        match &self.unique_id {
+            Some(unique_id) => {
+                write!(f, "Unique ID ")?;
+                unique_id.iter().try_for_each(|b| write!(f, "{:02X}", b))
+            },
+            None => write!(f, "No Unique ID"),
+        }
+    }
+}
+
+/// A contiguous range of flash whose contents did not match the expected data
+#[derive(Copy, Clone, Debug)]
+pub struct Mismatch {
+    /// Address of the first mismatching byte
+    pub address: u32,
+    /// Number of consecutive mismatching bytes starting at `address`
+    pub length: usize,
+    /// Expected value of the first mismatching byte
+    pub expected: u8,
+    /// Value read from flash for the first mismatching byte
+    pub actual: u8,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{:06X}..0x{:06X}: expected {:02X}, read {:02X}",
+               self.address, self.address as usize + self.length,
+               self.expected, self.actual)
+    }
+}
+
+/// Address, expected data, and read data of a chunk of flash
+type Readback<'d> = (u32, &'d [u8], Vec<u8>);
+
+/// Bit errors found in a single flash page during verification
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageMismatch {
+    /// Address of the start of the page
+    pub address: u32,
+    /// Number of bytes in the page which did not match
+    pub bytes: usize,
+    /// Number of bits which should have been 1 but read as 0
+    pub stuck_at_0: usize,
+    /// Number of bits which should have been 0 but read as 1
+    pub stuck_at_1: usize,
+    /// Bit positions which read as 0 instead of 1 in any byte of the page
+    pub stuck_at_0_mask: u8,
+    /// Bit positions which read as 1 instead of 0 in any byte of the page
+    pub stuck_at_1_mask: u8,
+}
+
+impl PageMismatch {
+    /// Total number of flipped bits in this page
+    pub fn flipped_bits(&self) -> usize {
+        self.stuck_at_0 + self.stuck_at_1
+    }
+}
+
+impl std::fmt::Display for PageMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "page 0x{:06X}: {} bytes, {} bits flipped \
+                   ({} stuck at 0 in bits {:08b}, {} stuck at 1 in bits {:08b})",
+               self.address, self.bytes, self.flipped_bits(),
+               self.stuck_at_0, self.stuck_at_0_mask, self.stuck_at_1, self.stuck_at_1_mask)
+    }
+}
+
+/// Report of all pages which failed verification after programming
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Each mismatching page, in address order
+    pub pages: Vec<PageMismatch>,
+    /// Number of times failing sectors were re-erased and re-programmed
+    pub retries: usize,
+}
+
+impl VerifyReport {
+    /// Check if no mismatches were found
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Total number of flipped bits over all pages
+    pub fn flipped_bits(&self) -> usize {
+        self.pages.iter().map(|p| p.flipped_bits()).sum()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} page(s) mismatched with {} bits flipped after {} retries",
+               self.pages.len(), self.flipped_bits(), self.retries)?;
+        for page in self.pages.iter() {
+            write!(f, "\n  {}", page)?;
+        }
+        Ok(())
     }
 }
 
 /// Flash manager
 pub struct Flash<'a> {
     programmer: &'a Programmer,
+    retries: usize,
+    /// Vendor quirks found by the last `read_id`, as the JEDEC ID
+    /// cannot be read while the flash is busy
+    quirks: Cell<Option<Quirks>>,
 }
 
 impl<'a> Flash<'a> {
+    pub(crate) const SECTOR_SIZE: usize = 4096;
+    const PAGE_SIZE: usize = 256;
+    /// Maximum time to wait for an erase or program to suspend
+    const SUSPEND_TIMEOUT: Duration = Duration::from_millis(10);
+    /// Maximum times to wait for each operation to finish, well beyond
+    /// the datasheet maximums of common parts
+    const PROGRAM_TIMEOUT: Duration = Duration::from_millis(100);
+    const ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+    const CHIP_ERASE_TIMEOUT: Duration = Duration::from_secs(600);
+
     /// Create a new `Flash` using the given `Programmer`
     pub fn new(programmer: &'a Programmer) -> Self {
-        Self { programmer }
+        Self { programmer, retries: 0, quirks: Cell::new(None) }
+    }
+
+    /// Set how many times `program` re-erases and re-programs sectors
+    /// which fail verification before giving up. Defaults to 0.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
     }
 
-    /// Read the attached flash device, manufacturer, and unique IDs
+    /// Read the attached flash device, manufacturer, and unique IDs.
+    ///
+    /// If the flash does not respond, it is first recovered using `recover`.
     pub fn read_id(&self) -> Result<FlashID> {
         self.programmer.reset()?;
         self.power_up()?;
-        self.reset()?;
+        let recovery = self.recover()?;
+        let quirks = self.quirks()?;
+        self.quirks.set(Some(quirks));
+        self.reset_with(quirks)?;
         let (manufacturer_id, device_id) = self.read_device_id()?;
-        let unique_id = self.read_unique_id()?;
-        Ok(FlashID { manufacturer_id, device_id, unique_id })
+        let unique_id = self.read_unique_id(quirks)?;
+        Ok(FlashID { manufacturer_id, device_id, unique_id, recovery })
+    }
+
+    /// Read the attached flash JEDEC manufacturer, memory type, and capacity IDs
+    pub fn jedec_id(&self) -> Result<[u8; 3]> {
+        let (manufacturer_id, memory_type, capacity) = self.read_jedec_id()?;
+        Ok([manufacturer_id, memory_type, capacity])
     }
 
     /// Read `length` bytes of data from the attached flash, starting at `address`
@@ -71,35 +251,306 @@ impl<'a> Flash<'a> {
 
     /// Program the attached flash with `data` starting at `address`.
     ///
-    /// If `verify` is true, also read-back the programmed data and
-    /// return FFPError::ReadbackError if it did not match what was written.
+    /// If `verify` is true, also check the programmed data. Any sectors
+    /// which do not match are re-erased and re-programmed up to the number
+    /// of times set by `set_retries`, after which FFPError::ReadbackError
+    /// is returned with a report of every mismatching page.
+    /// When the FFP supports it, each sector's CRC-32 is compared first,
+    /// and only sectors with a mismatching CRC are read back.
     pub fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
-        self.erase_for_data(address, data.len())?;
+        self.erase_range(address, data.len(), true)?;
         self.program_data(address, data)?;
-        if verify {
-            let programmed = self.read(address, data.len())?;
-            if programmed == data {
-                Ok(())
-            } else {
-                Err(FFPError::ReadbackError)?
+        if !verify {
+            return Ok(());
+        }
+
+        let mut report = self.verify_report(address, data)?;
+        while !report.is_empty() && report.retries < self.retries {
+            let mut sectors: Vec<u32> = report.pages.iter()
+                .map(|p| p.address & !(Self::SECTOR_SIZE as u32 - 1))
+                .collect();
+            sectors.dedup();
+
+            // Re-erase and re-program only the failing sectors, then
+            // verify just those sectors again.
+            let mut pages = Vec::new();
+            for (chunk_address, chunk) in Self::sector_chunks(address, data) {
+                let sector = chunk_address & !(Self::SECTOR_SIZE as u32 - 1);
+                if !sectors.contains(&sector) {
+                    continue;
+                }
+                self.write_enable()?;
+                self.sector_erase(sector)?;
+                self.wait_while_busy(Self::ERASE_TIMEOUT)?;
+                self.program_data(chunk_address, chunk)?;
+                pages.extend(self.verify_report(chunk_address, chunk)?.pages);
             }
-        } else {
+            report = VerifyReport { pages, retries: report.retries + 1 };
+        }
+
+        if report.is_empty() {
             Ok(())
+        } else {
+            Err(FFPError::ReadbackError { report })?
+        }
+    }
+
+    /// Compare the attached flash contents starting at `address` with `data`.
+    ///
+    /// Returns a list of all ranges which did not match, which is empty
+    /// if the flash contents are identical to `data`. When the FFP supports
+    /// it, each sector's CRC-32 is compared first, and only sectors with
+    /// a mismatching CRC are read back.
+    pub fn verify(&self, address: u32, data: &[u8]) -> Result<Vec<Mismatch>> {
+        // Join any ranges which continue over a sector boundary
+        let mut mismatches: Vec<Mismatch> = Vec::new();
+        for (chunk_address, chunk, programmed) in self.readback_differing(address, data)? {
+            for mismatch in Self::compare(chunk_address, chunk, &programmed) {
+                match mismatches.last_mut() {
+                    Some(last) if last.address as usize + last.length == mismatch.address as usize
+                        => last.length += mismatch.length,
+                    _ => mismatches.push(mismatch),
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Compare the attached flash contents starting at `address` with `data`,
+    /// returning a report of the flipped bits in each mismatching page.
+    pub fn verify_report(&self, address: u32, data: &[u8]) -> Result<VerifyReport> {
+        let mut pages = Vec::new();
+        for (chunk_address, chunk, programmed) in self.readback_differing(address, data)? {
+            let mut offset = 0;
+            while offset < chunk.len() {
+                let page_address = chunk_address + offset as u32;
+                let page_remaining = Self::PAGE_SIZE - (page_address as usize % Self::PAGE_SIZE);
+                let end = usize::min(offset + page_remaining, chunk.len());
+                if let Some(page) = Self::compare_page(
+                    page_address, &chunk[offset..end], &programmed[offset..end])
+                {
+                    pages.push(page);
+                }
+                offset = end;
+            }
+        }
+        Ok(VerifyReport { pages, retries: 0 })
+    }
+
+    /// Check that all bytes in `range` of the attached flash are erased (0xFF).
+    ///
+    /// Returns a list of all ranges which were not blank.
+    pub fn blank_check(&self, range: Range<u32>) -> Result<Vec<Mismatch>> {
+        let length = range.end.saturating_sub(range.start) as usize;
+        self.verify(range.start, &vec![0xFF; length])
+    }
+
+    /// Read the capacity of the attached flash in bytes from its JEDEC ID.
+    ///
+    /// Capacities above 16MB are reported as 16MB, as only 3-byte
+    /// addressing is used.
+    pub fn capacity(&self) -> Result<usize> {
+        let (_, _, capacity) = self.read_jedec_id()?;
+        match capacity {
+            0x10..=0x18 => Ok(1 << capacity),
+            0x19..=0x22 => Ok(1 << 24),
+            _ => Err(FFPError::UnknownCapacity { capacity })?,
         }
     }
 
-    /// Erase entire flash chip
+    /// Erase entire flash chip.
+    ///
+    /// A chip erase cannot be interrupted or suspended, so this blocks until
+    /// the erase has finished; use `erase_range_interruptible` to erase in
+    /// blocks which can be stopped part way through.
     pub fn erase(&self) -> Result<()> {
         self.write_enable()?;
         self.chip_erase()?;
-        self.wait_while_busy()?;
+        self.wait_while_busy(Self::CHIP_ERASE_TIMEOUT)?;
         Ok(())
     }
 
-    /// Reset the attached flash
-    pub fn reset(&self) -> Result<()> {
+    /// Erase `length` bytes of the attached flash starting at `address`.
+    ///
+    /// Flash can only be erased in whole 4KB sectors. If `round_outward`
+    /// is true, the range is extended to the enclosing sectors, otherwise
+    /// FFPError::UnalignedErase is returned unless it is already aligned.
+    /// The largest possible 64KB, 32KB, or 4KB erase is used at each step.
+    pub fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()> {
+        self.erase_range_interruptible(address, length, round_outward, &AtomicBool::new(false))
+    }
+
+    /// Erase `length` bytes of the attached flash starting at `address`,
+    /// stopping early if `interrupt` is set, for example by a Ctrl-C handler.
+    ///
+    /// The erase in progress when `interrupt` is set is always allowed to
+    /// finish, so no block is left partially erased, and then
+    /// FFPError::EraseInterrupted is returned with the address erased up to.
+    /// Otherwise behaves as `erase_range`.
+    pub fn erase_range_interruptible(&self, address: u32, length: usize, round_outward: bool,
+                                     interrupt: &AtomicBool) -> Result<()>
+    {
+        const BLOCK_32K: u32 = 32 * 1024;
+        const BLOCK_64K: u32 = 64 * 1024;
+        let sector = Self::SECTOR_SIZE as u32;
+        let out_of_range = || FFPError::AddressOutOfRange { address, length };
+        let mut start = address;
+        let mut end = u32::try_from(length).ok()
+            .and_then(|length| address.checked_add(length))
+            .ok_or_else(out_of_range)?;
+        if !start.is_multiple_of(sector) || !end.is_multiple_of(sector) {
+            if !round_outward {
+                Err(FFPError::UnalignedErase { address, length })?;
+            }
+            start -= start % sector;
+            end = end.div_ceil(sector).checked_mul(sector).ok_or_else(out_of_range)?;
+        }
+
+        while start < end {
+            let remaining = end - start;
+            self.write_enable()?;
+            let size = if start.is_multiple_of(BLOCK_64K) && remaining >= BLOCK_64K {
+                self.block_erase_64k(start)?;
+                BLOCK_64K
+            } else if start.is_multiple_of(BLOCK_32K) && remaining >= BLOCK_32K {
+                self.block_erase_32k(start)?;
+                BLOCK_32K
+            } else {
+                self.sector_erase(start)?;
+                sector
+            };
+            self.wait_while_busy(Self::ERASE_TIMEOUT)?;
+            start += size;
+            if start < end && interrupt.load(Ordering::SeqCst) {
+                Err(FFPError::EraseInterrupted { address: start })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start erasing the entire flash chip without waiting for it to finish.
+    ///
+    /// Use `is_busy` to check for completion. Not all flash supports
+    /// suspending a chip erase; use `start_block_erase` if reads are
+    /// required while erasing.
+    pub fn start_erase(&self) -> Result<()> {
+        self.write_enable()?;
+        self.chip_erase()
+    }
+
+    /// Start erasing the 64KB block at `address` without waiting for it to finish.
+    ///
+    /// Use `is_busy` to check for completion, or `suspend` to pause it.
+    pub fn start_block_erase(&self, address: u32) -> Result<()> {
+        if !address.is_multiple_of(64 * 1024) {
+            Err(FFPError::UnalignedErase { address, length: 64 * 1024 })?;
+        }
+        self.write_enable()?;
+        self.block_erase_64k(address)
+    }
+
+    /// Check if the attached flash is busy erasing or programming
+    pub fn is_busy(&self) -> Result<bool> {
+        self.read_status1().map(|status| status & 1 == 1)
+    }
+
+    /// Check if an erase or program is currently suspended, from the
+    /// manufacturer's suspend status bits.
+    ///
+    /// Returns `SuspendUnsupported` if the manufacturer's suspend commands are not known.
+    pub fn is_suspended(&self) -> Result<bool> {
+        let (_, _, status, mask) = self.suspend_commands()?;
+        let data = memory::exchange(self.programmer, &[status], 1)?;
+        Ok(data[0] & mask != 0)
+    }
+
+    /// Suspend an erase or program in progress.
+    ///
+    /// Returns true if the operation was suspended, in which case the flash
+    /// may be read but not otherwise modified until `resume` is called.
+    /// Returns false if the flash was not busy or the operation finished
+    /// before it could be suspended.
+    ///
+    /// Returns `SuspendUnsupported` if the manufacturer's suspend commands are
+    /// not known. Call `read_id` before starting the erase or program, as the
+    /// manufacturer cannot be identified while the flash is busy.
+    pub fn suspend(&self) -> Result<bool> {
+        let (suspend, _, _, _) = self.suspend_commands()?;
+        if !self.is_busy()? {
+            return Ok(false);
+        }
+        memory::exchange(self.programmer, &[suspend], 0)?;
+
+        // Flash takes tens of microseconds to suspend
+        let deadline = Instant::now() + Self::SUSPEND_TIMEOUT;
+        while self.is_busy()? {
+            if Instant::now() > deadline {
+                Err(FFPError::FlashTimeout)?;
+            }
+        }
+        self.is_suspended()
+    }
+
+    /// Resume a suspended erase or program, if any
+    pub fn resume(&self) -> Result<()> {
+        let (_, resume, _, _) = self.suspend_commands()?;
+        if self.is_suspended()? {
+            memory::exchange(self.programmer, &[resume], 0)?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes starting at `address`, suspending any erase or
+    /// program in progress for the duration of the read.
+    pub fn read_suspending(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        let suspended = self.suspend()?;
+        let data = self.read(address, length)?;
+        if suspended {
+            self.resume()?;
+        }
+        Ok(data)
+    }
+
+    /// Bring the attached flash back to standard SPI mode if it does not respond.
+    ///
+    /// A flash left in continuous-read (XIP) or QPI mode by the FPGA reads
+    /// back its JEDEC ID as all 0xFF. Each recovery step is tried in turn
+    /// until the flash responds: clocking out 0xFF to clear the continuous
+    /// read mode bits, sending the QPI exit commands, then sending the
+    /// standard software reset. Returns the step after which the flash
+    /// responded, or FFPError::FlashNotResponding if none succeeded.
+    pub fn recover(&self) -> Result<RecoveryStep> {
+        if self.responding()? {
+            return Ok(RecoveryStep::NotNeeded);
+        }
+
+        // Sixteen clocks of 0xFF clear the mode bits in both dual and quad modes
+        memory::exchange(self.programmer, &[0xFF; 2], 0)?;
+        if self.responding()? {
+            return Ok(RecoveryStep::ModeBitReset);
+        }
+
+        // Winbond and ISSI exit QPI on 0xFF, Macronix on 0xF5
+        self.command(Command::ExitQPI)?;
+        self.command(Command::ResetQuadIO)?;
+        if self.responding()? {
+            return Ok(RecoveryStep::ExitQPI);
+        }
+
         self.command(Command::EnableReset)?;
-        self.command(Command::Reset)
+        self.command(Command::Reset)?;
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        self.power_up()?;
+        if self.responding()? {
+            return Ok(RecoveryStep::Reset);
+        }
+
+        Err(FFPError::FlashNotResponding)?
+    }
+
+    /// Reset the attached flash, using the reset sequence for its manufacturer
+    pub fn reset(&self) -> Result<()> {
+        self.reset_with(self.quirks()?)
     }
 
     /// Power down the attached flash
@@ -112,25 +563,9 @@ impl<'a> Flash<'a> {
         self.command(Command::ReleasePowerdown)
     }
 
-    fn erase_for_data(&self, address: u32, length: usize) -> Result<()> {
-        // Adjust length and address to be 64K aligned
-        const BLOCK_SIZE: usize = 64 * 1024;
-        let length = length + (address as usize % BLOCK_SIZE) as usize;
-        let address = address & 0xFF0000;
-        let mut n_blocks = length / BLOCK_SIZE;
-        if length % BLOCK_SIZE != 0 { n_blocks += 1 };
-        for block in 0..n_blocks {
-            self.write_enable()?;
-            self.block_erase_64k(address + (block * BLOCK_SIZE) as u32)?;
-            self.wait_while_busy()?;
-        }
-        Ok(())
-    }
-
     fn program_data(&self, address: u32, data: &[u8]) -> Result<()> {
         // Pad to obtain page alignment
-        const PAGE_SIZE: usize = 256;
-        let pad_length = address as usize % PAGE_SIZE;
+        let pad_length = address as usize % Self::PAGE_SIZE;
         let tx = if pad_length != 0 {
             let mut tx = vec![0xFF; pad_length];
             tx.extend(data);
@@ -141,14 +576,131 @@ impl<'a> Flash<'a> {
         let address = address & 0xFFFF00;
 
         // Write pages
-        for (idx, page_data) in tx.chunks(PAGE_SIZE).enumerate() {
-            self.write_enable()?;
-            self.page_program(address + (idx*PAGE_SIZE) as u32, page_data)?;
-            self.wait_while_busy()?;
+        for (idx, page_data) in tx.chunks(Self::PAGE_SIZE).enumerate() {
+            let page_address = address + (idx*Self::PAGE_SIZE) as u32;
+            if self.programmer.has_capability(Capability::PageProgram) {
+                // Write enable, program, and wait for completion all on the FFP
+                let batch = Batch::new().flash_mode().page_program(page_address, page_data);
+                if self.programmer.batch(&batch)? != [0] {
+                    Err(FFPError::FlashTimeout)?;
+                }
+                continue;
+            } else if self.batched() {
+                // Send write enable and page program in a single batch
+                let mut pp = vec![Command::PageProgram as u8];
+                pp.extend(&page_address.to_be_bytes()[1..]);
+                pp.extend(page_data);
+                self.programmer.batch(&Batch::new()
+                    .flash_mode()
+                    .select().transfer(&[Command::WriteEnable as u8]).unselect()
+                    .select().transfer(&pp).unselect())?;
+            } else {
+                self.write_enable()?;
+                self.page_program(page_address, page_data)?;
+            }
+            self.wait_while_busy(Self::PROGRAM_TIMEOUT)?;
         }
         Ok(())
     }
 
+    /// Find all ranges where `actual` differs from `expected`, which both start at `address`.
+    pub(crate) fn compare(address: u32, expected: &[u8], actual: &[u8]) -> Vec<Mismatch> {
+        let mut mismatches: Vec<Mismatch> = Vec::new();
+        let mut in_range = false;
+        for (idx, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+            if e == a {
+                in_range = false;
+                continue;
+            }
+            match mismatches.last_mut() {
+                Some(last) if in_range => last.length += 1,
+                _ => mismatches.push(Mismatch {
+                    address: address + idx as u32, length: 1, expected: *e, actual: *a,
+                }),
+            }
+            in_range = true;
+        }
+        mismatches
+    }
+
+    /// Count the flipped bits between `expected` and `actual` for the page
+    /// containing `address`, returning None if they are identical.
+    fn compare_page(address: u32, expected: &[u8], actual: &[u8]) -> Option<PageMismatch> {
+        let mut page = PageMismatch {
+            address: address & !(Self::PAGE_SIZE as u32 - 1),
+            bytes: 0, stuck_at_0: 0, stuck_at_1: 0, stuck_at_0_mask: 0, stuck_at_1_mask: 0,
+        };
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            if e == a {
+                continue;
+            }
+            let stuck_at_0 = e & !a;
+            let stuck_at_1 = !e & a;
+            page.bytes += 1;
+            page.stuck_at_0 += stuck_at_0.count_ones() as usize;
+            page.stuck_at_1 += stuck_at_1.count_ones() as usize;
+            page.stuck_at_0_mask |= stuck_at_0;
+            page.stuck_at_1_mask |= stuck_at_1;
+        }
+        if page.bytes > 0 { Some(page) } else { None }
+    }
+
+    /// Read back each sector-sized chunk of `data` at `address` which may not
+    /// match the flash contents, returning the address, expected data, and
+    /// read data of each chunk. When the FFP supports it, chunks whose CRC-32
+    /// matches are skipped without being read back.
+    fn readback_differing<'d>(&self, address: u32, data: &'d [u8])
+        -> Result<Vec<Readback<'d>>>
+    {
+        let chunks = Self::sector_chunks(address, data);
+
+        // Without on-device CRC support, read back the entire region at once
+        if !self.programmer.has_capability(Capability::CRC32) {
+            let programmed = self.read(address, data.len())?;
+            let mut offset = 0;
+            let mut differing = Vec::new();
+            for (chunk_address, chunk) in chunks {
+                let readback = &programmed[offset..offset+chunk.len()];
+                if chunk != readback {
+                    differing.push((chunk_address, chunk, readback.to_vec()));
+                }
+                offset += chunk.len();
+            }
+            return Ok(differing);
+        }
+
+        // Compute the CRC-32 of each sector on the FFP
+        let batch = chunks.iter().fold(Batch::new().flash_mode(), |batch, (address, chunk)| {
+            batch.crc32(*address, chunk.len())
+        });
+        let crcs = self.programmer.batch(&batch)?;
+
+        // Read back any sectors with a mismatching CRC
+        let mut differing = Vec::new();
+        for ((chunk_address, chunk), crc) in chunks.into_iter().zip(crcs.chunks(4)) {
+            if crc32(chunk) != u32::from_le_bytes(crc.try_into()?) {
+                differing.push((chunk_address, chunk, self.read(chunk_address, chunk.len())?));
+            }
+        }
+        Ok(differing)
+    }
+
+    /// Split `data` to be written at `address` into chunks which do not cross
+    /// sector boundaries, returning the flash address of each chunk.
+    fn sector_chunks(address: u32, data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut chunks = Vec::new();
+        let mut address = address;
+        let mut data = data;
+        while !data.is_empty() {
+            let sector_remaining = Self::SECTOR_SIZE - (address as usize % Self::SECTOR_SIZE);
+            let n = usize::min(sector_remaining, data.len());
+            chunks.push((address, &data[..n]));
+            address += n as u32;
+            data = &data[n..];
+        }
+        chunks
+    }
+
     fn write_enable(&self) -> Result<()> {
         self.command(Command::WriteEnable)
     }
@@ -168,9 +720,11 @@ impl<'a> Flash<'a> {
     }
 
     fn fast_read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
-        let length = length + 1;
-        let address = &address.to_be_bytes()[1..];
-        self.exchange(Command::FastRead, address, length).map(|data| data[1..].to_vec())
+        // Send command, address, and dummy byte, then stream the data out
+        let mut tx = vec![Command::FastRead as u8];
+        tx.extend(&address.to_be_bytes()[1..]);
+        tx.push(0);
+        memory::read_after(self.programmer, &tx, length)
     }
 
     fn chip_erase(&self) -> Result<()> {
@@ -182,13 +736,11 @@ impl<'a> Flash<'a> {
         Ok(())
     }
 
-    #[allow(dead_code)]
     fn block_erase_32k(&self, address: u32) -> Result<()> {
         self.exchange(Command::BlockErase32KB, &address.to_be_bytes()[1..], 0)?;
         Ok(())
     }
 
-    #[allow(dead_code)]
     fn sector_erase(&self, address: u32) -> Result<()> {
         self.exchange(Command::SectorErase, &address.to_be_bytes()[1..], 0)?;
         Ok(())
@@ -199,39 +751,94 @@ impl<'a> Flash<'a> {
             .map(|data| (data[3], data[4]))
     }
 
-    fn read_unique_id(&self) -> Result<u64> {
-        self.exchange(Command::ReadUniqueID, &[], 4+8)
-            .and_then(|data| Ok(u64::from_be_bytes((&data[4..]).try_into()?)))
+    fn read_jedec_id(&self) -> Result<(u8, u8, u8)> {
+        self.exchange(Command::ReadJEDECID, &[], 3)
+            .map(|data| (data[0], data[1], data[2]))
     }
 
-    fn read_status1(&self) -> Result<u8> {
-        self.exchange(Command::ReadStatusRegister1, &[], 1).map(|data| data[0])
+    /// Look up the vendor quirks for the attached flash from its JEDEC ID
+    fn quirks(&self) -> Result<Quirks> {
+        let (manufacturer_id, _, _) = self.read_jedec_id()?;
+        Ok(Quirks::for_manufacturer(manufacturer_id))
     }
 
-    #[allow(dead_code)]
-    fn read_status2(&self) -> Result<u8> {
-        self.exchange(Command::ReadStatusRegister2, &[], 1).map(|data| data[0])
+    /// Look up the suspend, resume, and suspend status commands and mask,
+    /// using the quirks from `read_id` if it has been called
+    fn suspend_commands(&self) -> Result<(u8, u8, u8, u8)> {
+        let quirks = match self.quirks.get() {
+            Some(quirks) => quirks,
+            None => self.quirks()?,
+        };
+        match quirks.suspend {
+            Suspend::Command { suspend, resume, status, mask } => Ok((suspend, resume, status, mask)),
+            Suspend::None =>
+                Err(FFPError::SuspendUnsupported { manufacturer_id: quirks.manufacturer_id })?,
+        }
     }
 
-    fn is_busy(&self) -> Result<bool> {
-        self.read_status1().map(|status| status & 1 == 1)
+    fn reset_with(&self, quirks: Quirks) -> Result<()> {
+        match quirks.reset {
+            ResetSequence::EnableReset => {
+                self.command(Command::EnableReset)?;
+                self.command(Command::Reset)
+            },
+            ResetSequence::Legacy => self.command(Command::LegacyReset),
+        }
     }
 
-    fn wait_while_busy(&self) -> Result<()> {
-        while self.is_busy()? {}
-        Ok(())
+    fn read_unique_id(&self, quirks: Quirks) -> Result<Option<Vec<u8>>> {
+        match quirks.unique_id {
+            UniqueID::Command { command, dummy, length } => {
+                let data = memory::exchange(self.programmer, &[command], dummy+length)?;
+                Ok(Some(data[dummy..].to_vec()))
+            },
+            UniqueID::ExtendedJEDECID { offset, length } => {
+                let data = self.exchange(Command::ReadJEDECID, &[], offset+length)?;
+                Ok(Some(data[offset..].to_vec()))
+            },
+            UniqueID::None => Ok(None),
+        }
+    }
+
+    /// Check if the JEDEC ID reads back as anything other than all 0x00 or 0xFF
+    fn responding(&self) -> Result<bool> {
+        let (manufacturer_id, _, _) = self.read_jedec_id()?;
+        Ok(manufacturer_id != 0x00 && manufacturer_id != 0xFF)
+    }
+
+    fn read_status1(&self) -> Result<u8> {
+        self.exchange(Command::ReadStatusRegister1, &[], 1).map(|data| data[0])
+    }
+
+    /// Wait for the flash to finish an erase or program, returning
+    /// `FlashTimeout` if it is still busy after `timeout`
+    fn wait_while_busy(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let busy = if self.programmer.has_capability(Capability::PageProgram) {
+                // The FFP polls the flash itself, replying 1 if still busy
+                self.programmer.batch(&Batch::new().flash_mode().wait_busy())? != [0]
+            } else {
+                self.is_busy()?
+            };
+            if !busy {
+                return Ok(());
+            } else if Instant::now() > deadline {
+                Err(FFPError::FlashTimeout)?;
+            }
+        }
+    }
+
+    /// Check if flash commands can be sent as a single batch
+    fn batched(&self) -> bool {
+        self.programmer.has_capability(Capability::Batch)
     }
 
     /// Writes `command` and `data` to the flash memory, then returns `nbytes` of response.
     fn exchange(&self, command: Command, data: &[u8], nbytes: usize) -> Result<Vec<u8>> {
         let mut tx = vec![command as u8];
         tx.extend(data);
-        tx.extend(vec![0u8; nbytes]);
-        self.programmer.flash_mode()?;
-        self.programmer.select()?;
-        let rx = self.programmer.write(&tx)?;
-        self.programmer.unselect()?;
-        Ok(rx[1+data.len()..].to_vec())
+        memory::exchange(self.programmer, &tx, nbytes)
     }
 
     /// Convenience method for issuing a single command and not caring about the returned data
@@ -240,3 +847,39 @@ impl<'a> Flash<'a> {
         Ok(())
     }
 }
+
+impl<'a> MemoryDevice for Flash<'a> {
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        Flash::read(self, address, length)
+    }
+
+    fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        Flash::program(self, address, data, verify)
+    }
+
+    fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()> {
+        Flash::erase_range(self, address, length, round_outward)
+    }
+
+    fn erase_range_interruptible(&self, address: u32, length: usize, round_outward: bool,
+                                 interrupt: &AtomicBool) -> Result<()>
+    {
+        Flash::erase_range_interruptible(self, address, length, round_outward, interrupt)
+    }
+
+    fn erase(&self) -> Result<()> {
+        Flash::erase(self)
+    }
+
+    fn verify(&self, address: u32, data: &[u8]) -> Result<Vec<Mismatch>> {
+        Flash::verify(self, address, data)
+    }
+
+    fn blank_check(&self, range: Range<u32>) -> Result<Vec<Mismatch>> {
+        Flash::blank_check(self, range)
+    }
+
+    fn capacity(&self) -> Result<usize> {
+        Flash::capacity(self)
+    }
+}
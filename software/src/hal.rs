@@ -0,0 +1,209 @@
+//! embedded-hal 1.0 implementations using an FFP.
+//!
+//! These allow existing embedded-hal device drivers to be used from the
+//! host, talking to devices over the FFP's SPI bus. The SPI bus is used in
+//! whichever mode the `Programmer` is currently in, so call
+//! `Programmer::flash_mode` or `Programmer::fpga_mode` first.
+
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+use embedded_hal::{digital, spi};
+use crate::Programmer;
+
+/// Error from an FFP operation made through an embedded-hal trait
+#[derive(Debug)]
+pub struct Error(pub failure::Error);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<failure::Error> for Error {
+    fn from(error: failure::Error) -> Self {
+        Error(error)
+    }
+}
+
+impl spi::Error for Error {
+    fn kind(&self) -> spi::ErrorKind {
+        spi::ErrorKind::Other
+    }
+}
+
+impl digital::Error for Error {
+    fn kind(&self) -> digital::ErrorKind {
+        digital::ErrorKind::Other
+    }
+}
+
+/// The FFP's SPI bus, without control of CS
+pub struct SpiBus<'a> {
+    programmer: &'a Programmer,
+}
+
+impl<'a> SpiBus<'a> {
+    /// Byte clocked out while reading
+    const FILL: u8 = 0x00;
+
+    /// Create a new `SpiBus` using the given `Programmer`
+    pub fn new(programmer: &'a Programmer) -> Self {
+        Self { programmer }
+    }
+}
+
+impl spi::ErrorType for SpiBus<'_> {
+    type Error = Error;
+}
+
+impl spi::SpiBus for SpiBus<'_> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        words.copy_from_slice(&self.programmer.read(words.len(), Self::FILL)?);
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        self.programmer.write(words)?;
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        let mut tx = write.to_vec();
+        tx.resize(usize::max(read.len(), write.len()), Self::FILL);
+        let rx = self.programmer.write(&tx)?;
+        read.copy_from_slice(&rx[..read.len()]);
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        let rx = self.programmer.write(words)?;
+        words.copy_from_slice(&rx);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        // All transfers have completed by the time they return
+        Ok(())
+    }
+}
+
+/// A device on the FFP's SPI bus, selected using the FFP's CS
+pub struct SpiDevice<'a> {
+    programmer: &'a Programmer,
+    bus: SpiBus<'a>,
+}
+
+impl<'a> SpiDevice<'a> {
+    /// Create a new `SpiDevice` using the given `Programmer`
+    pub fn new(programmer: &'a Programmer) -> Self {
+        Self { programmer, bus: SpiBus::new(programmer) }
+    }
+
+    fn operation(&mut self, operation: &mut spi::Operation<u8>) -> Result<(), Error> {
+        use embedded_hal::spi::{SpiBus, Operation};
+        match operation {
+            Operation::Read(words) => self.bus.read(words),
+            Operation::Write(words) => self.bus.write(words),
+            Operation::Transfer(read, write) => self.bus.transfer(read, write),
+            Operation::TransferInPlace(words) => self.bus.transfer_in_place(words),
+            Operation::DelayNs(ns) => {
+                sleep(Duration::from_nanos(*ns as u64));
+                Ok(())
+            },
+        }
+    }
+}
+
+impl spi::ErrorType for SpiDevice<'_> {
+    type Error = Error;
+}
+
+impl spi::SpiDevice for SpiDevice<'_> {
+    /// Perform `operations` with CS asserted, always deasserting it afterwards
+    fn transaction(&mut self, operations: &mut [spi::Operation<'_, u8>]) -> Result<(), Error> {
+        self.programmer.select()?;
+        let result = operations.iter_mut().try_for_each(|op| self.operation(op));
+        self.programmer.unselect()?;
+        result
+    }
+}
+
+/// The FFP's FPGA reset output, which holds the FPGA in reset when low
+pub struct FpgaReset<'a> {
+    programmer: &'a Programmer,
+}
+
+impl<'a> FpgaReset<'a> {
+    /// Create a new `FpgaReset` using the given `Programmer`
+    pub fn new(programmer: &'a Programmer) -> Self {
+        Self { programmer }
+    }
+}
+
+impl digital::ErrorType for FpgaReset<'_> {
+    type Error = Error;
+}
+
+impl digital::OutputPin for FpgaReset<'_> {
+    fn set_low(&mut self) -> Result<(), Error> {
+        Ok(self.programmer.reset()?)
+    }
+
+    fn set_high(&mut self) -> Result<(), Error> {
+        Ok(self.programmer.unreset()?)
+    }
+}
+
+/// The FFP's target power switch, which powers the target when high
+pub struct TargetPower<'a> {
+    programmer: &'a Programmer,
+}
+
+impl<'a> TargetPower<'a> {
+    /// Create a new `TargetPower` using the given `Programmer`
+    pub fn new(programmer: &'a Programmer) -> Self {
+        Self { programmer }
+    }
+}
+
+impl digital::ErrorType for TargetPower<'_> {
+    type Error = Error;
+}
+
+impl digital::OutputPin for TargetPower<'_> {
+    fn set_low(&mut self) -> Result<(), Error> {
+        Ok(self.programmer.power_off()?)
+    }
+
+    fn set_high(&mut self) -> Result<(), Error> {
+        Ok(self.programmer.power_on()?)
+    }
+}
+
+/// The FFP's LED, which is lit when high
+pub struct Led<'a> {
+    programmer: &'a Programmer,
+}
+
+impl<'a> Led<'a> {
+    /// Create a new `Led` using the given `Programmer`
+    pub fn new(programmer: &'a Programmer) -> Self {
+        Self { programmer }
+    }
+}
+
+impl digital::ErrorType for Led<'_> {
+    type Error = Error;
+}
+
+impl digital::OutputPin for Led<'_> {
+    fn set_low(&mut self) -> Result<(), Error> {
+        Ok(self.programmer.led_off()?)
+    }
+
+    fn set_high(&mut self) -> Result<(), Error> {
+        Ok(self.programmer.led_on()?)
+    }
+}
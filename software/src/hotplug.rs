@@ -0,0 +1,160 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use rusb::UsbContext;
+use failure::ResultExt;
+use crate::{FFPError, Programmer, DeviceInfo, Result, error_message};
+
+/// An FFP being attached to or detached from the USB bus
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Attached { serial: String, port: String },
+    Detached { serial: String, port: String },
+    /// An FFP was attached, but could not be opened to read its serial number
+    Skipped { port: String, error: String },
+}
+
+impl std::fmt::Display for DeviceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceEvent::Attached { serial, port } =>
+                write!(f, "Attached: {} (port {})", serial, port),
+            DeviceEvent::Detached { serial, port } =>
+                write!(f, "Detached: {} (port {})", serial, port),
+            DeviceEvent::Skipped { port, error } =>
+                write!(f, "Skipped: FFP on port {}: {}", port, error),
+        }
+    }
+}
+
+/// Forwards libusb hotplug callbacks to the `Watcher`, which reads serial
+/// numbers outside the callback where it is safe to open devices.
+struct Callback {
+    events: Sender<(bool, rusb::Device<rusb::Context>)>,
+}
+
+impl rusb::Hotplug<rusb::Context> for Callback {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        self.events.send((true, device)).ok();
+    }
+
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+        self.events.send((false, device)).ok();
+    }
+}
+
+/// Watches for FFPs being attached and detached using libusb hotplug events.
+///
+/// FFPs already attached when the `Watcher` is created are reported as
+/// attached by the first calls to `next_event`. FFPs which cannot be opened
+/// to read their serial number are reported as `DeviceEvent::Skipped`.
+pub struct Watcher {
+    context: rusb::Context,
+    registration: rusb::Registration,
+    events: Receiver<(bool, rusb::Device<rusb::Context>)>,
+    /// Serial numbers and ports of attached FFPs by bus number and address,
+    /// since serials cannot be read once a device has left.
+    attached: HashMap<(u8, u8), DeviceInfo>,
+    pending: VecDeque<DeviceEvent>,
+}
+
+impl Watcher {
+    /// Number of attempts to read the serial number of a newly attached FFP,
+    /// which may not be accessible until the OS has finished setting it up
+    const OPEN_ATTEMPTS: usize = 10;
+    const OPEN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+    /// Start watching for FFPs using `context`
+    pub fn new(context: &rusb::Context) -> Result<Self> {
+        if !rusb::has_hotplug() {
+            Err(FFPError::HotplugUnsupported)?;
+        }
+        let (tx, events) = channel();
+        let registration = context.register_callback(
+            Some(Programmer::ID_VENDOR), Some(Programmer::ID_PRODUCT), None,
+            Box::new(Callback { events: tx })).context("Error registering hotplug callback")?;
+        let mut watcher = Watcher {
+            context: context.clone(), registration, events,
+            attached: HashMap::new(), pending: VecDeque::new(),
+        };
+
+        // Register before enumerating so no FFP is missed; any reported
+        // twice are ignored as already attached.
+        for device in Programmer::list_devices(context)? {
+            watcher.arrived(&device);
+        }
+        Ok(watcher)
+    }
+
+    /// Wait up to `timeout` for an FFP to be attached or detached.
+    ///
+    /// Returns `None` if no FFP was attached or detached in that time.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            while let Ok((arrived, device)) = self.events.try_recv() {
+                if arrived {
+                    self.arrived(&device);
+                } else if let Some(DeviceInfo { serial, port })
+                    = self.attached.remove(&Self::key(&device))
+                {
+                    self.pending.push_back(DeviceEvent::Detached { serial, port });
+                }
+            }
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.context.handle_events(Some(deadline - now))
+                        .context("Error handling USB events")?;
+        }
+    }
+
+    fn key(device: &rusb::Device<rusb::Context>) -> (u8, u8) {
+        (device.bus_number(), device.address())
+    }
+
+    /// Record `device` as attached, or queue an event skipping it if it cannot be opened
+    fn arrived(&mut self, device: &rusb::Device<rusb::Context>) {
+        if let Err(e) = self.attach(device) {
+            let port = Programmer::port_path(device).unwrap_or_else(|_| "?".to_string());
+            self.pending.push_back(DeviceEvent::Skipped { port, error: error_message(&e) });
+        }
+    }
+
+    fn attach(&mut self, device: &rusb::Device<rusb::Context>) -> Result<()> {
+        if self.attached.contains_key(&Self::key(device)) {
+            return Ok(());
+        }
+        let serial = self.read_serial(device)?;
+        let port = Programmer::port_path(device)?;
+        let info = DeviceInfo { serial: serial.clone(), port: port.clone() };
+        self.attached.insert(Self::key(device), info);
+        self.pending.push_back(DeviceEvent::Attached { serial, port });
+        Ok(())
+    }
+
+    fn read_serial(&self, device: &rusb::Device<rusb::Context>) -> Result<String> {
+        let mut attempts = 0;
+        loop {
+            match Programmer::read_serial(device) {
+                Ok(serial) => return Ok(serial),
+                Err(_) if attempts + 1 < Self::OPEN_ATTEMPTS => {
+                    attempts += 1;
+                    sleep(Self::OPEN_RETRY_DELAY);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.context.unregister_callback(self.registration);
+    }
+}
@@ -1,12 +1,44 @@
 use failure_derive::Fail;
 
 mod programmer;
+mod batch;
+mod transfer;
+mod transport;
+mod sim;
+mod trace;
+mod hotplug;
+mod station;
+mod memory;
+mod quirks;
 mod flash;
+mod nand;
+mod dataflash;
+mod stream;
+mod littlefs;
 mod fpga;
+mod crc;
 
-pub use programmer::Programmer;
-pub use flash::Flash;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+
+#[cfg(feature = "python")]
+mod python;
+
+pub use programmer::{Programmer, Capability, DeviceInfo};
+pub use transport::Transport;
+pub use sim::SimulatedFFP;
+pub use trace::{TracingTransport, ReplayTransport, WriteErrorHandler};
+pub use hotplug::{Watcher, DeviceEvent};
+pub use station::{Station, StationRecord, Job, Image, PowerSequence, Trigger, VerifyPolicy, Outcome};
+pub use batch::Batch;
+pub use memory::{MemoryDevice, Eeprom, Fram};
+pub use flash::{Flash, FlashID, RecoveryStep, Mismatch, PageMismatch, VerifyReport};
+pub use nand::{Nand, NandID, NandGeometry, EccStatus};
+pub use dataflash::{DataFlash, DataFlashID};
+pub use stream::FlashStream;
+pub use littlefs::{LittleFs, DirEntry};
 pub use fpga::FPGA;
+pub use crc::crc32;
 
 #[derive(Fail, Debug)]
 pub enum FFPError {
@@ -16,7 +48,7 @@ pub enum FFPError {
     #[fail(display="No FFP device found")]
     NoDeviceFound,
 
-    #[fail(display="Multiple FFP devices found. Choose one with --index or --serial.")]
+    #[fail(display="Multiple FFP devices found. Choose one with --index, --serial, or --port.")]
     MultipleDevicesFound,
 
     #[fail(display="Specified FFP device not found.")]
@@ -25,8 +57,106 @@ pub enum FFPError {
     #[fail(display="Not enough data read back from device: expected {}, read {}", expected, read)]
     NotEnoughData { expected: usize, read: usize },
 
-    #[fail(display="Flash readback verification failed")]
-    ReadbackError,
+    #[fail(display="Operation not supported by FFP firmware, try updating it")]
+    UnsupportedFirmware,
+
+    #[fail(display="Timed out waiting for flash to finish programming")]
+    FlashTimeout,
+
+    #[fail(display="Flash did not respond, even after attempting recovery")]
+    FlashNotResponding,
+
+    #[fail(display="Flash contents did not match in {} range(s)", count)]
+    VerifyFailed { count: usize },
+
+    #[fail(display="Flash was not blank in {} range(s)", count)]
+    BlankCheckFailed { count: usize },
+
+    #[fail(display="Unrecognised flash capacity code {:02X}", capacity)]
+    UnknownCapacity { capacity: u8 },
+
+    #[fail(display="Suspend is not supported for flash manufacturer {:02X}", manufacturer_id)]
+    SuspendUnsupported { manufacturer_id: u8 },
+
+    #[fail(display="Erase of {} bytes at 0x{:06X} is not aligned to erase blocks", length, address)]
+    UnalignedErase { address: u32, length: usize },
+
+    #[fail(display="Erase interrupted after erasing up to 0x{:06X}", address)]
+    EraseInterrupted { address: u32 },
+
+    #[fail(display="Flash readback verification failed: {}", report)]
+    ReadbackError { report: VerifyReport },
+
+    #[fail(display="Range of {} bytes at 0x{:06X} is beyond the end of the device", length, address)]
+    AddressOutOfRange { address: u32, length: usize },
+
+    #[fail(display="Unrecognised SPI NAND ID {:06X}", id)]
+    UnknownNand { id: u32 },
+
+    #[fail(display="Uncorrectable ECC error reading NAND page {}", page)]
+    NandECCError { page: u32 },
+
+    #[fail(display="NAND block {} failed to program or erase and has been marked bad", block)]
+    NandBlockFailed { block: u32 },
+
+    #[fail(display="Memory address must be 1, 2, or 3 bytes, not {}", address_bytes)]
+    InvalidAddressBytes { address_bytes: usize },
+
+    #[fail(display="Memory page size must not be 0")]
+    InvalidPageSize,
+
+    #[fail(display="{} is not supported on DataFlash", operation)]
+    UnsupportedOnDataFlash { operation: &'static str },
+
+    #[fail(display="Partition of {} bytes at 0x{:06X} does not hold sector aligned blocks of {} bytes",
+           size, offset, block_size)]
+    UnalignedPartition { offset: u32, size: usize, block_size: usize },
+
+    #[fail(display="No littlefs filesystem found in partition")]
+    NoFilesystem,
+
+    #[fail(display="littlefs filesystem is corrupt")]
+    FilesystemCorrupt,
+
+    #[fail(display="littlefs filesystem has {} blocks of {} bytes, which does not match the partition",
+           block_count, block_size)]
+    FilesystemMismatch { block_size: u32, block_count: u32 },
+
+    #[fail(display="Not enough space in littlefs filesystem")]
+    FilesystemFull,
+
+    #[fail(display="No such file or directory: {}", path)]
+    FileNotFound { path: String },
+
+    #[fail(display="Not a file: {}", path)]
+    NotAFile { path: String },
+
+    #[fail(display="Directory not empty: {}", path)]
+    DirectoryNotEmpty { path: String },
+
+    #[fail(display="Invalid path: {}", path)]
+    InvalidPath { path: String },
+
+    #[fail(display="Trace record {} expected {}, but got {}", record, expected, request)]
+    ReplayMismatch { record: usize, expected: String, request: String },
+
+    #[fail(display="Trace ended before {}", request)]
+    ReplayFinished { request: String },
+
+    #[fail(display="Replayed error: {}", error)]
+    ReplayedError { error: String },
+
+    #[fail(display="{} is not supported with multiple FFP devices", operation)]
+    ParallelUnsupported { operation: String },
+
+    #[fail(display="{} of {} FFP devices failed", failed, total)]
+    DevicesFailed { failed: usize, total: usize },
+
+    #[fail(display="Invalid job file: {}", reason)]
+    InvalidJob { reason: String },
+
+    #[fail(display="USB hotplug events are not supported on this platform")]
+    HotplugUnsupported,
 
     #[fail(display="An unknown error has occurred.")]
     UnknownError,
@@ -39,3 +169,37 @@ impl From<rusb::Error> for FFPError {
 }
 
 pub type Result<T> = std::result::Result<T, failure::Error>;
+
+/// Broad category of an error, for mapping to error types of other languages
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    Other,
+    Device,
+    USB,
+    Firmware,
+    Flash,
+    Verify,
+}
+
+/// Categorise `error` by the first `FFPError` in its chain of causes
+pub fn error_kind(error: &failure::Error) -> ErrorKind {
+    match error.iter_chain().find_map(|c| c.downcast_ref::<FFPError>()) {
+        Some(FFPError::NoDeviceFound) | Some(FFPError::MultipleDevicesFound)
+            | Some(FFPError::DeviceNotFound) => ErrorKind::Device,
+        Some(FFPError::USBError(_)) | Some(FFPError::NotEnoughData { .. }) => ErrorKind::USB,
+        Some(FFPError::UnsupportedFirmware) => ErrorKind::Firmware,
+        Some(FFPError::FlashTimeout) | Some(FFPError::FlashNotResponding)
+            | Some(FFPError::UnknownCapacity { .. }) | Some(FFPError::UnalignedErase { .. })
+            | Some(FFPError::SuspendUnsupported { .. })
+            | Some(FFPError::EraseInterrupted { .. }) | Some(FFPError::AddressOutOfRange { .. })
+            => ErrorKind::Flash,
+        Some(FFPError::VerifyFailed { .. }) | Some(FFPError::BlankCheckFailed { .. })
+            | Some(FFPError::ReadbackError { .. }) => ErrorKind::Verify,
+        _ => ErrorKind::Other,
+    }
+}
+
+/// Describe `error` including its full chain of causes, as printed by the CLI
+pub fn error_message(error: &failure::Error) -> String {
+    error.iter_chain().map(|c| c.to_string()).collect::<Vec<_>>().join(": ")
+}
@@ -0,0 +1,889 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use crate::{Flash, FFPError, Result};
+use crate::crc::crc32_update;
+
+/// On-disk littlefs version written when formatting
+const DISK_VERSION: u32 = 0x0002_0000;
+const BLOCK_NULL: u32 = 0xFFFF_FFFF;
+
+/// A littlefs metadata tag: valid bit, 11-bit type, 10-bit id, and 10-bit size
+#[derive(Copy, Clone, Debug)]
+struct Tag(u32);
+
+impl Tag {
+    const REG: u16 = 0x001;
+    const DIR: u16 = 0x002;
+    const SUPERBLOCK: u16 = 0x0FF;
+    const DIRSTRUCT: u16 = 0x200;
+    const INLINESTRUCT: u16 = 0x201;
+    const CTZSTRUCT: u16 = 0x202;
+    const USERATTR: u16 = 0x300;
+    const CREATE: u16 = 0x401;
+    const DELETE: u16 = 0x4FF;
+    const SOFTTAIL: u16 = 0x600;
+    const HARDTAIL: u16 = 0x601;
+    const CRC: u16 = 0x500;
+    const MOVESTATE: u16 = 0x7FF;
+
+    /// Id used for tags not associated with a file
+    const NO_ID: u16 = 0x3FF;
+
+    fn new(kind: u16, id: u16, size: usize) -> Self {
+        Tag((kind as u32) << 20 | (id as u32) << 10 | size as u32)
+    }
+
+    fn is_valid(self) -> bool {
+        self.0 & 0x8000_0000 == 0
+    }
+
+    fn kind(self) -> u16 {
+        ((self.0 >> 20) & 0x7FF) as u16
+    }
+
+    fn id(self) -> u16 {
+        ((self.0 >> 10) & 0x3FF) as u16
+    }
+
+    fn size(self) -> usize {
+        (self.0 & 0x3FF) as usize
+    }
+
+    fn is_delete(self) -> bool {
+        self.size() == 0x3FF
+    }
+
+    /// Total size of the tag and its data
+    fn dsize(self) -> usize {
+        4 + if self.is_delete() { 0 } else { self.size() }
+    }
+}
+
+/// Format-time configuration stored in the superblock entry
+#[derive(Copy, Clone, Debug)]
+struct Superblock {
+    version: u32,
+    block_size: u32,
+    block_count: u32,
+    name_max: u32,
+    file_max: u32,
+    attr_max: u32,
+}
+
+impl Superblock {
+    fn new(block_size: u32, block_count: u32) -> Self {
+        Superblock {
+            version: DISK_VERSION,
+            block_size,
+            block_count,
+            name_max: 255,
+            file_max: 0x7FFF_FFFF,
+            attr_max: 1022,
+        }
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 24 {
+            Err(FFPError::FilesystemCorrupt)?;
+        }
+        let word = |i: usize| u32::from_le_bytes(data[i*4..i*4+4].try_into().unwrap());
+        Ok(Superblock {
+            version: word(0), block_size: word(1), block_count: word(2),
+            name_max: word(3), file_max: word(4), attr_max: word(5),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [self.version, self.block_size, self.block_count,
+         self.name_max, self.file_max, self.attr_max]
+            .iter().flat_map(|w| w.to_le_bytes().to_vec()).collect()
+    }
+}
+
+/// User attributes of a file or directory, by attribute type
+type Attrs = BTreeMap<u8, Vec<u8>>;
+
+/// File held in memory
+#[derive(Clone, Debug, Default)]
+struct File {
+    data: Vec<u8>,
+    attrs: Attrs,
+    /// Struct tag type and data the file is stored with on flash, kept
+    /// while its contents are unchanged so its blocks can be reused
+    stored: Option<(u16, Vec<u8>)>,
+}
+
+/// Directory held in memory
+#[derive(Clone, Debug, Default)]
+struct Dir {
+    nodes: BTreeMap<String, Node>,
+    attrs: Attrs,
+}
+
+/// File or directory held in memory
+#[derive(Clone, Debug)]
+enum Node {
+    File(File),
+    Dir(Dir),
+}
+
+/// An entry in a littlefs directory, as returned by `LittleFs::list`
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Name of the file or directory
+    pub name: String,
+    /// True if this entry is a directory
+    pub is_dir: bool,
+    /// Size of the file in bytes, or 0 for directories
+    pub size: usize,
+}
+
+/// A file id in a metadata pair, with its name tag, most recent struct
+/// tag, and user attributes
+#[derive(Clone, Debug, Default)]
+struct Entry {
+    kind: u16,
+    name: Vec<u8>,
+    structure: Option<(u16, Vec<u8>)>,
+    attrs: Attrs,
+}
+
+/// Contents of the most recent valid commit of a metadata pair
+#[derive(Clone, Debug, Default)]
+struct MetadataPair {
+    entries: Vec<Entry>,
+    tail: Option<([u32; 2], bool)>,
+    gdelta: [u8; 12],
+    /// Revision count and block of the commit
+    rev: u32,
+    block: u32,
+}
+
+/// Superblock and root directory found when mounting an image
+struct Mount {
+    superblock: Superblock,
+    root: MetadataPair,
+    root_pair: [u32; 2],
+    gstate: [u8; 12],
+}
+
+/// Read-only view of a littlefs image held in memory, which records
+/// every block in use by the filesystem as it is read.
+struct Image<'d> {
+    data: &'d [u8],
+    block_size: usize,
+    used: Vec<bool>,
+}
+
+impl<'d> Image<'d> {
+    fn new(data: &'d [u8], block_size: usize) -> Self {
+        Image { data, block_size, used: vec![false; data.len() / block_size] }
+    }
+
+    fn block(&mut self, block: u32) -> Result<&'d [u8]> {
+        let start = block as usize * self.block_size;
+        match self.data.get(start..start + self.block_size) {
+            Some(data) => {
+                self.used[block as usize] = true;
+                Ok(data)
+            },
+            None => Err(FFPError::FilesystemCorrupt)?,
+        }
+    }
+
+    /// Fetch the most recent state of the metadata pair at `pair`
+    fn fetch(&mut self, pair: [u32; 2]) -> Result<MetadataPair> {
+        let blocks = [self.block(pair[0])?, self.block(pair[1])?];
+        let revs: Vec<u32> = blocks.iter()
+            .map(|b| u32::from_le_bytes(b[..4].try_into().unwrap()))
+            .collect();
+        // Try the block with the most recent revision first
+        let first = if (revs[1].wrapping_sub(revs[0]) as i32) > 0 { 1 } else { 0 };
+        for &idx in [first, 1 - first].iter() {
+            if let Some(mut dir) = Self::scan(blocks[idx]) {
+                dir.rev = revs[idx];
+                dir.block = pair[idx];
+                return Ok(dir);
+            }
+        }
+        Err(FFPError::FilesystemCorrupt)?
+    }
+
+    /// Replay all commits in a metadata block, returning the state as of
+    /// the last commit with a valid CRC, or None if there are none.
+    fn scan(block: &[u8]) -> Option<MetadataPair> {
+        let mut committed = None;
+        let mut dir = MetadataPair::default();
+        let mut crc = crc32_update(!0, &block[..4]);
+        let mut ptag = 0xFFFF_FFFF;
+        let mut off = 4;
+        while off + 4 <= block.len() {
+            let raw = &block[off..off+4];
+            let tag = Tag(u32::from_be_bytes(raw.try_into().unwrap()) ^ ptag);
+            if !tag.is_valid() || off + tag.dsize() > block.len() {
+                break;
+            }
+            crc = crc32_update(crc, raw);
+            ptag = tag.0;
+            let data = &block[off+4..off+tag.dsize()];
+            off += tag.dsize();
+
+            if tag.kind() & 0x780 == Tag::CRC {
+                if data.len() < 4 || u32::from_le_bytes(data[..4].try_into().unwrap()) != crc {
+                    break;
+                }
+                // The CRC tag sets the expected valid bit of the next commit
+                ptag ^= (tag.kind() as u32 & 1) << 31;
+                committed = Some(dir.clone());
+                crc = !0;
+                continue;
+            }
+
+            crc = crc32_update(crc, data);
+            let id = tag.id() as usize;
+            match tag.kind() >> 8 {
+                0x0 | 0x2 | 0x3 if id >= Tag::NO_ID as usize => (),
+                0x0 | 0x2 if !tag.is_delete() => {
+                    if dir.entries.len() <= id {
+                        dir.entries.resize(id + 1, Entry::default());
+                    }
+                    let entry = &mut dir.entries[id];
+                    if tag.kind() >> 8 == 0 {
+                        entry.kind = tag.kind();
+                        entry.name = data.to_vec();
+                    } else {
+                        entry.structure = Some((tag.kind(), data.to_vec()));
+                    }
+                },
+                0x3 => {
+                    if dir.entries.len() <= id {
+                        dir.entries.resize(id + 1, Entry::default());
+                    }
+                    let attrs = &mut dir.entries[id].attrs;
+                    let attr = tag.kind() as u8;
+                    if tag.is_delete() {
+                        attrs.remove(&attr);
+                    } else {
+                        attrs.insert(attr, data.to_vec());
+                    }
+                },
+                0x4 if tag.kind() == Tag::CREATE && id <= dir.entries.len() =>
+                    dir.entries.insert(id, Entry::default()),
+                0x4 if tag.kind() == Tag::DELETE && id < dir.entries.len() => {
+                    dir.entries.remove(id);
+                },
+                0x6 if data.len() == 8 => dir.tail = Some((Self::pair(data), tag.kind() & 1 == 1)),
+                0x7 if tag.kind() == Tag::MOVESTATE && data.len() == 12 =>
+                    dir.gdelta.copy_from_slice(data),
+                _ => (),
+            }
+        }
+        committed
+    }
+
+    fn pair(data: &[u8]) -> [u32; 2] {
+        [u32::from_le_bytes(data[0..4].try_into().unwrap()),
+         u32::from_le_bytes(data[4..8].try_into().unwrap())]
+    }
+
+    /// Find the superblock, root directory, and global state by following
+    /// the metadata pair list from blocks 0 and 1.
+    fn mount(&mut self) -> Result<Mount> {
+        let mut pair = [0, 1];
+        let mut found = None;
+        let mut gstate = [0u8; 12];
+        for _ in 0..self.used.len() {
+            let dir = match self.fetch(pair) {
+                Ok(dir) => dir,
+                Err(_) if found.is_none() => Err(FFPError::NoFilesystem)?,
+                Err(err) => return Err(err),
+            };
+            if let Some(entry) = dir.entries.first() {
+                if entry.kind == Tag::SUPERBLOCK && entry.name == b"littlefs" {
+                    match &entry.structure {
+                        Some((Tag::INLINESTRUCT, data)) =>
+                            found = Some((Superblock::from_bytes(data)?, dir.clone(), pair)),
+                        _ => Err(FFPError::FilesystemCorrupt)?,
+                    }
+                }
+            }
+            for (g, d) in gstate.iter_mut().zip(dir.gdelta.iter()) {
+                *g ^= d;
+            }
+            match dir.tail {
+                Some((tail, _)) if tail != [BLOCK_NULL, BLOCK_NULL] => pair = tail,
+                _ => {
+                    let (superblock, root, root_pair) = found.ok_or(FFPError::NoFilesystem)?;
+                    return Ok(Mount { superblock, root, root_pair, gstate });
+                },
+            }
+        }
+        Err(FFPError::FilesystemCorrupt)?
+    }
+
+    /// Read the directory starting at metadata pair `pair` and everything in it.
+    ///
+    /// `budget` limits the total number of metadata pairs read, to guard
+    /// against cycles in a corrupt filesystem.
+    fn read_dir(&mut self, pair: [u32; 2], gstate: &[u8; 12], budget: &mut usize) -> Result<Dir> {
+        let mut result = Dir::default();
+        let mut pair = pair;
+        loop {
+            *budget = budget.checked_sub(1).ok_or(FFPError::FilesystemCorrupt)?;
+            let mut dir = self.fetch(pair)?;
+
+            // Complete any move interrupted by power loss by deleting the source
+            let move_tag = Tag(u32::from_le_bytes(gstate[..4].try_into().unwrap()));
+            let move_pair = Self::pair(&gstate[4..]);
+            if move_tag.kind() >> 8 != 0
+                && (move_pair == pair || move_pair == [pair[1], pair[0]])
+                && (move_tag.id() as usize) < dir.entries.len()
+            {
+                dir.entries.remove(move_tag.id() as usize);
+            }
+
+            for entry in dir.entries {
+                let name = String::from_utf8_lossy(&entry.name).into_owned();
+                let attrs = entry.attrs;
+                let node = match (entry.kind, entry.structure) {
+                    (Tag::REG, Some((Tag::INLINESTRUCT, data))) => Node::File(File {
+                        stored: Some((Tag::INLINESTRUCT, data.clone())), data, attrs,
+                    }),
+                    (Tag::REG, Some((Tag::CTZSTRUCT, ctz))) if ctz.len() == 8 => {
+                        let [head, size] = Self::pair(&ctz);
+                        let data = self.read_ctz(head, size as usize)?;
+                        Node::File(File { data, attrs, stored: Some((Tag::CTZSTRUCT, ctz)) })
+                    },
+                    (Tag::DIR, Some((Tag::DIRSTRUCT, data))) if data.len() == 8 => {
+                        let mut dir = self.read_dir(Self::pair(&data), gstate, budget)?;
+                        dir.attrs = attrs;
+                        Node::Dir(dir)
+                    },
+                    // Attributes of the root directory are stored on the superblock entry
+                    (Tag::SUPERBLOCK, _) => {
+                        result.attrs = attrs;
+                        continue;
+                    },
+                    _ => Err(FFPError::FilesystemCorrupt)?,
+                };
+                result.nodes.insert(name, node);
+            }
+
+            match dir.tail {
+                Some((tail, true)) => pair = tail,
+                _ => return Ok(result),
+            }
+        }
+    }
+
+    /// Read a file of `size` bytes stored in a CTZ skip-list ending at `head`
+    fn read_ctz(&mut self, head: u32, size: usize) -> Result<Vec<u8>> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Follow the first pointer of each block back to the start of the file
+        let mut blocks = vec![head];
+        for _ in 0..ctz_index(self.block_size, size - 1) {
+            let data = self.block(*blocks.last().unwrap())?;
+            blocks.push(u32::from_le_bytes(data[..4].try_into().unwrap()));
+        }
+        blocks.reverse();
+
+        let mut file = Vec::with_capacity(size);
+        for (index, block) in blocks.into_iter().enumerate() {
+            let start = ctz_data_offset(index);
+            let n = usize::min(self.block_size - start, size - file.len());
+            file.extend(&self.block(block)?[start..start+n]);
+        }
+        Ok(file)
+    }
+}
+
+/// Index of the CTZ skip-list block containing byte `offset` of a file
+fn ctz_index(block_size: usize, offset: usize) -> usize {
+    let b = block_size - 2*4;
+    let i = offset / b;
+    if i == 0 {
+        0
+    } else {
+        (offset - 4*((i - 1).count_ones() as usize + 2)) / b
+    }
+}
+
+/// Offset of the file data within the CTZ skip-list block at `index`,
+/// after its pointers to previous blocks.
+fn ctz_data_offset(index: usize) -> usize {
+    if index == 0 { 0 } else { 4 * (index.trailing_zeros() as usize + 1) }
+}
+
+/// A metadata pair being laid out, with the tags for each of its files
+struct PairLayout {
+    pair: [u32; 2],
+    tags: Vec<(Tag, Vec<u8>)>,
+    continued: bool,
+}
+
+/// Lays out a new copy of a littlefs filesystem alongside the current one.
+///
+/// New blocks are only allocated from those not used by the current
+/// filesystem, which is left intact until the superblock pair is committed.
+struct Builder {
+    image: Vec<u8>,
+    /// Blocks used by the current filesystem
+    reserved: Vec<bool>,
+    /// Blocks whose new contents must be written
+    written: Vec<bool>,
+    /// Revision count of each block's current contents
+    revs: Vec<u32>,
+    block_size: usize,
+    inline_max: usize,
+    next: usize,
+}
+
+impl Builder {
+    fn new(current: &[u8], reserved: Vec<bool>, block_size: usize, inline_max: usize) -> Self {
+        let block_count = current.len() / block_size;
+        let revs = current.chunks(block_size)
+            .map(|b| u32::from_le_bytes(b[..4].try_into().unwrap()))
+            .collect();
+        Builder {
+            image: current.to_vec(),
+            reserved,
+            written: vec![false; block_count],
+            revs,
+            block_size,
+            inline_max,
+            next: 0,
+        }
+    }
+
+    /// Allocate an unused block, starting it out erased
+    fn alloc(&mut self) -> Result<u32> {
+        while self.next < self.written.len() {
+            let block = self.next;
+            self.next += 1;
+            if !self.reserved[block] && !self.written[block] {
+                self.block_mut(block as u32).iter_mut().for_each(|b| *b = 0xFF);
+                self.written[block] = true;
+                return Ok(block as u32);
+            }
+        }
+        Err(FFPError::FilesystemFull)?
+    }
+
+    fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        let start = block as usize * self.block_size;
+        &mut self.image[start..start + self.block_size]
+    }
+
+    /// Write `data` to a new CTZ skip-list, returning its head block
+    fn write_ctz(&mut self, data: &[u8]) -> Result<u32> {
+        let mut blocks: Vec<u32> = Vec::new();
+        let mut data = data;
+        while !data.is_empty() {
+            let index = blocks.len();
+            let block = self.alloc()?;
+            let start = ctz_data_offset(index);
+            let n = usize::min(self.block_size - start, data.len());
+            let pointers: Vec<u32> = (0..start/4).map(|j| blocks[index - (1 << j)]).collect();
+            let buf = self.block_mut(block);
+            for (j, pointer) in pointers.iter().enumerate() {
+                buf[j*4..j*4+4].copy_from_slice(&pointer.to_le_bytes());
+            }
+            buf[start..start+n].copy_from_slice(&data[..n]);
+            blocks.push(block);
+            data = &data[n..];
+        }
+        Ok(*blocks.last().unwrap())
+    }
+
+    /// Revision count for a new metadata pair starting in `block`, which
+    /// continues the count of the block's previous contents as littlefs does.
+    fn next_rev(&self, block: u32) -> u32 {
+        match self.revs[block as usize] {
+            BLOCK_NULL => 1,
+            rev => rev.wrapping_add(1),
+        }
+    }
+
+    /// Write a single commit containing `tags` to metadata block `block`
+    fn write_commit(&mut self, block: u32, rev: u32, tags: &[(Tag, Vec<u8>)]) {
+        let mut commit = rev.to_le_bytes().to_vec();
+        let mut ptag = 0xFFFF_FFFF;
+        for (tag, data) in tags.iter() {
+            commit.extend(&(tag.0 ^ ptag).to_be_bytes());
+            commit.extend(data);
+            ptag = tag.0;
+        }
+        commit.extend(&(Tag::new(Tag::CRC, Tag::NO_ID, 4).0 ^ ptag).to_be_bytes());
+        let crc = crc32_update(!0, &commit);
+        commit.extend(&crc.to_le_bytes());
+        let buf = self.block_mut(block);
+        buf.iter_mut().for_each(|b| *b = 0xFF);
+        buf[..commit.len()].copy_from_slice(&commit);
+        self.written[block as usize] = true;
+    }
+
+    /// Tags for the name, contents, and attributes of entry `id`
+    fn entry_tags(id: u16, kind: u16, name: &str, structure: (u16, Vec<u8>), attrs: &Attrs)
+        -> Vec<(Tag, Vec<u8>)>
+    {
+        let (struct_kind, data) = structure;
+        let mut tags = vec![
+            (Tag::new(kind, id, name.len()), name.as_bytes().to_vec()),
+            (Tag::new(struct_kind, id, data.len()), data),
+        ];
+        for (attr, data) in attrs.iter() {
+            tags.push((Tag::new(Tag::USERATTR | *attr as u16, id, data.len()), data.clone()));
+        }
+        tags
+    }
+
+    /// Lay out `root` and everything in it, writing all metadata pairs.
+    ///
+    /// The superblock and root directory are committed with revision `rev`
+    /// to the first block of `root_pair`, which must not hold the current
+    /// commit. Returns the new image and the blocks to program, ending with
+    /// that block so the new filesystem only takes effect once all else is
+    /// written.
+    fn build(mut self, superblock: &Superblock, root: &Dir, root_pair: [u32; 2], rev: u32)
+        -> Result<(Vec<u8>, Vec<u32>)>
+    {
+        let superblock_tags = Self::entry_tags(
+            0, Tag::SUPERBLOCK, "littlefs", (Tag::INLINESTRUCT, superblock.to_bytes()), &root.attrs);
+
+        // Keep each pair at most half full so littlefs has room for new commits
+        let limit = self.block_size / 2;
+        let inline_max = self.inline_max.min(superblock.attr_max as usize).min(self.block_size / 8);
+        let mut layouts: Vec<PairLayout> = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((root_pair, root, superblock_tags));
+        while let Some((pair, dir, initial_tags)) = queue.pop_front() {
+            let mut layout = PairLayout { pair, tags: initial_tags, continued: false };
+            let mut id = if layout.tags.is_empty() { 0 } else { 1 };
+            let mut size = 4 + 12 + 8 + layout.tags.iter().map(|(t, _)| t.dsize()).sum::<usize>();
+            for (name, node) in dir.nodes.iter() {
+                let (kind, structure, attrs) = match node {
+                    Node::File(File { stored: Some(stored), attrs, .. }) =>
+                        (Tag::REG, stored.clone(), attrs),
+                    Node::File(File { data, attrs, .. }) if data.len() <= inline_max =>
+                        (Tag::REG, (Tag::INLINESTRUCT, data.clone()), attrs),
+                    Node::File(File { data, attrs, .. }) => {
+                        let head = self.write_ctz(data)?;
+                        let mut ctz = head.to_le_bytes().to_vec();
+                        ctz.extend(&(data.len() as u32).to_le_bytes());
+                        (Tag::REG, (Tag::CTZSTRUCT, ctz), attrs)
+                    },
+                    Node::Dir(child) => {
+                        let pair = [self.alloc()?, self.alloc()?];
+                        queue.push_back((pair, child, Vec::new()));
+                        let mut data = pair[0].to_le_bytes().to_vec();
+                        data.extend(&pair[1].to_le_bytes());
+                        (Tag::DIR, (Tag::DIRSTRUCT, data), &child.attrs)
+                    },
+                };
+                let entry_size = 8 + name.len() + structure.1.len()
+                    + attrs.values().map(|data| 4 + data.len()).sum::<usize>();
+
+                // Continue the directory in a new pair if this one is full
+                if size + entry_size > limit && id > 0 {
+                    layout.continued = true;
+                    let next = [self.alloc()?, self.alloc()?];
+                    layouts.push(std::mem::replace(
+                        &mut layout, PairLayout { pair: next, tags: Vec::new(), continued: false }));
+                    id = 0;
+                    size = 4 + 12 + 8;
+                }
+
+                layout.tags.extend(Self::entry_tags(id, kind, name, structure, attrs));
+                size += entry_size;
+                id += 1;
+            }
+            layouts.push(layout);
+        }
+
+        // Link every pair into the metadata list, using hard tails within a directory
+        for idx in 0..layouts.len() {
+            let mut tags = std::mem::take(&mut layouts[idx].tags);
+            if let Some(next) = layouts.get(idx + 1) {
+                let kind = if layouts[idx].continued { Tag::HARDTAIL } else { Tag::SOFTTAIL };
+                let mut data = next.pair[0].to_le_bytes().to_vec();
+                data.extend(&next.pair[1].to_le_bytes());
+                tags.push((Tag::new(kind, Tag::NO_ID, 8), data));
+            }
+            let block = layouts[idx].pair[0];
+            let rev = if idx == 0 { rev } else { self.next_rev(block) };
+            self.write_commit(block, rev, &tags);
+        }
+
+        let blocks = (0..self.written.len() as u32)
+            .filter(|&block| self.written[block as usize] && block != root_pair[0])
+            .chain(std::iter::once(root_pair[0]))
+            .collect();
+        Ok((self.image, blocks))
+    }
+}
+
+/// Filesystem read from the partition
+struct Loaded {
+    image: Vec<u8>,
+    superblock: Superblock,
+    root: Dir,
+    /// Superblock pair, and revision and block of its current commit
+    root_pair: [u32; 2],
+    rev: u32,
+    block: u32,
+    /// Blocks used by the filesystem
+    used: Vec<bool>,
+}
+
+/// Access to a littlefs filesystem in a partition of the attached flash.
+///
+/// The partition offset must be sector aligned and its size a whole number
+/// of filesystem blocks, which default to the flash sector size. Changes
+/// are made by reading the whole filesystem, then writing a new copy of
+/// everything which changed into unused blocks, and finally committing it
+/// to the superblock pair. The previous filesystem is left intact until
+/// that last write, so an interrupted change is not visible, but the
+/// partition needs enough free space for the new copy.
+pub struct LittleFs<'a> {
+    flash: &'a Flash<'a>,
+    offset: u32,
+    size: usize,
+    block_size: usize,
+    inline_max: usize,
+}
+
+impl<'a> LittleFs<'a> {
+    /// Create a new `LittleFs` for the partition of `size` bytes at `offset`
+    pub fn new(flash: &'a Flash<'a>, offset: u32, size: usize) -> Result<Self> {
+        let block_size = Flash::SECTOR_SIZE;
+        Self::check_geometry(offset, size, block_size)?;
+        Ok(LittleFs { flash, offset, size, block_size, inline_max: 0 })
+    }
+
+    /// Set the filesystem block size, which must be a multiple of the
+    /// flash sector size and divide the partition size.
+    pub fn set_block_size(&mut self, block_size: usize) -> Result<()> {
+        Self::check_geometry(self.offset, self.size, block_size)?;
+        self.block_size = block_size;
+        Ok(())
+    }
+
+    /// Set the largest file size to store inline in its directory instead
+    /// of in its own blocks. Defaults to 0, so every non-empty file uses at
+    /// least one block.
+    ///
+    /// Firmware can only read inline files up to its littlefs `cache_size`,
+    /// so this must not be set any larger.
+    pub fn set_inline_max(&mut self, inline_max: usize) {
+        self.inline_max = inline_max;
+    }
+
+    /// Create a new empty filesystem in the partition
+    pub fn format(&self) -> Result<()> {
+        let image = self.read_partition()?;
+        let superblock = Superblock::new(self.block_size as u32, self.block_count());
+
+        // Commit over any existing superblock pair as for other changes,
+        // so an interrupted format leaves the previous filesystem in place.
+        let (rev, block) = match Image::new(&image, self.block_size).fetch([0, 1]) {
+            Ok(pair) => (pair.rev, pair.block),
+            Err(_) => (0, 1),
+        };
+        let used = vec![false; self.block_count() as usize];
+        let loaded = Loaded {
+            image, superblock, root: Dir::default(), root_pair: [0, 1], rev, block, used,
+        };
+        self.store(&loaded, &Dir::default())
+    }
+
+    /// List the contents of the directory at `path`, or just the file at `path`
+    pub fn list(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let root = self.load()?.root;
+        let entry = |name: &str, node: &Node| match node {
+            Node::Dir(_) => DirEntry { name: name.to_string(), is_dir: true, size: 0 },
+            Node::File(file) =>
+                DirEntry { name: name.to_string(), is_dir: false, size: file.data.len() },
+        };
+        let dir = match Self::find(&root, path)? {
+            None => &root,
+            Some(Node::Dir(dir)) => dir,
+            Some(file) => {
+                let name = Self::components(path)?.last().unwrap().to_string();
+                return Ok(vec![entry(&name, file)]);
+            },
+        };
+        Ok(dir.nodes.iter().map(|(name, node)| entry(name, node)).collect())
+    }
+
+    /// Read the contents of the file at `path`
+    pub fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let root = self.load()?.root;
+        match Self::find(&root, path)? {
+            Some(Node::File(file)) => Ok(file.data.clone()),
+            _ => Err(FFPError::NotAFile { path: path.to_string() })?,
+        }
+    }
+
+    /// Read user attribute `attr` of the file or directory at `path`,
+    /// returning None if it is not set.
+    pub fn get_attr(&self, path: &str, attr: u8) -> Result<Option<Vec<u8>>> {
+        let root = self.load()?.root;
+        let attrs = match Self::find(&root, path)? {
+            None => &root.attrs,
+            Some(Node::File(file)) => &file.attrs,
+            Some(Node::Dir(dir)) => &dir.attrs,
+        };
+        Ok(attrs.get(&attr).cloned())
+    }
+
+    /// Write `data` to the file at `path`, replacing it if it already exists
+    /// and creating any missing parent directories.
+    ///
+    /// The user attributes of a replaced file are kept.
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        let loaded = self.load()?;
+        let mut root = loaded.root.clone();
+        let invalid = || FFPError::InvalidPath { path: path.to_string() };
+        let components = Self::components(path)?;
+        let (name, parents) = components.split_last().ok_or_else(invalid)?;
+        let superblock = &loaded.superblock;
+        if name.len() > superblock.name_max as usize || data.len() > superblock.file_max as usize {
+            Err(invalid())?;
+        }
+        let mut dir = &mut root;
+        for parent in parents {
+            dir = match dir.nodes.entry(parent.to_string()).or_insert_with(|| Node::Dir(Dir::default())) {
+                Node::Dir(dir) => dir,
+                Node::File(_) => Err(invalid())?,
+            };
+        }
+        let attrs = match dir.nodes.get(*name) {
+            Some(Node::Dir(_)) => Err(FFPError::NotAFile { path: path.to_string() })?,
+            Some(Node::File(file)) => file.attrs.clone(),
+            None => Attrs::new(),
+        };
+        dir.nodes.insert(name.to_string(), Node::File(File { data: data.to_vec(), attrs, stored: None }));
+        self.store(&loaded, &root)
+    }
+
+    /// Remove the file or empty directory at `path`
+    pub fn remove(&self, path: &str) -> Result<()> {
+        let loaded = self.load()?;
+        let mut root = loaded.root.clone();
+        let not_found = || FFPError::FileNotFound { path: path.to_string() };
+        let components = Self::components(path)?;
+        let (name, parents) = components.split_last()
+            .ok_or(FFPError::InvalidPath { path: path.to_string() })?;
+        let mut dir = &mut root;
+        for parent in parents {
+            dir = match dir.nodes.get_mut(*parent) {
+                Some(Node::Dir(dir)) => dir,
+                _ => Err(not_found())?,
+            };
+        }
+        match dir.nodes.get(*name) {
+            Some(Node::Dir(child)) if !child.nodes.is_empty() =>
+                Err(FFPError::DirectoryNotEmpty { path: path.to_string() })?,
+            Some(_) => { dir.nodes.remove(*name); },
+            None => Err(not_found())?,
+        }
+        self.store(&loaded, &root)
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.size / self.block_size) as u32
+    }
+
+    /// Check a partition of `size` bytes at `offset` can hold whole
+    /// blocks of `block_size` bytes aligned to flash sectors.
+    fn check_geometry(offset: u32, size: usize, block_size: usize) -> Result<()> {
+        if !(offset as usize).is_multiple_of(Flash::SECTOR_SIZE)
+            || block_size == 0 || !block_size.is_multiple_of(Flash::SECTOR_SIZE)
+            || !size.is_multiple_of(block_size) || size < 2 * block_size
+        {
+            Err(FFPError::UnalignedPartition { offset, size, block_size })?;
+        }
+        Ok(())
+    }
+
+    fn read_partition(&self) -> Result<Vec<u8>> {
+        self.flash.read(self.offset, self.size)
+    }
+
+    /// Read the partition and parse the entire filesystem into memory
+    fn load(&self) -> Result<Loaded> {
+        let data = self.read_partition()?;
+        let mut image = Image::new(&data, self.block_size);
+        let mount = image.mount()?;
+        let superblock = mount.superblock;
+        if superblock.version >> 16 != DISK_VERSION >> 16
+            || superblock.block_size as usize != self.block_size
+            || superblock.block_count != self.block_count()
+        {
+            Err(FFPError::FilesystemMismatch {
+                block_size: superblock.block_size, block_count: superblock.block_count })?;
+        }
+        let mut budget = self.block_count() as usize;
+        let root = image.read_dir(mount.root_pair, &mount.gstate, &mut budget)?;
+        let used = image.used;
+        Ok(Loaded {
+            image: data, superblock, root, root_pair: mount.root_pair,
+            rev: mount.root.rev, block: mount.root.block, used,
+        })
+    }
+
+    /// Write a new copy of the filesystem containing `root` alongside
+    /// `current`, programming each block which differs, and finally commit
+    /// it to the superblock pair.
+    fn store(&self, current: &Loaded, root: &Dir) -> Result<()> {
+        let pair = current.root_pair;
+        let root_pair = if current.block == pair[0] { [pair[1], pair[0]] } else { pair };
+        let builder = Builder::new(&current.image, current.used.clone(), self.block_size, self.inline_max);
+        let (image, blocks) = builder.build(
+            &current.superblock, root, root_pair, current.rev.wrapping_add(1))?;
+        for block in blocks {
+            let range = block as usize * self.block_size..(block as usize + 1) * self.block_size;
+            let data = &image[range.clone()];
+            if data == &current.image[range] {
+                continue;
+            }
+            let address = self.offset + (block as usize * self.block_size) as u32;
+            if data.iter().all(|b| *b == 0xFF) {
+                self.flash.erase_range(address, self.block_size, false)?;
+            } else {
+                self.flash.program(address, data, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Split `path` into its components, ignoring empty and `.` components
+    fn components(path: &str) -> Result<Vec<&str>> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty() && *c != ".").collect();
+        if components.contains(&"..") {
+            Err(FFPError::InvalidPath { path: path.to_string() })?;
+        }
+        Ok(components)
+    }
+
+    /// Find the file or directory at `path`, or None for the root directory
+    fn find<'n>(root: &'n Dir, path: &str) -> Result<Option<&'n Node>> {
+        let not_found = || FFPError::FileNotFound { path: path.to_string() };
+        let components = Self::components(path)?;
+        let (name, parents) = match components.split_last() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        let mut dir = root;
+        for parent in parents {
+            dir = match dir.nodes.get(*parent) {
+                Some(Node::Dir(dir)) => dir,
+                _ => Err(not_found())?,
+            };
+        }
+        dir.nodes.get(*name).map(Some).ok_or_else(|| not_found().into())
+    }
+}
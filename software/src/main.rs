@@ -1,9 +1,13 @@
 use std::fs::File;
 use std::io::prelude::*;
-use std::time::Instant;
-use clap::{Arg, App, AppSettings, SubCommand};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 use clap::{value_t, crate_authors, crate_description, crate_version};
-use ffp::{Programmer, Flash, FPGA};
+use ffp::{Programmer, Flash, DataFlash, Nand, Eeprom, Fram, MemoryDevice, FPGA, SimulatedFFP, ReplayTransport, FFPError, LittleFs, Watcher};
+use ffp::{DeviceEvent, Station, StationRecord, Job, Trigger};
 
 #[allow(clippy::cognitive_complexity)]
 fn main() -> ffp::Result<()> {
@@ -23,10 +27,12 @@ fn main() -> ffp::Result<()> {
              .short("q")
              .global(true))
         .arg(Arg::with_name("serial")
-             .help("Serial number of FFP device to use")
+             .help("Serial number of FFP device to use, repeat to use several devices at once")
              .long("serial")
              .short("s")
              .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
              .global(true))
         .arg(Arg::with_name("index")
              .help("Index of FFP device to use")
@@ -35,6 +41,38 @@ fn main() -> ffp::Result<()> {
              .conflicts_with("serial")
              .takes_value(true)
              .global(true))
+        .arg(Arg::with_name("port")
+             .help("USB port path of FFP device to use, such as 1-2.3")
+             .long("port")
+             .short("p")
+             .conflicts_with_all(&["serial", "index"])
+             .takes_value(true)
+             .global(true))
+        .arg(Arg::with_name("all")
+             .help("Use all attached FFP devices at once")
+             .long("all")
+             .conflicts_with_all(&["serial", "index", "port"])
+             .global(true))
+        .arg(Arg::with_name("simulate")
+             .help("Use a simulated FFP with flash contents from FILE, saved on exit")
+             .long("simulate")
+             .value_name("FILE")
+             .conflicts_with_all(&["serial", "index", "port", "all"])
+             .takes_value(true)
+             .global(true))
+        .arg(Arg::with_name("trace")
+             .help("Record all communication with the FFP to FILE")
+             .long("trace")
+             .value_name("FILE")
+             .takes_value(true)
+             .global(true))
+        .arg(Arg::with_name("replay")
+             .help("Replay communication recorded by --trace from FILE instead of using an FFP")
+             .long("replay")
+             .value_name("FILE")
+             .conflicts_with_all(&["serial", "index", "port", "all", "simulate", "trace"])
+             .takes_value(true)
+             .global(true))
         .subcommand(SubCommand::with_name("fpga")
             .about("Reset, power, and program the FPGA")
             .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -56,7 +94,20 @@ fn main() -> ffp::Result<()> {
             .subcommand(SubCommand::with_name("id")
                         .about("Read flash ID"))
             .subcommand(SubCommand::with_name("erase")
-                        .about("Completely erase flash"))
+                        .about("Erase flash, either completely or just a range")
+                        .after_help("Erasing a range can be stopped with Ctrl-C, after the erase \
+                                     block in progress. Erasing the whole chip cannot be stopped.")
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) to erase from")
+                             .long("offset")
+                             .takes_value(true))
+                        .arg(Arg::with_name("length")
+                             .help("Length (in bytes) to erase [default: to end of flash]")
+                             .long("length")
+                             .takes_value(true))
+                        .arg(Arg::with_name("round")
+                             .help("Extend an unaligned range outward to whole 4KB sectors")
+                             .long("round")))
             .subcommand(SubCommand::with_name("program")
                         .about("Program flash chip with binary data from file")
                         .arg(Arg::with_name("file")
@@ -69,7 +120,30 @@ fn main() -> ffp::Result<()> {
                         .arg(Arg::with_name("no-verify")
                              .help("Disable automatic readback verification")
                              .short("n")
-                             .long("no-verify")))
+                             .long("no-verify"))
+                        .arg(Arg::with_name("retries")
+                             .help("Times to re-program sectors which fail verification")
+                             .long("retries")
+                             .default_value("0")))
+            .subcommand(SubCommand::with_name("verify")
+                        .about("Verify flash chip contents match binary data from file")
+                        .arg(Arg::with_name("file")
+                             .help("File to compare with flash contents")
+                             .required(true))
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) to compare from")
+                             .long("offset")
+                             .default_value("0")))
+            .subcommand(SubCommand::with_name("blank-check")
+                        .about("Check flash chip is erased")
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) to check from")
+                             .long("offset")
+                             .default_value("0"))
+                        .arg(Arg::with_name("length")
+                             .help("Length (in bytes) to check [default: entire flash]")
+                             .long("length")
+                             .takes_value(true)))
             .subcommand(SubCommand::with_name("read")
                         .about("Read contents of flash chip to file")
                         .arg(Arg::with_name("file")
@@ -79,6 +153,164 @@ fn main() -> ffp::Result<()> {
                              .help("Length (in bytes) to read from flash")
                              .long("length")
                              .default_value("135183"))
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) to read from")
+                             .long("offset")
+                             .default_value("0")))
+            .subcommand(SubCommand::with_name("fs")
+                        .about("Access a littlefs filesystem in a flash partition")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) of the partition")
+                             .long("offset")
+                             .required(true)
+                             .takes_value(true))
+                        .arg(Arg::with_name("size")
+                             .help("Size (in bytes) of the partition")
+                             .long("size")
+                             .required(true)
+                             .takes_value(true))
+                        .arg(Arg::with_name("block-size")
+                             .help("Filesystem block size, a multiple of the 4KB sector size")
+                             .long("block-size")
+                             .default_value("4096"))
+                        .arg(Arg::with_name("inline-max")
+                             .help("Largest file to store inline, at most the firmware's cache_size")
+                             .long("inline-max")
+                             .default_value("0"))
+                        .subcommand(SubCommand::with_name("ls")
+                                    .about("List a directory")
+                                    .arg(Arg::with_name("path")
+                                         .help("Directory to list")
+                                         .default_value("/")))
+                        .subcommand(SubCommand::with_name("get")
+                                    .about("Copy a file from the filesystem")
+                                    .arg(Arg::with_name("path")
+                                         .help("File to read from the filesystem")
+                                         .required(true))
+                                    .arg(Arg::with_name("file")
+                                         .help("File to write")
+                                         .required(true)))
+                        .subcommand(SubCommand::with_name("put")
+                                    .about("Copy a file to the filesystem")
+                                    .arg(Arg::with_name("file")
+                                         .help("File to read")
+                                         .required(true))
+                                    .arg(Arg::with_name("path")
+                                         .help("File to write in the filesystem")
+                                         .required(true)))
+                        .subcommand(SubCommand::with_name("rm")
+                                    .about("Remove a file or empty directory")
+                                    .arg(Arg::with_name("path")
+                                         .help("File or directory to remove")
+                                         .required(true)))
+                        .subcommand(SubCommand::with_name("format")
+                                    .about("Create a new empty filesystem"))))
+        .subcommand(SubCommand::with_name("nand")
+            .about("Read/write SPI NAND flash memory")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("id")
+                        .about("Read NAND ID and geometry"))
+            .subcommand(SubCommand::with_name("bad-blocks")
+                        .about("List bad blocks"))
+            .subcommand(SubCommand::with_name("erase")
+                        .about("Erase a range of good blocks")
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes, skipping bad blocks) to erase from")
+                             .long("offset")
+                             .default_value("0"))
+                        .arg(Arg::with_name("length")
+                             .help("Length (in bytes) to erase [default: to end of NAND]")
+                             .long("length")
+                             .takes_value(true))
+                        .arg(Arg::with_name("round")
+                             .help("Extend an unaligned range outward to whole blocks")
+                             .long("round")))
+            .subcommand(SubCommand::with_name("program")
+                        .about("Program NAND with binary data from file")
+                        .arg(Arg::with_name("file")
+                             .help("File to write to NAND")
+                             .required(true))
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes, skipping bad blocks) to write to")
+                             .long("offset")
+                             .default_value("0"))
+                        .arg(Arg::with_name("no-verify")
+                             .help("Disable automatic readback verification")
+                             .short("n")
+                             .long("no-verify")))
+            .subcommand(SubCommand::with_name("read")
+                        .about("Read contents of NAND to file")
+                        .arg(Arg::with_name("file")
+                             .help("File to write with contents of NAND")
+                             .required(true))
+                        .arg(Arg::with_name("length")
+                             .help("Length (in bytes) to read from NAND")
+                             .long("length")
+                             .required(true)
+                             .takes_value(true))
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes, skipping bad blocks) to read from")
+                             .long("offset")
+                             .default_value("0"))))
+        .subcommand(SubCommand::with_name("mem")
+            .about("Read/write any supported SPI memory device")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(Arg::with_name("type")
+                 .help("Type of memory device attached")
+                 .long("type")
+                 .possible_values(&["flash", "nand", "eeprom", "fram"])
+                 .default_value("flash"))
+            .arg(Arg::with_name("addr-bytes")
+                 .help("Number of address bytes used by EEPROM or FRAM")
+                 .long("addr-bytes")
+                 .possible_values(&["1", "2", "3"])
+                 .default_value("2"))
+            .arg(Arg::with_name("page-size")
+                 .help("Write page size (in bytes) of EEPROM")
+                 .long("page-size")
+                 .default_value("32"))
+            .arg(Arg::with_name("size")
+                 .help("Size (in bytes) of EEPROM or FRAM")
+                 .long("size")
+                 .takes_value(true)
+                 .required_ifs(&[("type", "eeprom"), ("type", "fram")]))
+            .subcommand(SubCommand::with_name("erase")
+                        .about("Erase a range of memory")
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) to erase from")
+                             .long("offset")
+                             .default_value("0"))
+                        .arg(Arg::with_name("length")
+                             .help("Length (in bytes) to erase [default: to end of memory]")
+                             .long("length")
+                             .takes_value(true))
+                        .arg(Arg::with_name("round")
+                             .help("Extend an unaligned range outward to whole erase blocks")
+                             .long("round")))
+            .subcommand(SubCommand::with_name("write")
+                        .about("Write memory with binary data from file")
+                        .arg(Arg::with_name("file")
+                             .help("File to write to memory")
+                             .required(true))
+                        .arg(Arg::with_name("offset")
+                             .help("Start address (in bytes) to write to")
+                             .long("offset")
+                             .default_value("0"))
+                        .arg(Arg::with_name("no-verify")
+                             .help("Disable automatic readback verification")
+                             .short("n")
+                             .long("no-verify")))
+            .subcommand(SubCommand::with_name("read")
+                        .about("Read contents of memory to file")
+                        .arg(Arg::with_name("file")
+                             .help("File to write with contents of memory")
+                             .required(true))
+                        .arg(Arg::with_name("length")
+                             .help("Length (in bytes) to read from memory")
+                             .long("length")
+                             .required(true)
+                             .takes_value(true))
                         .arg(Arg::with_name("offset")
                              .help("Start address (in bytes) to read from")
                              .long("offset")
@@ -86,16 +318,36 @@ fn main() -> ffp::Result<()> {
         .subcommand(SubCommand::with_name("bootload")
             .about("Reset FFP hardware into USB bootloader"))
         .subcommand(SubCommand::with_name("devices")
-            .about("List available FFP devices"))
+            .about("List available FFP devices")
+            .arg(Arg::with_name("watch")
+                 .help("Print FFP devices as they are attached and detached, until Ctrl-C")
+                 .long("watch")))
+        .subcommand(SubCommand::with_name("station")
+            .about("Program and log each board in turn, as described by a job file, until Ctrl-C")
+            .arg(Arg::with_name("job")
+                 .help("JSON job file describing the images, verification, and power sequencing")
+                 .required(true)))
         .get_matches();
 
     let t0 = Instant::now();
-    let context = rusb::Context::new().expect("Error getting rusb context");
     let quiet = matches.is_present("quiet");
 
     // Special-case devices which does not need a programmer
     if matches.subcommand_name().unwrap() == "devices" {
-        let devices = Programmer::get_serials(&context)?;
+        let context = rusb::Context::new().expect("Error getting rusb context");
+        if matches.subcommand_matches("devices").unwrap().is_present("watch") {
+            let interrupt = interrupt_on_ctrlc(None);
+            let mut watcher = Watcher::new(&context)?;
+            while !interrupt.load(Ordering::SeqCst) {
+                match watcher.next_event(Duration::from_millis(100))? {
+                    Some(event @ DeviceEvent::Skipped { .. }) => eprintln!("{}", event),
+                    Some(event) => println!("{}", event),
+                    None => (),
+                }
+            }
+            return Ok(());
+        }
+        let devices = Programmer::get_devices(&context)?;
         match devices.len() {
             0 => println!("No FFP devices found."),
             _ => {
@@ -103,44 +355,142 @@ fn main() -> ffp::Result<()> {
                     1 => println!("1 device found:"),
                     _ => println!("{} devices found:", devices.len()),
                 }
-                for (idx, serial) in devices.iter().enumerate() {
-                    println!("    {}: {}", idx, serial);
+                for (idx, device) in devices.iter().enumerate() {
+                    println!("    {}: {} (port {})", idx, device.serial, device.port);
                 }
             },
         }
         return Ok(());
     }
 
-    let programmer = if matches.is_present("serial") {
-        Programmer::by_serial(&context, matches.value_of("serial").unwrap())
-    } else if matches.is_present("index") {
-        Programmer::by_index(&context, value_t!(matches.value_of("index"), usize).unwrap())
+    let mut station = match matches.subcommand_matches("station") {
+        Some(matches) => Some(Station::new(Job::open(Path::new(matches.value_of("job").unwrap()))?)?),
+        None => None,
+    };
+
+    // Station mode with an attach trigger opens each FFP as it is attached,
+    // unless using a simulated or replayed FFP which is programmed just once
+    let simulated = matches.is_present("simulate") || matches.is_present("replay");
+    if let Some(station) = station.as_mut().filter(|s| s.job().trigger == Trigger::Attach) {
+        if !simulated {
+            let context = rusb::Context::new().expect("Error getting rusb context");
+            let interrupt = interrupt_on_ctrlc(None);
+            let mut watcher = Watcher::new(&context)?;
+            if !quiet { println!("Waiting for FFPs to be attached, press Ctrl-C to stop") };
+            while !interrupt.load(Ordering::SeqCst) {
+                let (serial, port) = match watcher.next_event(Duration::from_millis(100))? {
+                    Some(DeviceEvent::Attached { serial, port }) => (serial, port),
+                    Some(event @ DeviceEvent::Skipped { .. }) => {
+                        eprintln!("{}", event);
+                        continue;
+                    },
+                    _ => continue,
+                };
+                if matches.values_of("serial").is_some_and(|mut s| !s.any(|s| s == serial))
+                    || matches.value_of("port").is_some_and(|p| p != port)
+                {
+                    continue;
+                }
+                match Programmer::by_serial(&context, &serial) {
+                    Ok(programmer) => print_record(&station.program(&programmer, &serial)?),
+                    Err(e) => println!("Error opening FFP {}: {}", serial, e),
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    // Several FFPs each run the same command concurrently
+    let serials: Vec<String> = matches.values_of("serial")
+        .map(|serials| serials.map(String::from).collect()).unwrap_or_default();
+    if matches.is_present("all") || serials.len() > 1 {
+        if let Some(operation) = parallel_unsupported(&matches) {
+            Err(FFPError::ParallelUnsupported { operation })?;
+        }
+        let context = rusb::Context::new().expect("Error getting rusb context");
+        let mut devices = Programmer::enumerate_devices(&context)?;
+        let targets: Vec<_> = match matches.is_present("all") {
+            true => devices.into_iter().map(|(device, serial)| (serial, Some(device))).collect(),
+            false => serials.into_iter().map(|serial| {
+                let index = devices.iter().position(|(_, s)| *s == serial);
+                (serial, index.map(|index| devices.remove(index).0))
+            }).collect(),
+        };
+        return run_parallel(&matches, &context, &targets, quiet);
+    }
+
+    // A missing simulated flash file starts as an erased 1MB flash
+    let simulation = match matches.value_of("simulate") {
+        Some(path) => match std::fs::read(path) {
+            Ok(data) => Some(SimulatedFFP::with_flash(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(SimulatedFFP::new(1 << 20)),
+            Err(e) => Err(e)?,
+        },
+        None => None,
+    };
+
+    let mut programmer = if let Some(sim) = &simulation {
+        Programmer::from_transport(Box::new(sim.clone()))
+    } else if let Some(path) = matches.value_of("replay") {
+        Programmer::from_transport(Box::new(ReplayTransport::open(Path::new(path))?))
     } else {
-        Programmer::find(&context)
+        let context = rusb::Context::new().expect("Error getting rusb context");
+        if matches.is_present("serial") {
+            Programmer::by_serial(&context, matches.value_of("serial").unwrap())
+        } else if matches.is_present("index") {
+            Programmer::by_index(&context, value_t!(matches.value_of("index"), usize).unwrap())
+        } else if matches.is_present("port") {
+            Programmer::by_port(&context, matches.value_of("port").unwrap())
+        } else {
+            Programmer::find(&context)
+        }
     }?;
 
+    if let Some(path) = matches.value_of("trace") {
+        programmer.trace(Path::new(path), Box::new(|err| {
+            eprintln!("Error writing trace file, the trace is incomplete: {}", err);
+        }))?;
+    }
+
+    run(&matches, &programmer, station.as_mut(), Output { quiet, serial: None })?;
+
+    if let Some(sim) = &simulation {
+        std::fs::write(matches.value_of("simulate").unwrap(), sim.flash_contents())?;
+    }
+
+    let t1 = t0.elapsed();
+    if !quiet { println!("Finished in {}.{:02}s", t1.as_secs(), t1.subsec_millis()/10) };
+
+    Ok(())
+}
+
+/// Run the command in `matches` using `programmer`
+#[allow(clippy::cognitive_complexity)]
+fn run(matches: &ArgMatches, programmer: &Programmer, station: Option<&mut Station>, out: Output)
+    -> ffp::Result<()>
+{
     match matches.subcommand_name() {
         Some("fpga") => {
-            let fpga = FPGA::new(&programmer);
+            let fpga = FPGA::new(programmer);
             let matches = matches.subcommand_matches("fpga").unwrap();
             match matches.subcommand_name() {
                 Some("reset") => {
-                    if !quiet { println!("Resetting FPGA") };
+                    out.progress(format_args!("Resetting FPGA"));
                     fpga.reset()?;
                 },
                 Some("power") => {
                     let matches = matches.subcommand_matches("power").unwrap();
                     let arg = matches.value_of("power").unwrap();
                     if arg == "on" {
-                        if !quiet { println!("Turning on target power") };
+                        out.progress(format_args!("Turning on target power"));
                         fpga.power_on()?;
                     } else if arg == "off" {
-                        if !quiet { println!("Turning off target power") };
+                        out.progress(format_args!("Turning off target power"));
                         fpga.power_off()?;
                     }
                 },
                 Some("program") => {
-                    if !quiet { println!("Programming FPGA") };
+                    out.progress(format_args!("Programming FPGA"));
                     let matches = matches.subcommand_matches("program").unwrap();
                     let path = matches.value_of("file").unwrap();
                     let mut file = File::open(path)?;
@@ -152,52 +502,394 @@ fn main() -> ffp::Result<()> {
             }
         },
         Some("flash") => {
-            let flash = Flash::new(&programmer);
-            let id = flash.read_id().expect("Error reading flash ID");
-            if !quiet { println!("Flash ID: {}", id) };
             let matches = matches.subcommand_matches("flash").unwrap();
-            match matches.subcommand_name() {
-                Some("id") => {
-                    if quiet { println!("Flash ID: {}", id) };
+            let retries = match matches.subcommand() {
+                ("program", Some(matches)) => value_t!(matches.value_of("retries"), usize).unwrap(),
+                _ => 0,
+            };
+            let mut flash = Flash::new(programmer);
+            flash.set_retries(retries);
+            let id = flash.read_id()?;
+
+            // DataFlash has its own command set, so check for it when the ID is from its maker
+            let dataflash = match id.manufacturer_id() {
+                DataFlash::MANUFACTURER_ID => DataFlash::detect(programmer)?,
+                _ => None,
+            };
+            if let Some(mut dataflash) = dataflash {
+                dataflash.set_retries(retries);
+                out.progress(format_args!("DataFlash ID: {}", dataflash.id()));
+                match matches.subcommand_name() {
+                    Some("id") => {
+                        if out.quiet { out.result(format_args!("DataFlash ID: {}", dataflash.id())) };
+                    },
+                    Some("fs") => Err(FFPError::UnsupportedOnDataFlash { operation: "littlefs" })?,
+                    _ => run_flash(&dataflash, "DataFlash", matches, programmer, out)?,
+                }
+            } else {
+                out.progress(format_args!("Flash ID: {}", id));
+                match matches.subcommand_name() {
+                    Some("id") => {
+                        if out.quiet { out.result(format_args!("Flash ID: {}", id)) };
+                        out.result(format_args!("Flash responded {}", id.recovery()));
+                    },
+                    Some("fs") => run_fs(&flash, matches.subcommand_matches("fs").unwrap(), out)?,
+                    _ => run_flash(&flash, "flash", matches, programmer, out)?,
+                }
+            }
+        },
+        Some("nand") => {
+            let nand = Nand::new(programmer)?;
+            out.progress(format_args!("NAND ID: {}", nand.id()));
+            let matches = matches.subcommand_matches("nand").unwrap();
+            match matches.subcommand() {
+                ("id", _) => {
+                    let geometry = nand.geometry();
+                    if out.quiet { out.result(format_args!("NAND ID: {}", nand.id())) };
+                    out.result(format_args!("{} blocks of {} pages of {}+{} bytes, {} bad blocks",
+                                            geometry.blocks, geometry.pages_per_block,
+                                            geometry.page_size, geometry.spare_size,
+                                            nand.bad_blocks().len()));
                 },
-                Some("erase") => {
-                    if !quiet { println!("Erasing flash") };
-                    flash.erase()?;
+                ("bad-blocks", _) => {
+                    for block in nand.bad_blocks() {
+                        out.result(format_args!("{}", block));
+                    }
                 },
-                Some("program") => {
-                    if !quiet { println!("Programming flash") };
-                    let matches = matches.subcommand_matches("program").unwrap();
-                    let path = matches.value_of("file").unwrap();
+                ("erase", Some(matches)) => {
+                    out.progress(format_args!("Erasing NAND"));
+                    let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+                    let length = match matches.value_of("length") {
+                        Some(_) => value_t!(matches.value_of("length"), usize).unwrap(),
+                        None => nand.capacity().saturating_sub(offset as usize),
+                    };
+                    nand.erase_range(offset, length, matches.is_present("round"))?;
+                },
+                ("program", Some(matches)) => {
+                    out.progress(format_args!("Programming NAND"));
                     let offset = value_t!(matches.value_of("offset"), u32).unwrap();
-                    let verify = !matches.is_present("no-verify");
-                    let mut file = File::open(path)?;
                     let mut data = Vec::new();
-                    file.read_to_end(&mut data)?;
-                    flash.program(offset, &data, verify)?;
-                    programmer.unreset()?;
+                    File::open(matches.value_of("file").unwrap())?.read_to_end(&mut data)?;
+                    nand.program(offset, &data, !matches.is_present("no-verify"))?;
                 },
-                Some("read") => {
-                    if !quiet { println!("Reading flash to file") };
-                    let matches = matches.subcommand_matches("read").unwrap();
-                    let path = matches.value_of("file").unwrap();
+                ("read", Some(matches)) => {
+                    out.progress(format_args!("Reading NAND to file"));
                     let offset = value_t!(matches.value_of("offset"), u32).unwrap();
                     let length = value_t!(matches.value_of("length"), usize).unwrap();
-                    let mut file = File::create(path)?;
-                    let data = flash.read(offset, length)?;
-                    file.write_all(&data)?;
+                    let data = nand.read(offset, length)?;
+                    File::create(matches.value_of("file").unwrap())?.write_all(&data)?;
                 },
                 _ => panic!(),
             }
         },
+        Some("mem") => {
+            let matches = matches.subcommand_matches("mem").unwrap();
+            let addr_bytes = value_t!(matches.value_of("addr-bytes"), usize).unwrap();
+            let page_size = value_t!(matches.value_of("page-size"), usize).unwrap();
+            let size = || value_t!(matches.value_of("size"), usize).unwrap();
+            programmer.reset()?;
+            let mem: Box<dyn MemoryDevice> = match matches.value_of("type").unwrap() {
+                "flash" => {
+                    let flash = Flash::new(programmer);
+                    flash.read_id()?;
+                    Box::new(flash)
+                },
+                "nand" => Box::new(Nand::new(programmer)?),
+                "eeprom" => Box::new(Eeprom::new(programmer, addr_bytes, page_size, size())?),
+                "fram" => Box::new(Fram::new(programmer, addr_bytes, size())?),
+                _ => panic!(),
+            };
+            match matches.subcommand() {
+                ("erase", Some(matches)) => {
+                    out.progress(format_args!("Erasing memory"));
+                    let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+                    let length = match matches.value_of("length") {
+                        Some(_) => value_t!(matches.value_of("length"), usize).unwrap(),
+                        None => mem.capacity()?.saturating_sub(offset as usize),
+                    };
+                    mem.erase_range(offset, length, matches.is_present("round"))?;
+                },
+                ("write", Some(matches)) => {
+                    out.progress(format_args!("Writing memory"));
+                    let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+                    let mut data = Vec::new();
+                    File::open(matches.value_of("file").unwrap())?.read_to_end(&mut data)?;
+                    mem.program(offset, &data, !matches.is_present("no-verify"))?;
+                },
+                ("read", Some(matches)) => {
+                    out.progress(format_args!("Reading memory to file"));
+                    let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+                    let length = value_t!(matches.value_of("length"), usize).unwrap();
+                    let data = mem.read(offset, length)?;
+                    File::create(matches.value_of("file").unwrap())?.write_all(&data)?;
+                },
+                _ => panic!(),
+            }
+            programmer.unreset()?;
+        },
+        Some("station") => {
+            let station = station.unwrap();
+            let serial = programmer.serial()?;
+            if station.job().trigger == Trigger::Attach {
+                print_record(&station.program(programmer, &serial)?);
+            } else {
+                let interrupt = interrupt_on_ctrlc(None);
+                loop {
+                    out.progress(format_args!("Waiting for target power, press Ctrl-C to stop"));
+                    if !Station::wait_for_target_power(programmer, true, interrupt)? {
+                        break;
+                    }
+                    print_record(&station.program(programmer, &serial)?);
+                    out.progress(format_args!("Waiting for target to be removed"));
+                    if !Station::wait_for_target_power(programmer, false, interrupt)? {
+                        break;
+                    }
+                }
+            }
+        },
         Some("bootload") => {
-            if !quiet { println!("Resetting FFP into bootloader") };
+            out.progress(format_args!("Resetting FFP into bootloader"));
             programmer.bootload()?;
         },
         _ => panic!(),
     };
 
-    let t1 = t0.elapsed();
-    if !quiet { println!("Finished in {}.{:02}s", t1.as_secs(), t1.subsec_millis()/10) };
+    Ok(())
+}
+
+/// Run the command in `matches` on each FFP in `targets` at once, one thread
+/// per FFP, then print a summary of the results.
+///
+/// Each target is a serial and its device, or `None` if it was not found.
+/// Output from each FFP is prefixed with its serial.
+/// Returns an error if the command failed on any FFP.
+fn run_parallel(matches: &ArgMatches, context: &rusb::Context,
+                targets: &[(String, Option<rusb::Device<rusb::Context>>)], quiet: bool)
+    -> ffp::Result<()>
+{
+    if targets.is_empty() {
+        Err(FFPError::NoDeviceFound)?;
+    }
+
+    let results: Vec<(Duration, ffp::Result<()>)> = std::thread::scope(|scope| {
+        let threads: Vec<_> = targets.iter().map(|(serial, device)| scope.spawn(move || {
+            let t0 = Instant::now();
+            let out = Output { quiet, serial: Some(serial) };
+            let result = match device {
+                Some(device) => Programmer::from_device(context, device)
+                    .and_then(|programmer| run(matches, &programmer, None, out)),
+                None => Err(FFPError::DeviceNotFound.into()),
+            };
+            (t0.elapsed(), result)
+        })).collect();
+        threads.into_iter().map(|thread| thread.join().unwrap_or_else(|_| {
+            (Duration::default(), Err(failure::err_msg("Internal error (panic)")))
+        })).collect()
+    });
 
+    let serials: Vec<&String> = targets.iter().map(|(serial, _)| serial).collect();
+    let width = serials.iter().map(|serial| serial.len()).max().unwrap_or(0).max(6);
+    println!("{:<width$}  Result  {:>7}  Error", "Serial", "Time", width = width);
+    for (serial, (time, result)) in serials.iter().zip(results.iter()) {
+        let (status, error) = match result {
+            Ok(()) => ("OK", String::new()),
+            Err(e) => ("FAILED", e.iter_chain().map(|c| c.to_string())
+                                  .collect::<Vec<_>>().join(": ")),
+        };
+        println!("{:<width$}  {:<6}  {:>6.2}s  {}", serial, status, time.as_secs_f64(), error,
+                 width = width);
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        Err(FFPError::DevicesFailed { failed, total: serials.len() })?;
+    }
+    Ok(())
+}
+
+/// Describe the part of the command in `matches` which cannot be used with
+/// several FFPs at once, such as commands which write to an output file.
+fn parallel_unsupported(matches: &ArgMatches) -> Option<String> {
+    if matches.is_present("trace") {
+        return Some("--trace".to_string());
+    }
+    let mut command = vec!["ffp"];
+    let mut matches = matches;
+    while let (name, Some(submatches)) = matches.subcommand() {
+        command.push(name);
+        matches = submatches;
+    }
+    let command = command.join(" ");
+    match command.as_str() {
+        "ffp station" | "ffp flash read" | "ffp flash fs get" | "ffp nand read"
+            | "ffp mem read" => Some(command),
+        _ => None,
+    }
+}
+
+/// Return a flag which is set when Ctrl-C is pressed, first printing `message` if given.
+///
+/// The handler is only installed once, so all callers share the same flag.
+fn interrupt_on_ctrlc(message: Option<&'static str>) -> &'static AtomicBool {
+    static INTERRUPT: AtomicBool = AtomicBool::new(false);
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        ctrlc::set_handler(move || {
+            if let Some(message) = message { println!("{}", message) };
+            INTERRUPT.store(true, Ordering::SeqCst);
+        }).expect("Error setting Ctrl-C handler");
+    });
+    &INTERRUPT
+}
+
+/// Where `run` prints what it is doing
+#[derive(Clone, Copy)]
+struct Output<'a> {
+    /// Don't print progress messages
+    quiet: bool,
+    /// Prefix each line with this FFP serial, when running on several FFPs at once
+    serial: Option<&'a str>,
+}
+
+impl Output<'_> {
+    /// Print a progress message, unless quiet
+    fn progress(&self, args: std::fmt::Arguments) {
+        if !self.quiet { self.result(args) };
+    }
+
+    /// Print a result, which is shown even when quiet
+    fn result(&self, args: std::fmt::Arguments) {
+        match self.serial {
+            Some(serial) => println!("{}: {}", serial, args),
+            None => println!("{}", args),
+        }
+    }
+}
+
+/// Run the `flash fs` subcommand in `matches` on `flash`
+fn run_fs(flash: &Flash, matches: &ArgMatches, out: Output) -> ffp::Result<()> {
+    let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+    let size = value_t!(matches.value_of("size"), usize).unwrap();
+    let mut fs = LittleFs::new(flash, offset, size)?;
+    fs.set_block_size(value_t!(matches.value_of("block-size"), usize).unwrap())?;
+    fs.set_inline_max(value_t!(matches.value_of("inline-max"), usize).unwrap());
+    match matches.subcommand() {
+        ("ls", Some(matches)) => {
+            for entry in fs.list(matches.value_of("path").unwrap())? {
+                if entry.is_dir {
+                    out.result(format_args!("{:>10}  {}/", "", entry.name));
+                } else {
+                    out.result(format_args!("{:>10}  {}", entry.size, entry.name));
+                }
+            }
+        },
+        ("get", Some(matches)) => {
+            let data = fs.read(matches.value_of("path").unwrap())?;
+            File::create(matches.value_of("file").unwrap())?.write_all(&data)?;
+        },
+        ("put", Some(matches)) => {
+            out.progress(format_args!("Writing file to filesystem"));
+            let mut data = Vec::new();
+            File::open(matches.value_of("file").unwrap())?.read_to_end(&mut data)?;
+            fs.write(matches.value_of("path").unwrap(), &data)?;
+        },
+        ("rm", Some(matches)) => {
+            out.progress(format_args!("Removing from filesystem"));
+            fs.remove(matches.value_of("path").unwrap())?;
+        },
+        ("format", _) => {
+            out.progress(format_args!("Formatting filesystem"));
+            fs.format()?;
+        },
+        _ => panic!(),
+    }
+    Ok(())
+}
+
+/// Run the `flash` subcommands shared by SPI NOR flash and DataFlash on
+/// `mem`, which is called `name` in progress messages
+fn run_flash(mem: &dyn MemoryDevice, name: &str, matches: &ArgMatches, programmer: &Programmer,
+             out: Output) -> ffp::Result<()>
+{
+    match matches.subcommand() {
+        ("erase", Some(matches)) => {
+            if matches.is_present("offset") || matches.is_present("length") {
+                // Let any erase in progress finish cleanly on Ctrl-C
+                let interrupt = interrupt_on_ctrlc(
+                    Some("Interrupted, waiting for erase in progress to finish"));
+                let offset = match matches.value_of("offset") {
+                    Some(_) => value_t!(matches.value_of("offset"), u32).unwrap(),
+                    None => 0,
+                };
+                let length = match matches.value_of("length") {
+                    Some(_) => value_t!(matches.value_of("length"), usize).unwrap(),
+                    None => mem.capacity()?.saturating_sub(offset as usize),
+                };
+                out.progress(format_args!("Erasing {} from 0x{:06X}", name, offset));
+                mem.erase_range_interruptible(offset, length, matches.is_present("round"), interrupt)?;
+            } else {
+                // A whole-chip erase cannot be stopped once started
+                interrupt_on_ctrlc(Some("Interrupted, but a whole-chip erase cannot be stopped; \
+                                         waiting for it to finish"));
+                out.progress(format_args!("Erasing {}", name));
+                mem.erase()?;
+            }
+        },
+        ("program", Some(matches)) => {
+            out.progress(format_args!("Programming {}", name));
+            let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+            let mut data = Vec::new();
+            File::open(matches.value_of("file").unwrap())?.read_to_end(&mut data)?;
+            mem.program(offset, &data, !matches.is_present("no-verify"))?;
+            programmer.unreset()?;
+        },
+        ("verify", Some(matches)) => {
+            out.progress(format_args!("Verifying {}", name));
+            let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+            let mut data = Vec::new();
+            File::open(matches.value_of("file").unwrap())?.read_to_end(&mut data)?;
+            let mismatches = mem.verify(offset, &data)?;
+            for mismatch in mismatches.iter() {
+                out.result(format_args!("Mismatch at {}", mismatch));
+            }
+            if !mismatches.is_empty() {
+                Err(FFPError::VerifyFailed { count: mismatches.len() })?;
+            }
+        },
+        ("blank-check", Some(matches)) => {
+            out.progress(format_args!("Checking {} is blank", name));
+            let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+            let length = match matches.value_of("length") {
+                Some(_) => value_t!(matches.value_of("length"), u32).unwrap(),
+                None => (mem.capacity()? as u32).saturating_sub(offset),
+            };
+            let end = offset.checked_add(length).ok_or(
+                FFPError::AddressOutOfRange { address: offset, length: length as usize })?;
+            let mismatches = mem.blank_check(offset..end)?;
+            for mismatch in mismatches.iter() {
+                out.result(format_args!("Not blank at {}", mismatch));
+            }
+            if !mismatches.is_empty() {
+                Err(FFPError::BlankCheckFailed { count: mismatches.len() })?;
+            }
+        },
+        ("read", Some(matches)) => {
+            out.progress(format_args!("Reading {} to file", name));
+            let offset = value_t!(matches.value_of("offset"), u32).unwrap();
+            let length = value_t!(matches.value_of("length"), usize).unwrap();
+            let data = mem.read(offset, length)?;
+            File::create(matches.value_of("file").unwrap())?.write_all(&data)?;
+        },
+        _ => panic!(),
+    }
     Ok(())
 }
+
+/// Print the result of programming a board in station mode
+fn print_record(record: &StationRecord) {
+    println!("{}: {} in {:.1}s", record.ffp_serial,
+             record.result.to_string().to_uppercase(), record.duration);
+    if let Some(error) = &record.error {
+        println!("    {}", error);
+    }
+}
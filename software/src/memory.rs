@@ -0,0 +1,274 @@
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use crate::{Programmer, Flash, Capability, Batch, FFPError, Result, Mismatch};
+
+/// Common interface to memory devices attached to the FFP's flash SPI bus
+pub trait MemoryDevice {
+    /// Read `length` bytes of data starting at `address`
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>>;
+
+    /// Write `data` starting at `address`, first erasing if the device requires it.
+    ///
+    /// If `verify` is true, also check the written data.
+    fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()>;
+
+    /// Erase `length` bytes starting at `address`.
+    ///
+    /// Devices with erase blocks extend the range to whole blocks if
+    /// `round_outward` is true, and otherwise require it to be aligned.
+    /// Devices without erase blocks write 0xFF instead.
+    fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()>;
+
+    /// Erase like `erase_range`, but stop between erase operations once
+    /// `interrupt` is set, returning FFPError::EraseInterrupted.
+    ///
+    /// Devices which erase a range in a single operation ignore `interrupt`.
+    fn erase_range_interruptible(&self, address: u32, length: usize, round_outward: bool,
+                                 interrupt: &AtomicBool) -> Result<()>
+    {
+        let _ = interrupt;
+        self.erase_range(address, length, round_outward)
+    }
+
+    /// Erase the entire device
+    fn erase(&self) -> Result<()> {
+        self.erase_range(0, self.capacity()?, false)
+    }
+
+    /// Compare the device contents starting at `address` with `data`,
+    /// returning a list of all ranges which did not match.
+    fn verify(&self, address: u32, data: &[u8]) -> Result<Vec<Mismatch>> {
+        let written = self.read(address, data.len())?;
+        Ok(Flash::compare(address, data, &written))
+    }
+
+    /// Check that all bytes in `range` are erased (0xFF).
+    ///
+    /// Returns a list of all ranges which were not blank.
+    fn blank_check(&self, range: Range<u32>) -> Result<Vec<Mismatch>> {
+        let length = range.end.saturating_sub(range.start) as usize;
+        self.verify(range.start, &vec![0xFF; length])
+    }
+
+    /// Number of usable bytes in the device
+    fn capacity(&self) -> Result<usize>;
+}
+
+/// Write `tx` to the device in flash mode, then return `nbytes` of response.
+pub(crate) fn exchange(programmer: &Programmer, tx: &[u8], nbytes: usize) -> Result<Vec<u8>> {
+    if programmer.has_capability(Capability::Batch) {
+        let batch = Batch::new().flash_mode().select().transfer(tx).read(nbytes, 0).unselect();
+        let rx = programmer.batch(&batch)?;
+        return Ok(rx[tx.len()..].to_vec());
+    }
+    let mut tx = tx.to_vec();
+    tx.extend(vec![0u8; nbytes]);
+    programmer.flash_mode()?;
+    programmer.select()?;
+    let rx = programmer.write(&tx)?;
+    programmer.unselect()?;
+    Ok(rx[rx.len()-nbytes..].to_vec())
+}
+
+/// Write `header` to the device in flash mode, then stream out `length` bytes.
+pub(crate) fn read_after(programmer: &Programmer, header: &[u8], length: usize) -> Result<Vec<u8>> {
+    if programmer.has_capability(Capability::Batch) {
+        programmer.batch(&Batch::new().flash_mode().select().transfer(header))?;
+    } else {
+        programmer.flash_mode()?;
+        programmer.select()?;
+        programmer.write(header)?;
+    }
+    // Release CS even if the read fails, so the device isn't left mid-command
+    let rx = match programmer.read(length, 0) {
+        Ok(rx) => rx,
+        Err(err) => {
+            programmer.unselect().ok();
+            return Err(err);
+        },
+    };
+    programmer.unselect()?;
+    Ok(rx)
+}
+
+#[derive(Copy, Clone, Debug)]
+#[allow(unused)]
+#[repr(u8)]
+enum Command {
+    WriteEnable = 0x06,
+    WriteDisable = 0x04,
+    ReadStatusRegister = 0x05,
+    WriteStatusRegister = 0x01,
+    Read = 0x03,
+    Write = 0x02,
+}
+
+/// Commands and addressing shared by 25xx-series EEPROMs and SPI FRAM
+struct SerialMemory<'a> {
+    programmer: &'a Programmer,
+    address_bytes: usize,
+    size: usize,
+}
+
+impl<'a> SerialMemory<'a> {
+    fn new(programmer: &'a Programmer, address_bytes: usize, size: usize) -> Result<Self> {
+        if !(1..=3).contains(&address_bytes) {
+            Err(FFPError::InvalidAddressBytes { address_bytes })?;
+        }
+        Ok(Self { programmer, address_bytes, size })
+    }
+
+    /// Command byte followed by `address` in the configured number of bytes
+    fn header(&self, command: Command, address: u32) -> Vec<u8> {
+        let mut tx = vec![command as u8];
+        tx.extend(&address.to_be_bytes()[4-self.address_bytes..]);
+        tx
+    }
+
+    fn check_range(&self, address: u32, length: usize) -> Result<()> {
+        if address as usize + length > self.size {
+            Err(FFPError::AddressOutOfRange { address, length })?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        self.check_range(address, length)?;
+        read_after(self.programmer, &self.header(Command::Read, address), length)
+    }
+
+    /// Enable writes then write `data` at `address`, which must not cross a page boundary
+    fn write(&self, address: u32, data: &[u8]) -> Result<()> {
+        exchange(self.programmer, &[Command::WriteEnable as u8], 0)?;
+        let mut tx = self.header(Command::Write, address);
+        tx.extend(data);
+        exchange(self.programmer, &tx, 0)?;
+        Ok(())
+    }
+
+    fn verify(&self, address: u32, data: &[u8]) -> Result<()> {
+        let written = self.read(address, data.len())?;
+        let count = written.iter().zip(data.iter()).filter(|(w, d)| w != d).count();
+        if count > 0 {
+            Err(FFPError::VerifyFailed { count })?;
+        }
+        Ok(())
+    }
+}
+
+/// 25xx-series SPI EEPROM manager
+///
+/// Writes are split at page boundaries, and each page write waits
+/// for the EEPROM's write-in-progress flag to clear.
+pub struct Eeprom<'a> {
+    memory: SerialMemory<'a>,
+    page_size: usize,
+}
+
+impl<'a> Eeprom<'a> {
+    /// Maximum time to wait for a page write, well beyond the datasheet
+    /// maximums of common parts
+    const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Create a new `Eeprom` of `size` bytes using the given `Programmer`,
+    /// with `address_bytes` bytes of addressing and `page_size` byte pages.
+    ///
+    /// Returns FFPError::InvalidAddressBytes unless `address_bytes` is 1, 2,
+    /// or 3, and FFPError::InvalidPageSize if `page_size` is 0.
+    pub fn new(programmer: &'a Programmer, address_bytes: usize, page_size: usize, size: usize)
+        -> Result<Self>
+    {
+        if page_size == 0 {
+            Err(FFPError::InvalidPageSize)?;
+        }
+        Ok(Self { memory: SerialMemory::new(programmer, address_bytes, size)?, page_size })
+    }
+
+    /// Wait for a page write to finish, returning `FlashTimeout` if the
+    /// EEPROM is still busy after `WRITE_TIMEOUT`, such as when MISO reads
+    /// all ones because no EEPROM is fitted.
+    fn wait_while_busy(&self) -> Result<()> {
+        let status = [Command::ReadStatusRegister as u8];
+        let deadline = Instant::now() + Self::WRITE_TIMEOUT;
+        while exchange(self.memory.programmer, &status, 1)?[0] & 1 == 1 {
+            if Instant::now() > deadline {
+                Err(FFPError::FlashTimeout)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MemoryDevice for Eeprom<'a> {
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        self.memory.read(address, length)
+    }
+
+    fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        self.memory.check_range(address, data.len())?;
+        let mut address = address;
+        let mut data_left = data;
+        while !data_left.is_empty() {
+            let page_remaining = self.page_size - (address as usize % self.page_size);
+            let n = usize::min(page_remaining, data_left.len());
+            self.memory.write(address, &data_left[..n])?;
+            self.wait_while_busy()?;
+            address += n as u32;
+            data_left = &data_left[n..];
+        }
+        if verify {
+            self.memory.verify(address - data.len() as u32, data)?;
+        }
+        Ok(())
+    }
+
+    fn erase_range(&self, address: u32, length: usize, _round_outward: bool) -> Result<()> {
+        self.program(address, &vec![0xFF; length], false)
+    }
+
+    fn capacity(&self) -> Result<usize> {
+        Ok(self.memory.size)
+    }
+}
+
+/// SPI FRAM manager, such as the MB85RS series
+///
+/// FRAM is byte addressable and writes complete immediately, so any
+/// amount of data is written in a single command.
+pub struct Fram<'a> {
+    memory: SerialMemory<'a>,
+}
+
+impl<'a> Fram<'a> {
+    /// Create a new `Fram` of `size` bytes using the given `Programmer`,
+    /// with `address_bytes` bytes of addressing.
+    ///
+    /// Returns FFPError::InvalidAddressBytes unless `address_bytes` is 1, 2, or 3.
+    pub fn new(programmer: &'a Programmer, address_bytes: usize, size: usize) -> Result<Self> {
+        Ok(Self { memory: SerialMemory::new(programmer, address_bytes, size)? })
+    }
+}
+
+impl<'a> MemoryDevice for Fram<'a> {
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        self.memory.read(address, length)
+    }
+
+    fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        self.memory.check_range(address, data.len())?;
+        self.memory.write(address, data)?;
+        if verify {
+            self.memory.verify(address, data)?;
+        }
+        Ok(())
+    }
+
+    fn erase_range(&self, address: u32, length: usize, _round_outward: bool) -> Result<()> {
+        self.program(address, &vec![0xFF; length], false)
+    }
+
+    fn capacity(&self) -> Result<usize> {
+        Ok(self.memory.size)
+    }
+}
@@ -0,0 +1,484 @@
+use std::cell::RefCell;
+use std::ops::Range;
+use crate::{Programmer, Capability, Batch, FFPError, Result, MemoryDevice};
+use crate::memory;
+
+#[derive(Copy, Clone, Debug)]
+#[allow(unused)]
+#[repr(u8)]
+enum Command {
+    Reset = 0xFF,
+    ReadJEDECID = 0x9F,
+    GetFeature = 0x0F,
+    SetFeature = 0x1F,
+    WriteEnable = 0x06,
+    WriteDisable = 0x04,
+    PageDataRead = 0x13,
+    FastRead = 0x0B,
+    ProgramLoad = 0x02,
+    RandomProgramLoad = 0x84,
+    ProgramExecute = 0x10,
+    BlockErase = 0xD8,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+enum Feature {
+    Protection = 0xA0,
+    Configuration = 0xB0,
+    Status = 0xC0,
+}
+
+/// Bits in the status feature register
+mod status {
+    pub const BUSY: u8 = 1 << 0;
+    pub const ERASE_FAIL: u8 = 1 << 2;
+    pub const PROGRAM_FAIL: u8 = 1 << 3;
+    pub const ECC_SHIFT: u8 = 4;
+}
+
+/// Configuration bit enabling on-die ECC, common to all supported parts
+const CONFIG_ECC_ENABLE: u8 = 1 << 4;
+
+/// Layout of a SPI NAND device
+#[derive(Copy, Clone, Debug)]
+pub struct NandGeometry {
+    /// Bytes of data in each page, excluding the spare area
+    pub page_size: usize,
+    /// Bytes of spare area in each page
+    pub spare_size: usize,
+    /// Number of pages in each erase block
+    pub pages_per_block: usize,
+    /// Number of erase blocks in the device
+    pub blocks: usize,
+}
+
+impl NandGeometry {
+    /// Number of data bytes in each erase block
+    pub fn block_size(&self) -> usize {
+        self.page_size * self.pages_per_block
+    }
+}
+
+/// A supported SPI NAND part
+struct Part {
+    manufacturer_id: u8,
+    device_id: &'static [u8],
+    name: &'static str,
+    geometry: NandGeometry,
+    /// Configuration bits to set at start up
+    config: u8,
+    /// Blocks are split across two planes, selected by bit 12 of the column address
+    plane_select: bool,
+}
+
+const fn geometry(spare_size: usize, blocks: usize) -> NandGeometry {
+    NandGeometry { page_size: 2048, spare_size, pages_per_block: 64, blocks }
+}
+
+const PARTS: &[Part] = &[
+    Part { manufacturer_id: 0xEF, device_id: &[0xAA, 0x21], name: "W25N01GV",
+           geometry: geometry(64, 1024), config: CONFIG_ECC_ENABLE | 1 << 3, plane_select: false },
+    Part { manufacturer_id: 0xEF, device_id: &[0xAA, 0x22], name: "W25N02KV",
+           geometry: geometry(128, 2048), config: CONFIG_ECC_ENABLE | 1 << 3, plane_select: false },
+    Part { manufacturer_id: 0x2C, device_id: &[0x14], name: "MT29F1G01ABA",
+           geometry: geometry(128, 1024), config: CONFIG_ECC_ENABLE, plane_select: false },
+    Part { manufacturer_id: 0x2C, device_id: &[0x24], name: "MT29F2G01ABA",
+           geometry: geometry(128, 2048), config: CONFIG_ECC_ENABLE, plane_select: true },
+];
+
+#[derive(Copy, Clone, Debug)]
+pub struct NandID {
+    manufacturer_id: u8,
+    device_id: u16,
+    name: &'static str,
+}
+
+impl std::fmt::Display for NandID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Manufacturer {:02X}, Device {:04X} ({})",
+               self.manufacturer_id, self.device_id, self.name)
+    }
+}
+
+/// Result of on-die ECC when reading a page
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EccStatus {
+    /// No bit errors were found
+    Ok,
+    /// Bit errors were found and corrected
+    Corrected,
+    /// Bit errors were found which could not be corrected
+    Uncorrectable,
+}
+
+/// SPI NAND flash manager
+///
+/// Bad blocks are found by scanning the factory bad block markers when
+/// created. All addresses used by `read`, `program`, and `erase_range` are
+/// logical addresses which skip over bad blocks, so the usable capacity
+/// is the number of good blocks multiplied by the block size.
+///
+/// Blocks which fail to program or erase are marked bad and skipped from
+/// then on, moving all later logical addresses to the following block.
+pub struct Nand<'a> {
+    programmer: &'a Programmer,
+    id: NandID,
+    part: &'static Part,
+    bad_blocks: RefCell<Vec<u32>>,
+    good_blocks: RefCell<Vec<u32>>,
+}
+
+impl<'a> Nand<'a> {
+    /// Delay for a page to be read into the cache, longer than the worst case of all parts
+    const PAGE_READ_US: u16 = 100;
+
+    /// Number of blocks to check the bad block markers of in each batch
+    const SCAN_BATCH: u32 = 16;
+
+    /// Create a new `Nand` using the given `Programmer`.
+    ///
+    /// Resets and identifies the attached NAND, enables on-die ECC, clears
+    /// block protection, and scans for bad blocks.
+    pub fn new(programmer: &'a Programmer) -> Result<Self> {
+        programmer.reset()?;
+        let mut nand = Nand {
+            programmer,
+            id: NandID { manufacturer_id: 0, device_id: 0, name: "" },
+            part: &PARTS[0],
+            bad_blocks: RefCell::new(Vec::new()),
+            good_blocks: RefCell::new(Vec::new()),
+        };
+        nand.command(Command::Reset)?;
+        nand.wait_while_busy()?;
+
+        let (part, id) = nand.identify()?;
+        nand.part = part;
+        nand.id = id;
+        let config = nand.get_feature(Feature::Configuration)?;
+        nand.set_feature(Feature::Configuration, config | part.config)?;
+        nand.set_feature(Feature::Protection, 0)?;
+
+        let bad_blocks = nand.scan_bad_blocks()?;
+        *nand.good_blocks.borrow_mut() = (0..part.geometry.blocks as u32)
+            .filter(|b| !bad_blocks.contains(b))
+            .collect();
+        *nand.bad_blocks.borrow_mut() = bad_blocks;
+        Ok(nand)
+    }
+
+    /// Get the attached NAND manufacturer and device IDs
+    pub fn id(&self) -> NandID {
+        self.id
+    }
+
+    /// Get the layout of the attached NAND
+    pub fn geometry(&self) -> NandGeometry {
+        self.part.geometry
+    }
+
+    /// Get the physical block numbers of all bad blocks found or marked
+    pub fn bad_blocks(&self) -> Vec<u32> {
+        self.bad_blocks.borrow().clone()
+    }
+
+    /// Number of usable bytes, excluding bad blocks
+    pub fn capacity(&self) -> usize {
+        self.good_blocks.borrow().len() * self.part.geometry.block_size()
+    }
+
+    /// Read `length` bytes of data starting at logical address `address`
+    pub fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        let page_size = self.part.geometry.page_size;
+        let mut data = Vec::with_capacity(length);
+        for (page, range) in self.pages(address, length)? {
+            let (page_data, ecc) = self.read_page(page)?;
+            if ecc == EccStatus::Uncorrectable {
+                Err(FFPError::NandECCError { page })?;
+            }
+            data.extend(&page_data[range.start % page_size..][..range.len()]);
+        }
+        Ok(data)
+    }
+
+    /// Program `data` starting at logical address `address`, first erasing
+    /// every block it covers.
+    ///
+    /// If `verify` is true, also read back the programmed data and return
+    /// FFPError::VerifyFailed with the number of mismatching pages.
+    pub fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        self.erase_range(address, data.len(), true)?;
+        let page_size = self.part.geometry.page_size;
+        let pages = self.pages(address, data.len())?;
+        let mut offset = 0;
+        for (page, range) in pages.iter() {
+            // Pad partial pages with erased bytes
+            let mut page_data = vec![0xFF; page_size];
+            page_data[range.start % page_size..][..range.len()]
+                .copy_from_slice(&data[offset..offset+range.len()]);
+            self.program_page(*page, &page_data)?;
+            offset += range.len();
+        }
+
+        if verify {
+            let programmed = self.read(address, data.len())?;
+            let start = address as usize;
+            let count = pages.iter()
+                .map(|(_, range)| range.start - start..range.end - start)
+                .filter(|range| programmed[range.clone()] != data[range.clone()])
+                .count();
+            if count > 0 {
+                Err(FFPError::VerifyFailed { count })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase `length` bytes starting at logical address `address`.
+    ///
+    /// NAND can only be erased in whole blocks. If `round_outward` is true,
+    /// the range is extended to the enclosing blocks, otherwise
+    /// FFPError::UnalignedErase is returned unless it is already aligned.
+    pub fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()> {
+        let block_size = self.part.geometry.block_size();
+        let start = address as usize;
+        let end = start + length;
+        if (!start.is_multiple_of(block_size) || !end.is_multiple_of(block_size)) && !round_outward {
+            Err(FFPError::UnalignedErase { address, length })?;
+        }
+        if end > self.capacity() {
+            Err(FFPError::AddressOutOfRange { address, length })?;
+        }
+        for logical in start / block_size..end.div_ceil(block_size) {
+            let block = self.good_blocks.borrow()[logical];
+            self.erase_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Read physical page `page`, returning its data and the ECC status
+    pub fn read_page(&self, page: u32) -> Result<(Vec<u8>, EccStatus)> {
+        self.exchange(Command::PageDataRead, &Self::row(page), 0)?;
+        let ecc = match (self.wait_while_busy()? >> status::ECC_SHIFT) & 0b11 {
+            0b00 => EccStatus::Ok,
+            0b10 => EccStatus::Uncorrectable,
+            _ => EccStatus::Corrected,
+        };
+        let data = self.read_cache(page, 0, self.part.geometry.page_size)?;
+        Ok((data, ecc))
+    }
+
+    /// Program physical page `page` with `data`, which must not be longer than a page.
+    ///
+    /// If programming fails, the block is marked bad and
+    /// FFPError::NandBlockFailed is returned.
+    pub fn program_page(&self, page: u32, data: &[u8]) -> Result<()> {
+        assert!(data.len() <= self.part.geometry.page_size, "Cannot program more than one page");
+        self.write_enable()?;
+        let mut tx = self.column(page, 0).to_be_bytes().to_vec();
+        tx.extend(data);
+        self.exchange(Command::ProgramLoad, &tx, 0)?;
+        self.exchange(Command::ProgramExecute, &Self::row(page), 0)?;
+        if self.wait_while_busy()? & status::PROGRAM_FAIL != 0 {
+            return self.block_failed(page / self.part.geometry.pages_per_block as u32);
+        }
+        Ok(())
+    }
+
+    /// Erase physical block `block`.
+    ///
+    /// If erasing fails, the block is marked bad and
+    /// FFPError::NandBlockFailed is returned.
+    pub fn erase_block(&self, block: u32) -> Result<()> {
+        self.write_enable()?;
+        self.exchange(Command::BlockErase, &Self::row(self.first_page(block)), 0)?;
+        if self.wait_while_busy()? & status::ERASE_FAIL != 0 {
+            return self.block_failed(block);
+        }
+        Ok(())
+    }
+
+    /// Mark physical block `block` as bad by clearing its bad block marker.
+    ///
+    /// The block is skipped from then on, so logical addresses in it and
+    /// all later blocks move to the following good block.
+    pub fn mark_bad(&self, block: u32) -> Result<()> {
+        let page = self.first_page(block);
+        self.write_enable()?;
+        let mut tx = self.column(page, self.part.geometry.page_size).to_be_bytes().to_vec();
+        tx.push(0x00);
+        self.exchange(Command::ProgramLoad, &tx, 0)?;
+        self.exchange(Command::ProgramExecute, &Self::row(page), 0)?;
+        self.wait_while_busy()?;
+
+        let mut bad_blocks = self.bad_blocks.borrow_mut();
+        if !bad_blocks.contains(&block) {
+            bad_blocks.push(block);
+            bad_blocks.sort_unstable();
+        }
+        self.good_blocks.borrow_mut().retain(|&b| b != block);
+        Ok(())
+    }
+
+    fn block_failed(&self, block: u32) -> Result<()> {
+        self.mark_bad(block)?;
+        Err(FFPError::NandBlockFailed { block })?
+    }
+
+    /// Find each physical page and the range of logical addresses within it
+    /// covered by `length` bytes starting at logical address `address`.
+    fn pages(&self, address: u32, length: usize) -> Result<Vec<(u32, Range<usize>)>> {
+        let geometry = self.part.geometry;
+        let start = address as usize;
+        let end = start + length;
+        if end > self.capacity() {
+            Err(FFPError::AddressOutOfRange { address, length })?;
+        }
+        let good_blocks = self.good_blocks.borrow();
+        let mut pages = Vec::new();
+        let mut position = start;
+        while position < end {
+            let next = usize::min(end, (position / geometry.page_size + 1) * geometry.page_size);
+            let block = good_blocks[position / geometry.block_size()];
+            let page = self.first_page(block)
+                + ((position % geometry.block_size()) / geometry.page_size) as u32;
+            pages.push((page, position..next));
+            position = next;
+        }
+        Ok(pages)
+    }
+
+    fn first_page(&self, block: u32) -> u32 {
+        block * self.part.geometry.pages_per_block as u32
+    }
+
+    /// Row address bytes for `page`
+    fn row(page: u32) -> [u8; 3] {
+        let [_, r2, r1, r0] = page.to_be_bytes();
+        [r2, r1, r0]
+    }
+
+    /// Column address for `column` within `page`, including any plane select bit
+    fn column(&self, page: u32, column: usize) -> u16 {
+        let block = page / self.part.geometry.pages_per_block as u32;
+        let plane = if self.part.plane_select && block & 1 == 1 { 1 << 12 } else { 0 };
+        column as u16 | plane
+    }
+
+    /// Read `length` bytes from the page cache starting at `column`
+    fn read_cache(&self, page: u32, column: usize, length: usize) -> Result<Vec<u8>> {
+        let mut tx = vec![Command::FastRead as u8];
+        tx.extend(&self.column(page, column).to_be_bytes());
+        tx.push(0);
+        memory::read_after(self.programmer, &tx, length)
+    }
+
+    fn identify(&self) -> Result<(&'static Part, NandID)> {
+        // The ID follows one dummy byte
+        let id = self.exchange(Command::ReadJEDECID, &[0], 3)?;
+        for part in PARTS.iter() {
+            if id[0] == part.manufacturer_id && id[1..].starts_with(part.device_id) {
+                let device_id = part.device_id.iter().fold(0, |id, b| id << 8 | *b as u16);
+                let nand_id = NandID { manufacturer_id: id[0], device_id, name: part.name };
+                return Ok((part, nand_id));
+            }
+        }
+        Err(FFPError::UnknownNand { id: u32::from_be_bytes([0, id[0], id[1], id[2]]) })?
+    }
+
+    /// Check the bad block marker in the spare area of the first page of every block
+    fn scan_bad_blocks(&self) -> Result<Vec<u32>> {
+        let geometry = self.part.geometry;
+        let blocks = geometry.blocks as u32;
+        let mut markers = Vec::with_capacity(geometry.blocks);
+        if self.batched() {
+            // Wait a fixed time for each page read instead of polling status
+            for first in (0..blocks).step_by(Self::SCAN_BATCH as usize) {
+                let mut batch = Batch::new().flash_mode();
+                let mut tx_len = 0;
+                for block in first..u32::min(first + Self::SCAN_BATCH, blocks) {
+                    let page = self.first_page(block);
+                    let mut read = vec![Command::FastRead as u8];
+                    read.extend(&self.column(page, geometry.page_size).to_be_bytes());
+                    read.push(0);
+                    let mut load = vec![Command::PageDataRead as u8];
+                    load.extend(&Self::row(page));
+                    batch = batch
+                        .select().transfer(&load).unselect()
+                        .delay_us(Self::PAGE_READ_US)
+                        .select().transfer(&read).read(1, 0).unselect();
+                    tx_len = load.len() + read.len();
+                }
+                let rx = self.programmer.batch(&batch)?;
+                markers.extend(rx.chunks(tx_len + 1).map(|r| r[tx_len]));
+            }
+        } else {
+            for block in 0..blocks {
+                let page = self.first_page(block);
+                self.exchange(Command::PageDataRead, &Self::row(page), 0)?;
+                self.wait_while_busy()?;
+                markers.push(self.read_cache(page, geometry.page_size, 1)?[0]);
+            }
+        }
+        Ok((0..blocks).zip(markers).filter(|(_, m)| *m != 0xFF).map(|(b, _)| b).collect())
+    }
+
+    fn get_feature(&self, feature: Feature) -> Result<u8> {
+        self.exchange(Command::GetFeature, &[feature as u8], 1).map(|data| data[0])
+    }
+
+    fn set_feature(&self, feature: Feature, value: u8) -> Result<()> {
+        self.exchange(Command::SetFeature, &[feature as u8, value], 0)?;
+        Ok(())
+    }
+
+    fn write_enable(&self) -> Result<()> {
+        self.command(Command::WriteEnable)
+    }
+
+    /// Wait until the NAND is no longer busy, returning the final status
+    fn wait_while_busy(&self) -> Result<u8> {
+        loop {
+            let status = self.get_feature(Feature::Status)?;
+            if status & status::BUSY == 0 {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Check if commands can be sent as a single batch
+    fn batched(&self) -> bool {
+        self.programmer.has_capability(Capability::Batch)
+    }
+
+    /// Writes `command` and `data` to the NAND, then returns `nbytes` of response.
+    fn exchange(&self, command: Command, data: &[u8], nbytes: usize) -> Result<Vec<u8>> {
+        let mut tx = vec![command as u8];
+        tx.extend(data);
+        memory::exchange(self.programmer, &tx, nbytes)
+    }
+
+    /// Convenience method for issuing a single command and not caring about the returned data
+    fn command(&self, command: Command) -> Result<()> {
+        self.exchange(command, &[], 0)?;
+        Ok(())
+    }
+}
+
+impl<'a> MemoryDevice for Nand<'a> {
+    fn read(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+        Nand::read(self, address, length)
+    }
+
+    fn program(&self, address: u32, data: &[u8], verify: bool) -> Result<()> {
+        Nand::program(self, address, data, verify)
+    }
+
+    fn erase_range(&self, address: u32, length: usize, round_outward: bool) -> Result<()> {
+        Nand::erase_range(self, address, length, round_outward)
+    }
+
+    fn capacity(&self) -> Result<usize> {
+        Ok(Nand::capacity(self))
+    }
+}
@@ -1,7 +1,13 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::path::Path;
 use std::time::Duration;
 use rusb::UsbContext;
 use failure::ResultExt;
-use crate::{FFPError, Result};
+use crate::{FFPError, Result, Batch, Transport};
+use crate::transport::UsbTransport;
+use crate::transfer::error_from_code;
+use crate::trace::{TracingTransport, WriteErrorHandler, Detached};
 
 #[derive(Copy, Clone, Debug)]
 #[repr(u8)]
@@ -10,45 +16,150 @@ enum Command {
     SetFPGAReset = 2,
     SetMode = 3,
     SetTPwr = 4,
+    GetTPwr = 5,
     SetLED = 6,
     Bootload = 7,
+    GetCapabilities = 8,
+    SPIRead = 9,
+    SetFraming = 10,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u16)]
-enum Mode {
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum Mode {
     HighZ = 0,
     Flash = 1,
     FPGA = 2,
 }
 
+/// Optional features which may be supported by the FFP firmware
+#[derive(Copy, Clone, Debug)]
+#[repr(u16)]
+pub enum Capability {
+    /// Firmware can clock out a requested number of bytes and stream
+    /// the received data back without the host sending any data.
+    SPIRead = 1 << 0,
+
+    /// Firmware can execute a `Batch` of SPI operations.
+    Batch = 1 << 1,
+
+    /// Firmware can program flash pages and wait for the flash to be ready
+    /// without host involvement, using `Batch::page_program` and `Batch::wait_busy`.
+    PageProgram = 1 << 2,
+
+    /// Firmware can compute the CRC-32 of a region of flash, using `Batch::crc32`.
+    CRC32 = 1 << 3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u16)]
+enum Framing {
+    Raw = 0,
+    Batch = 1,
+}
+
+/// An attached FFP, as listed by `Programmer::get_devices`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub serial: String,
+    /// USB topology path: the bus number and chain of hub ports, such as `1-2.3`
+    pub port: String,
+}
+
 /// Interface to FFP hardware
 pub struct Programmer {
-    handle: rusb::DeviceHandle<rusb::Context>,
+    transport: Box<dyn Transport>,
+    capabilities: u16,
+    framing: Cell<Framing>,
+    mode: Cell<Mode>,
 }
 
 impl Programmer {
-    const ID_VENDOR: u16        = 0x1209;
-    const ID_PRODUCT: u16       = 0xff50;
-    const REQUEST_TYPE_SET: u8  = 2 << 5;
-    const TX_EP: u8             = 0x01;
-    const RX_EP: u8             = 0x81;
-    const CHUNK_SIZE: usize     = 64;
+    pub(crate) const ID_VENDOR: u16     = 0x1209;
+    pub(crate) const ID_PRODUCT: u16    = 0xff50;
+    const MAX_READ_SIZE: usize          = 0xFF_0000;
 
     /// Create a new `Programmer` using the provided `DeviceHandle`.
     ///
+    /// Bulk transfers are made one packet at a time; use
+    /// `from_handle_with_context` to keep several in flight.
     /// Turns on the FFP LED.
     pub fn from_handle(mut handle: rusb::DeviceHandle<rusb::Context>) -> Result<Self> {
         handle.claim_interface(0).context("Error claiming interface")?;
-        let programmer = Self { handle };
+        Self::from_transport(Box::new(UsbTransport::new(None, handle)))
+    }
+
+    /// Create a new `Programmer` using the provided `DeviceHandle`,
+    /// which must have been opened from `context`.
+    ///
+    /// Turns on the FFP LED.
+    pub fn from_handle_with_context(context: &rusb::Context,
+                                    mut handle: rusb::DeviceHandle<rusb::Context>)
+        -> Result<Self>
+    {
+        handle.claim_interface(0).context("Error claiming interface")?;
+        Self::from_transport(Box::new(UsbTransport::new(Some(context), handle)))
+    }
+
+    /// Create a new `Programmer` by opening `device`, as listed by
+    /// `enumerate_devices`.
+    ///
+    /// Turns on the FFP LED.
+    pub fn from_device(context: &rusb::Context, device: &rusb::Device<rusb::Context>)
+        -> Result<Self>
+    {
+        let handle = device.open().context("Error opening device")?;
+        Self::from_handle_with_context(context, handle)
+    }
+
+    /// Create a new `Programmer` communicating over `transport`,
+    /// such as a `SimulatedFFP`.
+    ///
+    /// Turns on the FFP LED.
+    pub fn from_transport(transport: Box<dyn Transport>) -> Result<Self> {
+        let capabilities = Self::read_capabilities(transport.as_ref());
+        let programmer = Self {
+            transport, capabilities,
+            framing: Cell::new(Framing::Raw),
+            mode: Cell::new(Mode::HighZ),
+        };
         programmer.led_on()?;
         Ok(programmer)
     }
 
-    /// Get a list of all attached FFP serial numbers
+    /// Record all further communication with the FFP to a trace file at `path`.
+    ///
+    /// The trace starts by repeating the capabilities request and LED
+    /// command made when the `Programmer` was created, so it can be played
+    /// back by passing a `ReplayTransport` to `from_transport`. The first
+    /// error writing the trace is passed to `on_write_error`, without
+    /// failing the communication being traced.
+    pub fn trace(&mut self, path: &Path, on_write_error: WriteErrorHandler) -> Result<()> {
+        let file = File::create(path).context("Error creating trace file")?;
+        self.set_framing(Framing::Raw)?;
+        let inner = std::mem::replace(&mut self.transport, Box::new(Detached));
+        let tracing = TracingTransport::new(inner, file).on_write_error(on_write_error);
+        self.transport = Box::new(tracing);
+        self.capabilities = Self::read_capabilities(self.transport.as_ref());
+        self.led_on()
+    }
+
+    /// Get a list of all attached FFP serial numbers, skipping any which cannot be read
     pub fn get_serials(context: &rusb::Context) -> Result<Vec<String>> {
         let devices = Self::enumerate_devices(context)?;
-        Ok(devices.iter().map(|(_, serial)| serial.clone()).collect())
+        Ok(devices.into_iter().map(|(_, serial)| serial).filter(|serial| serial != "?").collect())
+    }
+
+    /// Get a list of all attached FFP serial numbers and USB port paths.
+    ///
+    /// A serial or port which cannot be read, such as from an FFP already
+    /// opened by another process, is given as `?`.
+    pub fn get_devices(context: &rusb::Context) -> Result<Vec<DeviceInfo>> {
+        Ok(Self::enumerate_devices(context)?.into_iter().map(|(device, serial)| DeviceInfo {
+            serial,
+            port: Self::port_path(&device).unwrap_or_else(|_| "?".to_string()),
+        }).collect())
     }
 
     /// Create a new `Programmer` by finding an attached FFP on the USB bus
@@ -58,12 +169,13 @@ impl Programmer {
             0 => Err(FFPError::NoDeviceFound)?,
             1 => {
                 let handle = devices[0].0.open().context("Error opening device")?;
-                Self::from_handle(handle)
+                Self::from_handle_with_context(context, handle)
             },
             _ => {
                 println!("Multiple FFP devices found:");
-                for (idx, (_, serial)) in devices.iter().enumerate() {
-                    println!("    {}: {}", idx, serial);
+                for (idx, (device, serial)) in devices.iter().enumerate() {
+                    let port = Self::port_path(device).unwrap_or_else(|_| "?".to_string());
+                    println!("    {}: {} (port {})", idx, serial, port);
                 }
                 Err(FFPError::MultipleDevicesFound)?
             }
@@ -76,7 +188,19 @@ impl Programmer {
         for (device, device_serial) in devices {
             if device_serial == serial {
                 let handle = device.open().context("Error opening device")?;
-                return Self::from_handle(handle);
+                return Self::from_handle_with_context(context, handle);
+            }
+        }
+        Err(FFPError::DeviceNotFound)?
+    }
+
+    /// Create a new `Programmer` by finding the FFP attached at the given USB
+    /// port path, such as `1-2.3` for port 3 of the hub on port 2 of bus 1
+    pub fn by_port(context: &rusb::Context, port: &str) -> Result<Self> {
+        for device in Self::list_devices(context)? {
+            if Self::port_path(&device).is_ok_and(|path| path == port) {
+                let handle = device.open().context("Error opening device")?;
+                return Self::from_handle_with_context(context, handle);
             }
         }
         Err(FFPError::DeviceNotFound)?
@@ -87,12 +211,17 @@ impl Programmer {
         let devices = Self::enumerate_devices(context)?;
         if index < devices.len() {
             let handle = devices[index].0.open().context("Error opening device")?;
-            Self::from_handle(handle)
+            Self::from_handle_with_context(context, handle)
         } else {
             Err(FFPError::DeviceNotFound)?
         }
     }
 
+    /// Read the serial number of this FFP
+    pub fn serial(&self) -> Result<String> {
+        self.transport.serial()
+    }
+
     /// Turn on the FFP LED
     pub fn led_on(&self) -> Result<()> {
         self.set(Command::SetLED, 1)
@@ -125,17 +254,17 @@ impl Programmer {
 
     /// Set SPI pins to high impedance
     pub fn high_z_mode(&self) -> Result<()> {
-        self.set(Command::SetMode, Mode::HighZ as u16)
+        self.set_mode(Mode::HighZ)
     }
 
     /// Set SPI pins to flash mode (for communicating with SPI flash)
     pub fn flash_mode(&self) -> Result<()> {
-        self.set(Command::SetMode, Mode::Flash as u16)
+        self.set_mode(Mode::Flash)
     }
 
     /// Set SPI pins to fpga mode (for communicating with FPGA)
     pub fn fpga_mode(&self) -> Result<()> {
-        self.set(Command::SetMode, Mode::FPGA as u16)
+        self.set_mode(Mode::FPGA)
     }
 
     /// Enable target power switch on FFP
@@ -148,60 +277,182 @@ impl Programmer {
         self.set(Command::SetTPwr, 0)
     }
 
+    /// Check if the FFP senses power on the target's power rail
+    pub fn target_powered(&self) -> Result<bool> {
+        let data = self.transport.control_in(Command::GetTPwr as u8, 0, 0, 2)
+            .context("Error reading target power")?;
+        Ok(data.first() == Some(&1))
+    }
+
     /// Reset FFP hardware into USB bootloader mode
     pub fn bootload(&self) -> Result<()> {
         self.set(Command::Bootload, 0)
     }
 
-    /// Write `data` to the FFP's bulk data endpoint
-    pub fn write(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let timeout = Duration::from_millis(100);
+    /// Check if the attached FFP firmware supports `capability`
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities & (capability as u16) != 0
+    }
+
+    /// Read `nbytes` from the FFP's bulk data endpoint, while clocking out `fill`.
+    ///
+    /// If the firmware supports it, the FFP generates the clocks itself and
+    /// streams the received data back, otherwise `fill` is sent from the host.
+    pub fn read(&self, nbytes: usize, fill: u8) -> Result<Vec<u8>> {
+        if !self.has_capability(Capability::SPIRead) {
+            return self.write(&vec![fill; nbytes]);
+        }
+        let mut rx = Vec::with_capacity(nbytes);
+        let mut remaining = nbytes;
+        while remaining > 0 {
+            let count = usize::min(remaining, Self::MAX_READ_SIZE);
+            let value = (count & 0xFFFF) as u16;
+            let index = u16::from_le_bytes([(count >> 16) as u8, fill]);
+            self.request(Command::SPIRead, value, index)?;
+            rx.extend(self.transport.receive(count)?);
+            remaining -= count;
+        }
+        Ok(rx)
+    }
+
+    /// Execute `batch` on the FFP, returning all data received by the batch.
+    ///
+    /// Requires firmware with `Capability::Batch`.
+    pub fn batch(&self, batch: &Batch) -> Result<Vec<u8>> {
+        if !self.has_capability(Capability::Batch) {
+            Err(FFPError::UnsupportedFirmware)?;
+        }
+        self.set_framing(Framing::Batch)?;
+
+        // The FFP only accepts bulk data once out of high-z mode, so switch
+        // to the batch's first mode before sending it.
+        if self.mode.get() == Mode::HighZ {
+            if let Some(mode) = batch.modes().next() {
+                self.set_mode(mode)?;
+            }
+        }
+
         let mut rx = Vec::new();
-        for chunk in data.chunks(Self::CHUNK_SIZE) {
-            let mut rx_chunk = vec![0u8; chunk.len()];
-            self.handle.write_bulk(Self::TX_EP, chunk, timeout)
-                       .context("Error writing data")?;
-            match self.handle.read_bulk(Self::RX_EP, &mut rx_chunk, timeout) {
-                Ok(n) if n == chunk.len() => rx.extend(rx_chunk),
-                Ok(n) => Err(FFPError::NotEnoughData {
-                    expected: chunk.len(), read: n
-                })?,
-                Err(e) => Err(FFPError::USBError(e))
-                          .context("Error reading data")?,
+        for packet in batch.packets(self.mode.get()) {
+            let rx_packet = self.transport.packet(&packet.data)?;
+            // The FFP only changes mode once it has replied to the packet
+            if let Some(mode) = packet.mode {
+                self.mode.set(mode);
+            }
+            if rx_packet.len() != packet.rx {
+                Err(FFPError::NotEnoughData { expected: packet.rx, read: rx_packet.len() })?;
             }
+            rx.extend(rx_packet);
         }
         Ok(rx)
     }
 
+    /// Write `data` to the FFP's bulk data endpoint, returning the data read back.
+    ///
+    /// Several transfers are kept in flight at once to make full use of the bus.
+    pub fn write(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.set_framing(Framing::Raw)?;
+        self.transport.exchange(data)
+    }
+
+    /// Set how the FFP interprets bulk data, if not already set
+    fn set_framing(&self, framing: Framing) -> Result<()> {
+        if self.framing.get() != framing {
+            self.set(Command::SetFraming, framing as u16)?;
+            self.framing.set(framing);
+        }
+        Ok(())
+    }
+
+    /// Set SPI pin mode, and record it to track when bulk data is accepted
+    fn set_mode(&self, mode: Mode) -> Result<()> {
+        self.set(Command::SetMode, mode as u16)?;
+        self.mode.set(mode);
+        Ok(())
+    }
+
     /// Issue a control request to a specific value
     fn set(&self, request: Command, value: u16) -> Result<()> {
-        let timeout = Duration::from_millis(100);
-        match self.handle.write_control(
-            Self::REQUEST_TYPE_SET, request as u8, value, 0, &[], timeout)
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(FFPError::USBError(e))
-                        .context(format!("Error sending request {:?} {}", request, value))?,
+        self.request(request, value, 0)
+    }
+
+    /// Issue a control request with a specific value and index
+    fn request(&self, request: Command, value: u16, index: u16) -> Result<()> {
+        self.transport.control_out(request as u8, value, index)
+            .context(format!("Error sending request {:?} {}", request, value))?;
+        Ok(())
+    }
+
+    /// Read the bitmask of capabilities supported by the FFP firmware.
+    ///
+    /// Older firmware does not support the request and is treated as
+    /// having no optional capabilities.
+    fn read_capabilities(transport: &dyn Transport) -> u16 {
+        match transport.control_in(Command::GetCapabilities as u8, 0, 0, 2) {
+            Ok(buf) if buf.len() == 2 => u16::from_le_bytes([buf[0], buf[1]]),
+            _ => 0,
         }
     }
 
-    /// Return a list of all discovered FFP devices (by vendor and product ID)
-    fn enumerate_devices(context: &rusb::Context) ->
+    /// Return a list of all discovered FFP devices and their serial numbers.
+    ///
+    /// Devices whose serial number cannot be read, for example because
+    /// another process has them open, are listed with the serial "?".
+    pub fn enumerate_devices(context: &rusb::Context) ->
         Result<Vec<(rusb::Device<rusb::Context>, String)>>
     {
-        let timeout = Duration::from_millis(100);
+        Ok(Self::list_devices(context)?.into_iter().map(|device| {
+            let serial = Self::read_serial(&device).unwrap_or_else(|_| "?".to_string());
+            (device, serial)
+        }).collect())
+    }
+
+    /// Return a list of all discovered FFP devices (by vendor and product ID),
+    /// without opening them
+    pub(crate) fn list_devices(context: &rusb::Context) -> Result<Vec<rusb::Device<rusb::Context>>> {
         let mut devices = Vec::new();
         for device in context.devices().context("Error getting devices")?.iter() {
             let dd = device.device_descriptor().context("Error reading descriptor")?;
             if dd.vendor_id() == Self::ID_VENDOR && dd.product_id() == Self::ID_PRODUCT {
-                let handle = device.open().context("Error opening device")?;
-                let languages = handle.read_languages(timeout)?;
-                let serial = handle.read_serial_number_string(languages[0], &dd, timeout)?;
-                devices.push((device, serial));
+                devices.push(device);
             }
         }
         Ok(devices)
     }
+
+    /// Get the USB topology path of `device`, formatted as Linux names
+    /// devices in sysfs: the bus number, then the dot-separated hub ports.
+    pub(crate) fn port_path(device: &rusb::Device<rusb::Context>) -> Result<String> {
+        // USB allows at most 7 tiers of hubs
+        let mut ports = [0u8; 7];
+        let count = unsafe {
+            libusb1_sys::libusb_get_port_numbers(
+                device.as_raw(), ports.as_mut_ptr(), ports.len() as libc::c_int)
+        };
+        if count < 0 {
+            Err(FFPError::USBError(error_from_code(count)))?;
+        }
+        let ports: Vec<String> = ports[..count as usize].iter().map(|p| p.to_string()).collect();
+        Ok(format!("{}-{}", device.bus_number(), ports.join(".")))
+    }
+
+    /// Open `device` to read its serial number string
+    pub(crate) fn read_serial(device: &rusb::Device<rusb::Context>) -> Result<String> {
+        let handle = device.open().context("Error opening device")?;
+        Self::read_handle_serial(&handle)
+    }
+
+    /// Read the serial number string of an opened FFP
+    pub(crate) fn read_handle_serial(handle: &rusb::DeviceHandle<rusb::Context>) -> Result<String> {
+        let timeout = Duration::from_millis(100);
+        let dd = handle.device().device_descriptor().context("Error reading descriptor")?;
+        let languages = handle.read_languages(timeout)?;
+        let language = match languages.first() {
+            Some(&language) => language,
+            None => Err(FFPError::USBError(rusb::Error::NotFound))?,
+        };
+        Ok(handle.read_serial_number_string(language, &dd, timeout)?)
+    }
 }
 
 impl Drop for Programmer {
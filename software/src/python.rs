@@ -0,0 +1,301 @@
+//! Python bindings, built as the `ffp` Python extension module.
+
+use pyo3::prelude::*;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOSError};
+use pyo3::types::PyBytes;
+use crate::{FFPError, ErrorKind, error_kind, error_message};
+
+create_exception!(ffp, Error, PyException, "Error from the FFP or an attached device");
+create_exception!(ffp, DeviceError, Error, "No FFP device was found, or it was ambiguous");
+create_exception!(ffp, USBError, Error, "Error communicating with the FFP over USB");
+create_exception!(ffp, FirmwareError, Error, "Operation not supported by the FFP firmware");
+create_exception!(ffp, FlashError, Error, "Flash did not respond or rejected an operation");
+create_exception!(ffp, VerifyError, Error, "Device contents did not match the expected data");
+
+/// Convert an error into the matching Python exception.
+///
+/// The message includes the full chain of context, as printed by the CLI.
+fn to_pyerr(error: failure::Error) -> PyErr {
+    let message = error_message(&error);
+    if error.iter_chain().any(|c| c.downcast_ref::<std::io::Error>().is_some()) {
+        return PyOSError::new_err(message);
+    }
+    match error_kind(&error) {
+        ErrorKind::Device => DeviceError::new_err(message),
+        ErrorKind::USB => USBError::new_err(message),
+        ErrorKind::Firmware => FirmwareError::new_err(message),
+        ErrorKind::Flash => FlashError::new_err(message),
+        ErrorKind::Verify => VerifyError::new_err(message),
+        ErrorKind::Other => Error::new_err(message),
+    }
+}
+
+/// Call `f`, converting any error into a Python exception
+fn wrap<T>(f: impl FnOnce() -> crate::Result<T>) -> PyResult<T> {
+    f().map_err(to_pyerr)
+}
+
+fn context() -> crate::Result<rusb::Context> {
+    Ok(rusb::Context::new().map_err(FFPError::USBError)?)
+}
+
+/// Get a list of the serial numbers of all attached FFP devices
+#[pyfunction]
+fn get_serials() -> PyResult<Vec<String>> {
+    wrap(|| crate::Programmer::get_serials(&context()?))
+}
+
+/// Interface to FFP hardware.
+///
+/// Create using `Programmer.find()`, `Programmer.by_serial()`,
+/// `Programmer.by_port()`, or `Programmer.by_index()`, or use
+/// `Programmer.simulated()` to test without hardware.
+#[pyclass(module = "ffp", unsendable)]
+struct Programmer {
+    inner: crate::Programmer,
+}
+
+#[pymethods]
+impl Programmer {
+    /// Find the only attached FFP
+    #[staticmethod]
+    fn find() -> PyResult<Self> {
+        let inner = wrap(|| crate::Programmer::find(&context()?))?;
+        Ok(Self { inner })
+    }
+
+    /// Find an attached FFP by its serial number
+    #[staticmethod]
+    fn by_serial(serial: &str) -> PyResult<Self> {
+        let inner = wrap(|| crate::Programmer::by_serial(&context()?, serial))?;
+        Ok(Self { inner })
+    }
+
+    /// Find an attached FFP by its USB port path, such as "1-2.3"
+    #[staticmethod]
+    fn by_port(port: &str) -> PyResult<Self> {
+        let inner = wrap(|| crate::Programmer::by_port(&context()?, port))?;
+        Ok(Self { inner })
+    }
+
+    /// Find an attached FFP by its index in `get_serials()`
+    #[staticmethod]
+    fn by_index(index: usize) -> PyResult<Self> {
+        let inner = wrap(|| crate::Programmer::by_index(&context()?, index))?;
+        Ok(Self { inner })
+    }
+
+    /// Create a `Programmer` for a simulated FFP with an erased flash of
+    /// `size` bytes, for testing without hardware. Flash operations
+    /// complete instantly.
+    #[staticmethod]
+    #[pyo3(signature = (size=1 << 20))]
+    fn simulated(size: usize) -> PyResult<Self> {
+        if !size.is_power_of_two() || !(1 << 16..=1 << 24).contains(&size) {
+            return Err(Error::new_err("Flash size must be a power of two between 64KB and 16MB"));
+        }
+        let sim = crate::SimulatedFFP::new(size);
+        sim.set_time_scale(0.0);
+        let inner = wrap(|| crate::Programmer::from_transport(Box::new(sim)))?;
+        Ok(Self { inner })
+    }
+
+    fn led_on(&self) -> PyResult<()> { wrap(|| self.inner.led_on()) }
+    fn led_off(&self) -> PyResult<()> { wrap(|| self.inner.led_off()) }
+    fn reset(&self) -> PyResult<()> { wrap(|| self.inner.reset()) }
+    fn unreset(&self) -> PyResult<()> { wrap(|| self.inner.unreset()) }
+    fn select(&self) -> PyResult<()> { wrap(|| self.inner.select()) }
+    fn unselect(&self) -> PyResult<()> { wrap(|| self.inner.unselect()) }
+    fn high_z_mode(&self) -> PyResult<()> { wrap(|| self.inner.high_z_mode()) }
+    fn flash_mode(&self) -> PyResult<()> { wrap(|| self.inner.flash_mode()) }
+    fn fpga_mode(&self) -> PyResult<()> { wrap(|| self.inner.fpga_mode()) }
+    fn power_on(&self) -> PyResult<()> { wrap(|| self.inner.power_on()) }
+    fn power_off(&self) -> PyResult<()> { wrap(|| self.inner.power_off()) }
+    fn bootload(&self) -> PyResult<()> { wrap(|| self.inner.bootload()) }
+
+    /// Write `data` over SPI, returning the bytes read back
+    fn write<'py>(&self, py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let rx = wrap(|| self.inner.write(data))?;
+        Ok(PyBytes::new(py, &rx))
+    }
+
+    /// Read `nbytes` over SPI while clocking out `fill`
+    #[pyo3(signature = (nbytes, fill=0))]
+    fn read<'py>(&self, py: Python<'py>, nbytes: usize, fill: u8) -> PyResult<Bound<'py, PyBytes>> {
+        let rx = wrap(|| self.inner.read(nbytes, fill))?;
+        Ok(PyBytes::new(py, &rx))
+    }
+}
+
+/// IDs read from an attached flash
+#[pyclass(module = "ffp", get_all)]
+struct FlashID {
+    manufacturer_id: u8,
+    device_id: u8,
+    unique_id: Option<Vec<u8>>,
+    description: String,
+}
+
+#[pymethods]
+impl FlashID {
+    fn __str__(&self) -> String {
+        self.description.clone()
+    }
+}
+
+/// SPI flash attached to a `Programmer`
+#[pyclass(module = "ffp", unsendable)]
+struct Flash {
+    programmer: Py<Programmer>,
+    #[pyo3(get, set)]
+    retries: usize,
+}
+
+impl Flash {
+    /// Call `f` with a `Flash` using this object's `Programmer`
+    fn with<T>(&self, py: Python, f: impl FnOnce(&crate::Flash) -> crate::Result<T>)
+        -> PyResult<T>
+    {
+        let programmer = self.programmer.borrow(py);
+        let mut flash = crate::Flash::new(&programmer.inner);
+        flash.set_retries(self.retries);
+        f(&flash).map_err(to_pyerr)
+    }
+}
+
+#[pymethods]
+impl Flash {
+    #[new]
+    fn new(programmer: Py<Programmer>) -> Self {
+        Self { programmer, retries: 0 }
+    }
+
+    /// Read the flash manufacturer, device, and unique IDs
+    fn read_id(&self, py: Python) -> PyResult<FlashID> {
+        let id = self.with(py, |flash| flash.read_id())?;
+        Ok(FlashID {
+            manufacturer_id: id.manufacturer_id(),
+            device_id: id.device_id(),
+            unique_id: id.unique_id().map(|u| u.to_vec()),
+            description: id.to_string(),
+        })
+    }
+
+    /// Get the flash capacity in bytes
+    fn capacity(&self, py: Python) -> PyResult<usize> {
+        self.with(py, |flash| flash.capacity())
+    }
+
+    /// Read `length` bytes starting at `address`
+    fn read<'py>(&self, py: Python<'py>, address: u32, length: usize)
+        -> PyResult<Bound<'py, PyBytes>>
+    {
+        let data = self.with(py, |flash| flash.read(address, length))?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Erase and program `data` starting at `address`, optionally verifying it
+    #[pyo3(signature = (address, data, verify=true))]
+    fn program(&self, py: Python, address: u32, data: &[u8], verify: bool) -> PyResult<()> {
+        self.with(py, |flash| flash.program(address, data, verify))
+    }
+
+    /// Check the flash contents starting at `address` match `data`.
+    ///
+    /// Returns a list of `(address, length)` tuples of mismatching ranges.
+    fn verify(&self, py: Python, address: u32, data: &[u8]) -> PyResult<Vec<(u32, usize)>> {
+        let mismatches = self.with(py, |flash| flash.verify(address, data))?;
+        Ok(mismatches.iter().map(|m| (m.address, m.length)).collect())
+    }
+
+    /// Erase the entire flash
+    fn erase(&self, py: Python) -> PyResult<()> {
+        self.with(py, |flash| flash.erase())
+    }
+
+    /// Erase `length` bytes starting at `address`, which must be aligned
+    /// to erase blocks unless `round_outward` is set
+    #[pyo3(signature = (address, length, round_outward=false))]
+    fn erase_range(&self, py: Python, address: u32, length: usize, round_outward: bool)
+        -> PyResult<()>
+    {
+        self.with(py, |flash| flash.erase_range(address, length, round_outward))
+    }
+
+    /// Reset the flash
+    fn reset(&self, py: Python) -> PyResult<()> {
+        self.with(py, |flash| flash.reset())
+    }
+
+    /// Put the flash into deep power-down
+    fn power_down(&self, py: Python) -> PyResult<()> {
+        self.with(py, |flash| flash.power_down())
+    }
+
+    /// Release the flash from deep power-down
+    fn power_up(&self, py: Python) -> PyResult<()> {
+        self.with(py, |flash| flash.power_up())
+    }
+}
+
+/// iCE40 FPGA attached to a `Programmer`
+#[pyclass(module = "ffp", name = "FPGA", unsendable)]
+struct Fpga {
+    programmer: Py<Programmer>,
+}
+
+impl Fpga {
+    /// Call `f` with an `FPGA` using this object's `Programmer`
+    fn with(&self, py: Python, f: impl FnOnce(&crate::FPGA) -> crate::Result<()>) -> PyResult<()> {
+        let programmer = self.programmer.borrow(py);
+        f(&crate::FPGA::new(&programmer.inner)).map_err(to_pyerr)
+    }
+}
+
+#[pymethods]
+impl Fpga {
+    #[new]
+    fn new(programmer: Py<Programmer>) -> Self {
+        Self { programmer }
+    }
+
+    /// Reset the FPGA
+    fn reset(&self, py: Python) -> PyResult<()> {
+        self.with(py, |fpga| fpga.reset())
+    }
+
+    /// Enable target power
+    fn power_on(&self, py: Python) -> PyResult<()> {
+        self.with(py, |fpga| fpga.power_on())
+    }
+
+    /// Disable target power
+    fn power_off(&self, py: Python) -> PyResult<()> {
+        self.with(py, |fpga| fpga.power_off())
+    }
+
+    /// Program the FPGA with `bitstream` over slave SPI
+    fn program(&self, py: Python, bitstream: &[u8]) -> PyResult<()> {
+        self.with(py, |fpga| fpga.program(bitstream))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "ffp")]
+fn python_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_function(wrap_pyfunction!(get_serials, m)?)?;
+    m.add_class::<Programmer>()?;
+    m.add_class::<Flash>()?;
+    m.add_class::<FlashID>()?;
+    m.add_class::<Fpga>()?;
+    m.add("Error", py.get_type::<Error>())?;
+    m.add("DeviceError", py.get_type::<DeviceError>())?;
+    m.add("USBError", py.get_type::<USBError>())?;
+    m.add("FirmwareError", py.get_type::<FirmwareError>())?;
+    m.add("FlashError", py.get_type::<FlashError>())?;
+    m.add("VerifyError", py.get_type::<VerifyError>())?;
+    Ok(())
+}
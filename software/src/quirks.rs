@@ -0,0 +1,100 @@
+/// How a flash vendor provides a factory-programmed unique ID
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum UniqueID {
+    /// Send `command` then `dummy` bytes, and receive `length` bytes of ID
+    Command { command: u8, dummy: usize, length: usize },
+    /// Read `length` bytes starting at `offset` in an extended JEDEC ID response
+    ExtendedJEDECID { offset: usize, length: usize },
+    /// No unique ID is available
+    None,
+}
+
+/// How a flash vendor implements software reset
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum ResetSequence {
+    /// JEDEC standard Enable Reset (0x66) followed by Reset (0x99)
+    EnableReset,
+    /// Single legacy software reset command (0xF0)
+    Legacy,
+}
+
+/// How a flash vendor implements erase and program suspend
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Suspend {
+    /// Send `suspend` or `resume`, and read a register with `status`, in
+    /// which any bit in `mask` is set while an operation is suspended
+    Command { suspend: u8, resume: u8, status: u8, mask: u8 },
+    /// Suspend is not supported, or its commands are not known
+    None,
+}
+
+/// Vendor-specific behaviour of a flash manufacturer
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Quirks {
+    pub manufacturer_id: u8,
+    pub unique_id: UniqueID,
+    pub reset: ResetSequence,
+    pub suspend: Suspend,
+}
+
+const WINBOND: Quirks = Quirks {
+    manufacturer_id: 0xEF,
+    unique_id: UniqueID::Command { command: 0x4B, dummy: 4, length: 8 },
+    reset: ResetSequence::EnableReset,
+    suspend: Suspend::Command { suspend: 0x75, resume: 0x7A, status: 0x35, mask: 0x80 },
+};
+
+/// Macronix reports suspend in its security register
+const MACRONIX: Quirks = Quirks {
+    manufacturer_id: 0xC2,
+    unique_id: UniqueID::None,
+    reset: ResetSequence::EnableReset,
+    suspend: Suspend::Command { suspend: 0xB0, resume: 0x30, status: 0x2B, mask: 0x0C },
+};
+
+/// Micron returns its unique ID after the extended device ID bytes, and
+/// reports suspend in its flag status register
+const MICRON: Quirks = Quirks {
+    manufacturer_id: 0x20,
+    unique_id: UniqueID::ExtendedJEDECID { offset: 6, length: 14 },
+    reset: ResetSequence::EnableReset,
+    suspend: Suspend::Command { suspend: 0x75, resume: 0x7A, status: 0x70, mask: 0x44 },
+};
+
+const ISSI: Quirks = Quirks {
+    manufacturer_id: 0x9D,
+    unique_id: UniqueID::Command { command: 0x4B, dummy: 4, length: 16 },
+    reset: ResetSequence::EnableReset,
+    suspend: Suspend::None,
+};
+
+const GIGADEVICE: Quirks = Quirks {
+    manufacturer_id: 0xC8,
+    unique_id: UniqueID::Command { command: 0x4B, dummy: 4, length: 16 },
+    reset: ResetSequence::EnableReset,
+    suspend: Suspend::Command { suspend: 0x75, resume: 0x7A, status: 0x35, mask: 0x84 },
+};
+
+const SPANSION: Quirks = Quirks {
+    manufacturer_id: 0x01,
+    unique_id: UniqueID::Command { command: 0x4B, dummy: 4, length: 8 },
+    reset: ResetSequence::Legacy,
+    suspend: Suspend::None,
+};
+
+const KNOWN: &[Quirks] = &[WINBOND, MACRONIX, MICRON, ISSI, GIGADEVICE, SPANSION];
+
+impl Quirks {
+    /// Find the quirks for the given JEDEC manufacturer ID.
+    ///
+    /// Unknown manufacturers are assumed to support the JEDEC standard
+    /// reset sequence but to have no unique ID or suspend.
+    pub fn for_manufacturer(manufacturer_id: u8) -> Self {
+        KNOWN.iter().find(|q| q.manufacturer_id == manufacturer_id).copied().unwrap_or(Quirks {
+            manufacturer_id,
+            unique_id: UniqueID::None,
+            reset: ResetSequence::EnableReset,
+            suspend: Suspend::None,
+        })
+    }
+}
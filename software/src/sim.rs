@@ -0,0 +1,1243 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use crate::{FFPError, Result, Transport, crc32};
+use crate::transfer::{Endpoints, Pipeline, Transfer};
+
+/// Vendor control requests handled by the FFP firmware
+mod request {
+    pub const SET_CS: u8 = 1;
+    pub const SET_FPGA_RESET: u8 = 2;
+    pub const SET_MODE: u8 = 3;
+    pub const SET_TPWR: u8 = 4;
+    pub const GET_TPWR: u8 = 5;
+    pub const SET_LED: u8 = 6;
+    pub const BOOTLOAD: u8 = 7;
+    pub const GET_CAPABILITIES: u8 = 8;
+    pub const SPI_READ: u8 = 9;
+    pub const SET_FRAMING: u8 = 10;
+}
+
+/// Batch operation opcodes handled by the FFP firmware
+mod opcode {
+    pub const SELECT: u8 = 1;
+    pub const UNSELECT: u8 = 2;
+    pub const TRANSFER: u8 = 3;
+    pub const READ: u8 = 4;
+    pub const DELAY: u8 = 5;
+    pub const SET_MODE: u8 = 6;
+    pub const PAGE_LOAD: u8 = 7;
+    pub const PAGE_PROGRAM: u8 = 8;
+    pub const WAIT_BUSY: u8 = 9;
+    pub const CRC32: u8 = 10;
+}
+
+/// Commands understood by the emulated flash
+mod command {
+    pub const WRITE_ENABLE: u8 = 0x06;
+    pub const WRITE_DISABLE: u8 = 0x04;
+    pub const READ_STATUS_1: u8 = 0x05;
+    pub const READ_STATUS_2: u8 = 0x35;
+    pub const WRITE_STATUS: u8 = 0x01;
+    pub const PAGE_PROGRAM: u8 = 0x02;
+    pub const SECTOR_ERASE: u8 = 0x20;
+    pub const BLOCK_ERASE_32K: u8 = 0x52;
+    pub const BLOCK_ERASE_64K: u8 = 0xD8;
+    pub const CHIP_ERASE: u8 = 0xC7;
+    pub const CHIP_ERASE_ALT: u8 = 0x60;
+    pub const SUSPEND: u8 = 0x75;
+    pub const RESUME: u8 = 0x7A;
+    pub const MACRONIX_SUSPEND: u8 = 0xB0;
+    pub const MACRONIX_RESUME: u8 = 0x30;
+    pub const READ_SECURITY: u8 = 0x2B;
+    pub const POWER_DOWN: u8 = 0xB9;
+    pub const RELEASE_POWER_DOWN: u8 = 0xAB;
+    pub const READ_DATA: u8 = 0x03;
+    pub const FAST_READ: u8 = 0x0B;
+    pub const DEVICE_ID: u8 = 0x90;
+    pub const JEDEC_ID: u8 = 0x9F;
+    pub const UNIQUE_ID: u8 = 0x4B;
+    pub const ENABLE_RESET: u8 = 0x66;
+    pub const RESET: u8 = 0x99;
+    pub const EXIT_QPI: u8 = 0xFF;
+    pub const MACRONIX_EXIT_QPI: u8 = 0xF5;
+}
+
+/// SPI NAND commands used by the simulation
+mod nand_command {
+    pub const RESET: u8 = 0xFF;
+    pub const JEDEC_ID: u8 = 0x9F;
+    pub const GET_FEATURE: u8 = 0x0F;
+    pub const SET_FEATURE: u8 = 0x1F;
+    pub const WRITE_ENABLE: u8 = 0x06;
+    pub const WRITE_DISABLE: u8 = 0x04;
+    pub const PAGE_DATA_READ: u8 = 0x13;
+    pub const FAST_READ: u8 = 0x0B;
+    pub const PROGRAM_LOAD: u8 = 0x02;
+    pub const RANDOM_PROGRAM_LOAD: u8 = 0x84;
+    pub const PROGRAM_EXECUTE: u8 = 0x10;
+    pub const BLOCK_ERASE: u8 = 0xD8;
+}
+
+/// DataFlash commands used by the simulation
+mod dataflash_command {
+    pub const CONTINUOUS_READ: u8 = 0x0B;
+    pub const STATUS: u8 = 0xD7;
+    pub const JEDEC_ID: u8 = 0x9F;
+    pub const BUFFER1_WRITE: u8 = 0x84;
+    pub const BUFFER1_TO_MAIN_WITH_ERASE: u8 = 0x83;
+    pub const MAIN_TO_BUFFER1: u8 = 0x53;
+    pub const PAGE_ERASE: u8 = 0x81;
+    pub const BLOCK_ERASE: u8 = 0x50;
+    pub const CHIP_ERASE: [u8; 4] = [0xC7, 0x94, 0x80, 0x9A];
+    pub const BINARY_PAGES: [u8; 4] = [0x3D, 0x2A, 0x80, 0xA6];
+}
+
+const MODE_HIGH_Z: u16 = 0;
+const MODE_FLASH: u16 = 1;
+const MODE_FPGA: u16 = 2;
+
+/// Emulated Winbond W25Q-series SPI NOR flash.
+///
+/// Commands are decoded as bytes are clocked in, and program and erase
+/// operations take effect when CS is deasserted. While a program or erase
+/// is in progress the BUSY status bit is set for the typical duration of
+/// the operation and all other commands except status reads, suspend, and
+/// reset are ignored.
+///
+/// When reporting the Macronix manufacturer ID, suspend and resume use the
+/// Macronix commands and the suspend status is in the security register.
+/// Other manufacturer IDs do not support suspend.
+///
+/// The flash can be left in continuous-read (XIP) or QPI mode, as by an
+/// FPGA, in which it reads back all 1s. XIP mode ends when the mode bits
+/// are clocked in as 0xFF. QPI mode ends on the QPI exit command for the
+/// manufacturer, 0xFF or Macronix's 0xF5, or on a software reset.
+struct SpiFlash {
+    manufacturer_id: u8,
+    memory: Vec<u8>,
+    selected: bool,
+    rx: Vec<u8>,
+    write_enabled: bool,
+    reset_enabled: bool,
+    powered_down: bool,
+    busy_until: Option<Instant>,
+    suspended: Option<Duration>,
+    time_scale: f64,
+    /// Address, bit mask, and number of remaining page programs of each
+    /// injected bit error
+    program_errors: Vec<(usize, u8, usize)>,
+    xip: bool,
+    qpi: bool,
+}
+
+impl SpiFlash {
+    const WINBOND: u8 = 0xEF;
+    const MACRONIX: u8 = 0xC2;
+    const MEMORY_TYPE: u8 = 0x40;
+    const UNIQUE_ID: [u8; 8] = [0xD2, 0x66, 0xB4, 0x13, 0x5F, 0x2A, 0x28, 0x37];
+    const PAGE_SIZE: usize = 256;
+    const PAGE_PROGRAM_TIME: Duration = Duration::from_micros(700);
+    const SECTOR_ERASE_TIME: Duration = Duration::from_millis(45);
+    const BLOCK_ERASE_32K_TIME: Duration = Duration::from_millis(120);
+    const BLOCK_ERASE_64K_TIME: Duration = Duration::from_millis(150);
+    const CHIP_ERASE_TIME_PER_MB: Duration = Duration::from_millis(2000);
+
+    fn new(memory: Vec<u8>) -> Self {
+        Self {
+            manufacturer_id: Self::WINBOND, memory, selected: false, rx: Vec::new(), write_enabled: false,
+            reset_enabled: false, powered_down: false, busy_until: None,
+            suspended: None, time_scale: 1.0, program_errors: Vec::new(), xip: false, qpi: false,
+        }
+    }
+
+    /// JEDEC capacity code, the base-2 logarithm of the size in bytes
+    fn capacity_code(&self) -> u8 {
+        self.memory.len().trailing_zeros() as u8
+    }
+
+    fn busy(&self) -> bool {
+        self.busy_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    fn status1(&self) -> u8 {
+        (self.busy() as u8) | ((self.write_enabled as u8) << 1)
+    }
+
+    fn status2(&self) -> u8 {
+        match self.manufacturer_id {
+            Self::WINBOND => (self.suspended.is_some() as u8) << 7,
+            _ => 0x00,
+        }
+    }
+
+    /// Macronix security register, with the erase suspend bit
+    fn security(&self) -> u8 {
+        match self.manufacturer_id {
+            Self::MACRONIX => (self.suspended.is_some() as u8) << 3,
+            _ => 0x00,
+        }
+    }
+
+    /// Suspend and resume commands for the emulated manufacturer, if supported
+    fn suspend_commands(&self) -> Option<(u8, u8)> {
+        match self.manufacturer_id {
+            Self::WINBOND => Some((command::SUSPEND, command::RESUME)),
+            Self::MACRONIX => Some((command::MACRONIX_SUSPEND, command::MACRONIX_RESUME)),
+            _ => None,
+        }
+    }
+
+    /// QPI exit command for the emulated manufacturer, if known
+    fn qpi_exit_command(&self) -> Option<u8> {
+        match self.manufacturer_id {
+            Self::WINBOND => Some(command::EXIT_QPI),
+            Self::MACRONIX => Some(command::MACRONIX_EXIT_QPI),
+            _ => None,
+        }
+    }
+
+    fn select(&mut self) {
+        self.selected = true;
+        self.rx.clear();
+    }
+
+    fn unselect(&mut self) {
+        if self.selected {
+            self.selected = false;
+            self.execute();
+        }
+    }
+
+    /// Clock one byte in from `mosi`, returning the byte clocked out
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        if !self.selected {
+            return 0xFF;
+        }
+        self.rx.push(mosi);
+        let idx = self.rx.len() - 1;
+        if idx == 0 || self.xip || self.qpi {
+            return 0xFF;
+        }
+        let command = self.rx[0];
+        if self.powered_down && command != command::RELEASE_POWER_DOWN {
+            return 0xFF;
+        }
+        let status_read = [command::READ_STATUS_1, command::READ_STATUS_2, command::READ_SECURITY];
+        if self.busy() && !status_read.contains(&command) {
+            return 0xFF;
+        }
+        let size = self.memory.len();
+        match command {
+            command::READ_STATUS_1 => self.status1(),
+            command::READ_STATUS_2 => self.status2(),
+            command::READ_SECURITY => self.security(),
+            command::JEDEC_ID => match idx {
+                1 => self.manufacturer_id,
+                2 => Self::MEMORY_TYPE,
+                3 => self.capacity_code(),
+                _ => 0x00,
+            },
+            command::DEVICE_ID if idx >= 4 =>
+                [self.manufacturer_id, self.capacity_code() - 1][(idx - 4) % 2],
+            command::RELEASE_POWER_DOWN if idx >= 4 => self.capacity_code() - 1,
+            command::UNIQUE_ID if idx >= 5 => Self::UNIQUE_ID[(idx - 5) % 8],
+            command::READ_DATA if idx >= 4 => self.memory[(self.address() + idx - 4) % size],
+            command::FAST_READ if idx >= 5 => self.memory[(self.address() + idx - 5) % size],
+            _ => 0xFF,
+        }
+    }
+
+    /// Address sent in bytes 1 to 3 of the current command
+    fn address(&self) -> usize {
+        let address = u32::from_be_bytes([0, self.rx[1], self.rx[2], self.rx[3]]) as usize;
+        address % self.memory.len()
+    }
+
+    /// Carry out the command received while CS was asserted
+    fn execute(&mut self) {
+        let rx = self.rx.clone();
+        let command = match rx.first() {
+            Some(&command) => command,
+            None => return,
+        };
+        let reset_enabled = std::mem::replace(&mut self.reset_enabled, false);
+        let (suspend, resume) = self.suspend_commands().unzip();
+
+        if self.xip {
+            // The first byte is taken as address, and the mode bits follow it
+            if rx.len() >= 2 && rx[..2] == [0xFF, 0xFF] {
+                self.xip = false;
+            }
+            return;
+        }
+        if self.qpi {
+            if Some(command) == self.qpi_exit_command() {
+                self.qpi = false;
+                return;
+            } else if command == command::RESET && reset_enabled {
+                self.qpi = false;
+            } else if command != command::ENABLE_RESET {
+                return;
+            }
+        }
+
+        if self.powered_down {
+            if command == command::RELEASE_POWER_DOWN {
+                self.powered_down = false;
+            }
+            return;
+        }
+
+        match command {
+            command::ENABLE_RESET => self.reset_enabled = true,
+            command::RESET if reset_enabled => {
+                self.write_enabled = false;
+                self.busy_until = None;
+                self.suspended = None;
+            },
+            _ if self.busy() && Some(command) == suspend => {
+                let remaining = self.busy_until.unwrap() - Instant::now();
+                self.suspended = Some(remaining);
+                self.busy_until = None;
+            },
+            _ if self.busy() => (),
+            command::WRITE_ENABLE => self.write_enabled = true,
+            command::WRITE_DISABLE => self.write_enabled = false,
+            command::WRITE_STATUS => self.write_enabled = false,
+            command::POWER_DOWN => self.powered_down = true,
+            _ if Some(command) == resume => {
+                if let Some(remaining) = self.suspended.take() {
+                    self.busy_until = Some(Instant::now() + remaining);
+                }
+            },
+            command::PAGE_PROGRAM if rx.len() > 4 && self.writable() => {
+                // Data wraps around within the page, and can only clear bits
+                let address = self.address();
+                let page = address - address % Self::PAGE_SIZE;
+                for (idx, byte) in rx[4..].iter().enumerate() {
+                    let offset = (address + idx) % Self::PAGE_SIZE;
+                    self.memory[page + offset] &= byte;
+                }
+                for (address, mask, count) in self.program_errors.iter_mut() {
+                    if *count > 0 && *address - *address % Self::PAGE_SIZE == page {
+                        self.memory[*address] ^= *mask;
+                        *count -= 1;
+                    }
+                }
+                self.start(Self::PAGE_PROGRAM_TIME);
+            },
+            command::SECTOR_ERASE if rx.len() == 4 && self.writable() =>
+                self.erase(4 * 1024, Self::SECTOR_ERASE_TIME),
+            command::BLOCK_ERASE_32K if rx.len() == 4 && self.writable() =>
+                self.erase(32 * 1024, Self::BLOCK_ERASE_32K_TIME),
+            command::BLOCK_ERASE_64K if rx.len() == 4 && self.writable() =>
+                self.erase(64 * 1024, Self::BLOCK_ERASE_64K_TIME),
+            command::CHIP_ERASE | command::CHIP_ERASE_ALT
+                if rx.len() == 1 && self.writable() && self.suspended.is_none() =>
+            {
+                self.memory.iter_mut().for_each(|b| *b = 0xFF);
+                let megabytes = self.memory.len() as f64 / (1024.0 * 1024.0);
+                self.start(Self::CHIP_ERASE_TIME_PER_MB.mul_f64(megabytes));
+            },
+            _ => (),
+        }
+    }
+
+    /// Check the write enable latch is set, then clear it for the operation starting
+    fn writable(&mut self) -> bool {
+        std::mem::replace(&mut self.write_enabled, false)
+    }
+
+    /// Erase the `size` byte block containing the current command's address
+    fn erase(&mut self, size: usize, duration: Duration) {
+        let start = self.address() - self.address() % size;
+        self.memory[start..start+size].iter_mut().for_each(|b| *b = 0xFF);
+        self.start(duration);
+    }
+
+    /// Set BUSY for `duration`, scaled by the simulation time scale
+    fn start(&mut self, duration: Duration) {
+        self.busy_until = Some(Instant::now() + duration.mul_f64(self.time_scale));
+    }
+}
+
+/// Emulated Winbond W25N01GV SPI NAND flash.
+///
+/// Only programmed pages are stored, and page reads, programs, and erases
+/// complete instantly. Factory bad blocks have their bad block marker
+/// cleared, and programming or erasing a failing block sets the matching
+/// failure bit in the status register instead of succeeding.
+struct SpiNand {
+    pages: HashMap<u32, Vec<u8>>,
+    cache: Vec<u8>,
+    failing: Vec<u32>,
+    selected: bool,
+    rx: Vec<u8>,
+    write_enabled: bool,
+    status: u8,
+    protection: u8,
+    config: u8,
+}
+
+impl SpiNand {
+    const ID: [u8; 3] = [0xEF, 0xAA, 0x21];
+    const DATA_SIZE: usize = 2048;
+    const PAGE_SIZE: usize = Self::DATA_SIZE + 64;
+    const PAGES_PER_BLOCK: u32 = 64;
+    const BLOCKS: u32 = 1024;
+    const ERASE_FAIL: u8 = 1 << 2;
+    const PROGRAM_FAIL: u8 = 1 << 3;
+
+    fn new(bad_blocks: &[u32]) -> Self {
+        let mut nand = Self {
+            pages: HashMap::new(), cache: vec![0xFF; Self::PAGE_SIZE], failing: Vec::new(),
+            selected: false, rx: Vec::new(), write_enabled: false, status: 0,
+            protection: 0x7C, config: 0,
+        };
+        for &block in bad_blocks {
+            let mut page = vec![0xFF; Self::PAGE_SIZE];
+            page[Self::DATA_SIZE] = 0x00;
+            nand.pages.insert(block * Self::PAGES_PER_BLOCK, page);
+        }
+        nand
+    }
+
+    /// Contents of `page` including its spare area
+    fn page(&self, page: u32) -> Vec<u8> {
+        self.pages.get(&page).cloned().unwrap_or_else(|| vec![0xFF; Self::PAGE_SIZE])
+    }
+
+    fn select(&mut self) {
+        self.selected = true;
+        self.rx.clear();
+    }
+
+    fn unselect(&mut self) {
+        if self.selected {
+            self.selected = false;
+            self.execute();
+        }
+    }
+
+    /// Clock one byte in from `mosi`, returning the byte clocked out
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        if !self.selected {
+            return 0xFF;
+        }
+        self.rx.push(mosi);
+        let idx = self.rx.len() - 1;
+        match self.rx[0] {
+            // The ID follows one dummy byte
+            nand_command::JEDEC_ID if idx >= 2 => Self::ID.get(idx - 2).copied().unwrap_or(0x00),
+            nand_command::GET_FEATURE if idx == 2 => self.feature(self.rx[1]),
+            nand_command::FAST_READ if idx >= 4 =>
+                self.cache.get(self.column() + idx - 4).copied().unwrap_or(0xFF),
+            _ => 0xFF,
+        }
+    }
+
+    fn feature(&self, address: u8) -> u8 {
+        match address {
+            0xA0 => self.protection,
+            0xB0 => self.config,
+            0xC0 => self.status | (self.write_enabled as u8) << 1,
+            _ => 0x00,
+        }
+    }
+
+    /// Column address sent in bytes 1 and 2 of the current command
+    fn column(&self) -> usize {
+        (u16::from_be_bytes([self.rx[1], self.rx[2]]) & 0x0FFF) as usize
+    }
+
+    /// Page address sent in bytes 1 to 3 of the current command
+    fn row(&self) -> u32 {
+        u32::from_be_bytes([0, self.rx[1], self.rx[2], self.rx[3]]) % (Self::BLOCKS * Self::PAGES_PER_BLOCK)
+    }
+
+    /// Carry out the command received while CS was asserted
+    fn execute(&mut self) {
+        let rx = self.rx.clone();
+        let command = match rx.first() {
+            Some(&command) => command,
+            None => return,
+        };
+        match command {
+            nand_command::RESET => {
+                self.write_enabled = false;
+                self.status = 0;
+            },
+            nand_command::WRITE_ENABLE => self.write_enabled = true,
+            nand_command::WRITE_DISABLE => self.write_enabled = false,
+            nand_command::SET_FEATURE if rx.len() == 3 => match rx[1] {
+                0xA0 => self.protection = rx[2],
+                0xB0 => self.config = rx[2],
+                _ => (),
+            },
+            nand_command::PAGE_DATA_READ if rx.len() == 4 => self.cache = self.page(self.row()),
+            nand_command::PROGRAM_LOAD | nand_command::RANDOM_PROGRAM_LOAD if rx.len() >= 3 => {
+                if command == nand_command::PROGRAM_LOAD {
+                    self.cache = vec![0xFF; Self::PAGE_SIZE];
+                }
+                let column = self.column();
+                for (cache, byte) in self.cache.iter_mut().skip(column).zip(&rx[3..]) {
+                    *cache = *byte;
+                }
+            },
+            nand_command::PROGRAM_EXECUTE if rx.len() == 4 && self.writable() => {
+                // Programming can only clear bits, and still does so in a failing block
+                let page = self.row();
+                let mut data = self.page(page);
+                data.iter_mut().zip(&self.cache).for_each(|(d, c)| *d &= c);
+                self.pages.insert(page, data);
+                self.status = if self.failing.contains(&(page / Self::PAGES_PER_BLOCK)) {
+                    Self::PROGRAM_FAIL
+                } else {
+                    0
+                };
+            },
+            nand_command::BLOCK_ERASE if rx.len() == 4 && self.writable() => {
+                let block = self.row() / Self::PAGES_PER_BLOCK;
+                if self.failing.contains(&block) {
+                    self.status = Self::ERASE_FAIL;
+                } else {
+                    self.pages.retain(|page, _| page / Self::PAGES_PER_BLOCK != block);
+                    self.status = 0;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Check the write enable latch is set, then clear it for the operation starting
+    fn writable(&mut self) -> bool {
+        std::mem::replace(&mut self.write_enabled, false)
+    }
+}
+
+/// Emulated Adesto AT45DB041E DataFlash.
+///
+/// Pages are stored at their full DataFlash size. In power-of-2 page mode
+/// only the first 256 bytes of each page are used, and the page number
+/// starts at address bit 8 instead of bit 9. While a program, erase, or
+/// buffer transfer is in progress READY is cleared for the typical duration
+/// of the operation and all other commands except status reads are ignored.
+/// Configuring power-of-2 pages takes effect immediately rather than after
+/// a power cycle.
+struct SpiDataFlash {
+    memory: Vec<u8>,
+    buffer: Vec<u8>,
+    binary_pages: bool,
+    selected: bool,
+    rx: Vec<u8>,
+    busy_until: Option<Instant>,
+    time_scale: f64,
+}
+
+impl SpiDataFlash {
+    const ID: [u8; 5] = [0x1F, 0x24, 0x00, 0x01, 0x00];
+    const PAGES: usize = 2048;
+    const PAGE_SIZE: usize = 264;
+
+    /// Status register with the density code for 4Mbit
+    const STATUS: u8 = 0b0111 << 2;
+    const READY: u8 = 0x80;
+
+    const BUFFER_TRANSFER_TIME: Duration = Duration::from_micros(200);
+    const PAGE_PROGRAM_TIME: Duration = Duration::from_millis(15);
+    const PAGE_ERASE_TIME: Duration = Duration::from_millis(12);
+    const BLOCK_ERASE_TIME: Duration = Duration::from_millis(30);
+    const CHIP_ERASE_TIME: Duration = Duration::from_secs(7);
+
+    fn new(binary_pages: bool) -> Self {
+        Self {
+            memory: vec![0xFF; Self::PAGES * Self::PAGE_SIZE], buffer: vec![0xFF; Self::PAGE_SIZE],
+            binary_pages, selected: false, rx: Vec::new(), busy_until: None, time_scale: 1.0,
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.busy_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    /// Clear READY for `duration`, scaled by the simulation time scale
+    fn start(&mut self, duration: Duration) {
+        self.busy_until = Some(Instant::now() + duration.mul_f64(self.time_scale));
+    }
+
+    /// Size of each page in the configured addressing mode
+    fn page_size(&self) -> usize {
+        if self.binary_pages { 256 } else { Self::PAGE_SIZE }
+    }
+
+    /// Page and byte within the page addressed by bytes 1 to 3 of the current command
+    fn address(&self) -> (usize, usize) {
+        let address = u32::from_be_bytes([0, self.rx[1], self.rx[2], self.rx[3]]) as usize;
+        let shift = if self.binary_pages { 8 } else { 9 };
+        ((address >> shift) % Self::PAGES, (address & ((1 << shift) - 1)) % self.page_size())
+    }
+
+    fn select(&mut self) {
+        self.selected = true;
+        self.rx.clear();
+    }
+
+    fn unselect(&mut self) {
+        if self.selected {
+            self.selected = false;
+            self.execute();
+        }
+    }
+
+    /// Clock one byte in from `mosi`, returning the byte clocked out
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        if !self.selected {
+            return 0xFF;
+        }
+        self.rx.push(mosi);
+        let idx = self.rx.len() - 1;
+        if self.busy() && self.rx[0] != dataflash_command::STATUS {
+            return 0xFF;
+        }
+        match self.rx[0] {
+            dataflash_command::JEDEC_ID if idx >= 1 => Self::ID.get(idx - 1).copied().unwrap_or(0x00),
+            dataflash_command::STATUS if idx >= 1 => {
+                let ready = if self.busy() { 0 } else { Self::READY };
+                ready | Self::STATUS | self.binary_pages as u8
+            },
+            dataflash_command::CONTINUOUS_READ if idx >= 5 => {
+                // Reads continue into the following pages
+                let (page, byte) = self.address();
+                let offset = byte + idx - 5;
+                let page = (page + offset / self.page_size()) % Self::PAGES;
+                self.memory[page * Self::PAGE_SIZE + offset % self.page_size()]
+            },
+            _ => 0xFF,
+        }
+    }
+
+    /// Carry out the command received while CS was asserted
+    fn execute(&mut self) {
+        let rx = self.rx.clone();
+        let command = match rx.first() {
+            Some(&command) => command,
+            None => return,
+        };
+        if self.busy() {
+            return;
+        }
+        let page_size = self.page_size();
+        match command {
+            dataflash_command::BUFFER1_WRITE if rx.len() > 4 => {
+                let (_, byte) = self.address();
+                for (idx, data) in rx[4..].iter().enumerate() {
+                    self.buffer[(byte + idx) % page_size] = *data;
+                }
+            },
+            dataflash_command::BUFFER1_TO_MAIN_WITH_ERASE if rx.len() == 4 => {
+                let start = self.address().0 * Self::PAGE_SIZE;
+                self.memory[start..start+page_size].copy_from_slice(&self.buffer[..page_size]);
+                self.start(Self::PAGE_PROGRAM_TIME);
+            },
+            dataflash_command::MAIN_TO_BUFFER1 if rx.len() == 4 => {
+                let start = self.address().0 * Self::PAGE_SIZE;
+                self.buffer[..page_size].copy_from_slice(&self.memory[start..start+page_size]);
+                self.start(Self::BUFFER_TRANSFER_TIME);
+            },
+            dataflash_command::PAGE_ERASE if rx.len() == 4 =>
+                self.erase(self.address().0, 1, Self::PAGE_ERASE_TIME),
+            dataflash_command::BLOCK_ERASE if rx.len() == 4 =>
+                self.erase(self.address().0 & !7, 8, Self::BLOCK_ERASE_TIME),
+            _ if rx == dataflash_command::CHIP_ERASE =>
+                self.erase(0, Self::PAGES, Self::CHIP_ERASE_TIME),
+            _ if rx == dataflash_command::BINARY_PAGES => {
+                self.binary_pages = true;
+                self.start(Self::PAGE_PROGRAM_TIME);
+            },
+            _ => (),
+        }
+    }
+
+    /// Erase `count` pages starting at `page`, taking `duration`
+    fn erase(&mut self, page: usize, count: usize, duration: Duration) {
+        let range = page * Self::PAGE_SIZE..(page + count) * Self::PAGE_SIZE;
+        self.memory[range].iter_mut().for_each(|b| *b = 0xFF);
+        self.start(duration);
+    }
+}
+
+/// Memory attached to the simulated FFP in place of the SPI NOR flash
+enum Attached {
+    Nand(SpiNand),
+    DataFlash(SpiDataFlash),
+}
+
+/// Emulated Lattice iCE40 FPGA slave SPI configuration interface.
+///
+/// Releasing CRESET while SS is asserted enters slave configuration, after
+/// which bytes are received while SS is asserted. Once SS is deasserted
+/// and enough further clocks are sent, the FPGA is configured if the data
+/// contained a valid bitstream preamble. Releasing CRESET with SS
+/// deasserted boots from flash instead.
+#[derive(Default)]
+struct Ice40 {
+    receiving: bool,
+    configured: bool,
+    received: Vec<u8>,
+    bitstream: Vec<u8>,
+    clocks: usize,
+}
+
+impl Ice40 {
+    const PREAMBLE: [u8; 4] = [0x7E, 0xAA, 0x99, 0x7E];
+
+    /// Clocks required after the bitstream for configuration to complete
+    const DONE_CLOCKS: usize = 49;
+
+    fn has_preamble(data: &[u8]) -> bool {
+        data.windows(4).any(|w| w == Self::PREAMBLE)
+    }
+
+    fn reset(&mut self) {
+        self.receiving = false;
+        self.configured = false;
+    }
+
+    fn release(&mut self, ss_asserted: bool, flash: &[u8]) {
+        if ss_asserted {
+            self.receiving = true;
+            self.received.clear();
+            self.clocks = 0;
+        } else {
+            self.configured = Self::has_preamble(&flash[..usize::min(flash.len(), 256)]);
+        }
+    }
+
+    fn transfer(&mut self, ss_asserted: bool, mosi: u8) -> u8 {
+        if self.receiving {
+            if ss_asserted {
+                self.received.push(mosi);
+                self.clocks = 0;
+            } else if !self.received.is_empty() {
+                self.clocks += 8;
+                if self.clocks >= Self::DONE_CLOCKS {
+                    self.receiving = false;
+                    self.configured = Self::has_preamble(&self.received);
+                    self.bitstream = std::mem::take(&mut self.received);
+                }
+            }
+        }
+        0xFF
+    }
+}
+
+/// State of the simulated FFP and its attached devices
+struct Simulation {
+    flash: SpiFlash,
+    attached: Option<Attached>,
+    fpga: Ice40,
+    capabilities: u16,
+    mode: u16,
+    cs_asserted: bool,
+    fpga_reset_asserted: bool,
+    tpwr: bool,
+    external_power: bool,
+    led: bool,
+    batch_framing: bool,
+    spi_read: (usize, u8),
+    page_buf: [u8; 256],
+    time_scale: f64,
+    /// Reply in the single EP1 transmit buffer. As in the firmware, EP1
+    /// OUT is only re-armed once the host has collected it.
+    ep1_tx: Option<Vec<u8>>,
+    /// Packets sent to EP1 OUT while it was not armed, which the host
+    /// controller keeps retrying until the FFP accepts them
+    ep1_rx: VecDeque<Vec<u8>>,
+    /// Number of bulk packets accepted on EP1 OUT
+    bulk_packets: usize,
+    /// Number of pipelined bulk packets sent while the FFP was idle, each
+    /// of which costs the host a full USB round trip
+    bulk_round_trips: usize,
+}
+
+impl Simulation {
+    /// Number of status polls to wait for a page program, as in the firmware
+    const PAGE_PROGRAM_TIMEOUT: Duration = Duration::from_millis(20);
+
+    /// Maximum time to wait in each WaitBusy operation, as in the firmware
+    const WAIT_BUSY_TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn set_cs(&mut self, asserted: bool) {
+        self.cs_asserted = asserted;
+        if self.mode == MODE_HIGH_Z {
+            return;
+        }
+        match (&mut self.attached, asserted) {
+            (Some(Attached::Nand(nand)), true) => nand.select(),
+            (Some(Attached::Nand(nand)), false) => nand.unselect(),
+            (Some(Attached::DataFlash(dataflash)), true) => dataflash.select(),
+            (Some(Attached::DataFlash(dataflash)), false) => dataflash.unselect(),
+            (None, true) => self.flash.select(),
+            (None, false) => self.flash.unselect(),
+        }
+    }
+
+    /// Exchange one byte over SPI. The flash shares CS with the FPGA's SS,
+    /// so sees all traffic, but MISO is only connected in the current mode.
+    fn spi_byte(&mut self, mosi: u8) -> u8 {
+        match self.mode {
+            MODE_FLASH => match &mut self.attached {
+                Some(Attached::Nand(nand)) => nand.transfer(mosi),
+                Some(Attached::DataFlash(dataflash)) => dataflash.transfer(mosi),
+                None => self.flash.transfer(mosi),
+            },
+            MODE_FPGA => {
+                self.flash.transfer(mosi);
+                self.fpga.transfer(self.cs_asserted, mosi)
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn spi_bytes(&mut self, data: &[u8]) -> Vec<u8> {
+        data.iter().map(|&b| self.spi_byte(b)).collect()
+    }
+
+    /// Send a complete flash command with CS asserted, returning the received data
+    fn flash_command(&mut self, data: &[u8]) -> Vec<u8> {
+        self.set_cs(true);
+        let rx = self.spi_bytes(data);
+        self.set_cs(false);
+        rx
+    }
+
+    /// Poll the flash status register until not busy, giving up after `timeout`
+    fn flash_wait_busy(&mut self, timeout: Duration) -> u8 {
+        let t0 = Instant::now();
+        loop {
+            let status = self.flash_command(&[command::READ_STATUS_1, 0])[1];
+            if status & 1 == 0 {
+                return 0;
+            } else if t0.elapsed() >= timeout {
+                return 1;
+            }
+            sleep(Duration::from_micros(100));
+        }
+    }
+
+    /// Execute a batch packet as the firmware does, stopping at the first
+    /// malformed operation.
+    fn process_batch(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut rx = Vec::new();
+        let mut mode = None;
+        let mut idx = 0;
+        while idx < data.len() {
+            let op = data[idx];
+            let args = &data[idx+1..];
+            idx += 1;
+            match op {
+                opcode::SELECT => self.set_cs(true),
+                opcode::UNSELECT => self.set_cs(false),
+                opcode::TRANSFER => {
+                    let len = match args.first() {
+                        Some(&len) if args.len() > len as usize => len as usize,
+                        _ => break,
+                    };
+                    rx.extend(self.spi_bytes(&args[1..1+len]));
+                    idx += 1 + len;
+                },
+                opcode::READ if args.len() >= 2 => {
+                    rx.extend(self.spi_bytes(&vec![args[1]; args[0] as usize]));
+                    idx += 2;
+                },
+                opcode::DELAY if args.len() >= 2 => {
+                    let us = u16::from_le_bytes([args[0], args[1]]) as u64;
+                    sleep(Duration::from_micros(us).mul_f64(self.time_scale));
+                    idx += 2;
+                },
+                opcode::SET_MODE => {
+                    // Applied after the reply, and ends the packet
+                    if args.first() == Some(&(MODE_FLASH as u8))
+                       || args.first() == Some(&(MODE_FPGA as u8))
+                    {
+                        mode = Some(args[0] as u16);
+                    }
+                    break;
+                },
+                opcode::PAGE_LOAD if args.len() >= 2 => {
+                    let (offset, len) = (args[0] as usize, args[1] as usize);
+                    if args.len() < 2 + len || offset + len > self.page_buf.len() {
+                        break;
+                    }
+                    self.page_buf[offset..offset+len].copy_from_slice(&args[2..2+len]);
+                    idx += 2 + len;
+                },
+                opcode::PAGE_PROGRAM if args.len() >= 4 => {
+                    let len = if args[3] == 0 { 256 } else { args[3] as usize };
+                    self.flash_command(&[command::WRITE_ENABLE]);
+                    let mut tx = vec![command::PAGE_PROGRAM, args[0], args[1], args[2]];
+                    tx.extend(&self.page_buf[..len]);
+                    self.flash_command(&tx);
+                    rx.push(self.flash_wait_busy(Self::PAGE_PROGRAM_TIMEOUT));
+                    idx += 4;
+                },
+                opcode::WAIT_BUSY => rx.push(self.flash_wait_busy(Self::WAIT_BUSY_TIMEOUT)),
+                opcode::CRC32 if args.len() >= 6 => {
+                    let len = u32::from_be_bytes([0, args[3], args[4], args[5]]) as usize;
+                    self.set_cs(true);
+                    self.spi_bytes(&[command::FAST_READ, args[0], args[1], args[2], 0]);
+                    let data = self.spi_bytes(&vec![0; len]);
+                    self.set_cs(false);
+                    rx.extend(&crc32(&data).to_le_bytes());
+                    idx += 6;
+                },
+                _ => break,
+            }
+        }
+        if let Some(mode) = mode {
+            self.mode = mode;
+            self.spi_read = (0, 0);
+        }
+        rx
+    }
+
+    /// Process the next packet waiting on EP1 OUT, if the previous reply
+    /// has been collected so the endpoint is armed
+    fn accept_packet(&mut self) -> Result<()> {
+        if self.ep1_tx.is_none() {
+            if let Some(packet) = self.ep1_rx.pop_front() {
+                let reply = self.process_packet(&packet)?;
+                self.ep1_tx = Some(reply);
+            }
+        }
+        Ok(())
+    }
+
+    /// Process a bulk packet received on EP1, returning the reply
+    fn process_packet(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.mode == MODE_HIGH_Z {
+            // The firmware stalls EP1 OUT in high impedance mode
+            Err(FFPError::USBError(rusb::Error::Pipe))?;
+        }
+        self.bulk_packets += 1;
+        if self.batch_framing {
+            Ok(self.process_batch(data))
+        } else {
+            Ok(self.spi_bytes(data))
+        }
+    }
+}
+
+/// Bulk transfer on the simulated EP1.
+///
+/// OUT packets are processed as soon as EP1 OUT is armed, which is once the
+/// reply to the previous packet has been collected by finishing an IN
+/// transfer. Until then they are queued, as the host controller would.
+pub(crate) enum SimTransfer {
+    Out,
+    In { sim: SimulatedFFP, length: usize },
+}
+
+impl Transfer for SimTransfer {
+    fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            SimTransfer::Out => Ok(Vec::new()),
+            SimTransfer::In { sim, length } => {
+                let mut sim = sim.lock();
+                let reply = match sim.ep1_tx.take() {
+                    Some(reply) => reply,
+                    None => Err(FFPError::USBError(rusb::Error::Timeout))?,
+                };
+                sim.accept_packet()?;
+                if reply.len() != length {
+                    Err(FFPError::NotEnoughData { expected: length, read: reply.len() })?;
+                }
+                Ok(reply)
+            },
+        }
+    }
+}
+
+impl Endpoints for &SimulatedFFP {
+    type Transfer = SimTransfer;
+
+    fn submit(&self, endpoint: u8, buffer: Vec<u8>) -> Result<SimTransfer> {
+        if endpoint & 0x80 != 0 {
+            return Ok(SimTransfer::In { sim: (*self).clone(), length: buffer.len() });
+        }
+        let mut sim = self.lock();
+        if sim.ep1_tx.is_none() && sim.ep1_rx.is_empty() {
+            sim.bulk_round_trips += 1;
+        }
+        sim.ep1_rx.push_back(buffer);
+        sim.accept_packet()?;
+        Ok(SimTransfer::Out)
+    }
+}
+
+/// In-process simulation of an FFP, for testing without hardware.
+///
+/// Models the FFP's CS, FPGA reset, mode, and power state, with an
+/// attached emulated W25Q-series SPI NOR flash, W25N-series SPI NAND, or
+/// AT45DB DataFlash, and iCE40 FPGA. Use `Programmer::from_transport` to create a `Programmer` using it.
+/// Clones share the same simulated hardware, so one may be given to the
+/// `Programmer` while another is used to inspect the simulated devices.
+#[derive(Clone)]
+pub struct SimulatedFFP {
+    simulation: Arc<Mutex<Simulation>>,
+}
+
+impl SimulatedFFP {
+    /// Capabilities of the current FFP firmware
+    const CAPABILITIES: u16 = 0b1111;
+
+    /// Bulk SPI data endpoints
+    const TX_EP: u8 = 0x01;
+    const RX_EP: u8 = 0x81;
+
+    /// Create a new `SimulatedFFP` attached to an erased flash of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self::with_flash(vec![0xFF; size])
+    }
+
+    /// Create a new `SimulatedFFP` attached to a flash with the given contents.
+    ///
+    /// The flash size must be a power of two between 64KB and 16MB.
+    pub fn with_flash(contents: Vec<u8>) -> Self {
+        assert!(contents.len().is_power_of_two()
+                && (1 << 16..=1 << 24).contains(&contents.len()),
+                "Flash size must be a power of two between 64KB and 16MB");
+        let simulation = Simulation {
+            flash: SpiFlash::new(contents),
+            attached: None,
+            fpga: Ice40::default(),
+            capabilities: Self::CAPABILITIES,
+            mode: MODE_HIGH_Z,
+            cs_asserted: false,
+            fpga_reset_asserted: false,
+            tpwr: false,
+            external_power: false,
+            led: false,
+            batch_framing: false,
+            spi_read: (0, 0),
+            page_buf: [0; 256],
+            time_scale: 1.0,
+            ep1_tx: None,
+            ep1_rx: VecDeque::new(),
+            bulk_packets: 0,
+            bulk_round_trips: 0,
+        };
+        Self { simulation: Arc::new(Mutex::new(simulation)) }
+    }
+
+    /// Create a new `SimulatedFFP` attached to an erased W25N01GV SPI NAND
+    /// instead of a SPI NOR flash, with the given factory bad blocks.
+    pub fn with_nand(bad_blocks: &[u32]) -> Self {
+        let sim = Self::new(1 << 16);
+        sim.lock().attached = Some(Attached::Nand(SpiNand::new(bad_blocks)));
+        sim
+    }
+
+    /// Create a new `SimulatedFFP` attached to an erased AT45DB041E DataFlash
+    /// instead of a SPI NOR flash, optionally configured for power-of-2 pages.
+    pub fn with_dataflash(binary_pages: bool) -> Self {
+        let sim = Self::new(1 << 16);
+        sim.lock().attached = Some(Attached::DataFlash(SpiDataFlash::new(binary_pages)));
+        sim
+    }
+
+    /// Set the firmware capabilities reported by the simulated FFP.
+    ///
+    /// Defaults to all capabilities; use 0 to simulate older firmware.
+    pub fn set_capabilities(&self, capabilities: u16) {
+        self.lock().capabilities = capabilities;
+    }
+
+    /// Scale all simulated delays, such as flash program and erase times,
+    /// by `scale`. Defaults to 1.0 for realistic timing, use 0.0 for instant.
+    pub fn set_time_scale(&self, scale: f64) {
+        let mut simulation = self.lock();
+        simulation.time_scale = scale;
+        simulation.flash.time_scale = scale;
+        if let Some(Attached::DataFlash(dataflash)) = &mut simulation.attached {
+            dataflash.time_scale = scale;
+        }
+    }
+
+    /// Set the JEDEC manufacturer ID reported by the simulated NOR flash.
+    ///
+    /// Defaults to Winbond (0xEF). Macronix (0xC2) changes the suspend
+    /// commands, and other manufacturers do not support suspend.
+    pub fn set_manufacturer_id(&self, manufacturer_id: u8) {
+        self.lock().flash.manufacturer_id = manufacturer_id;
+    }
+
+    /// Flip the bits in `mask` of the NOR flash byte at `address` after
+    /// each of the next `count` programs of its page, to simulate bit errors.
+    pub fn inject_program_errors(&self, address: u32, mask: u8, count: usize) {
+        self.lock().flash.program_errors.push((address as usize, mask, count));
+    }
+
+    /// Leave the simulated NOR flash in continuous-read (XIP) mode
+    pub fn set_flash_xip(&self) {
+        self.lock().flash.xip = true;
+    }
+
+    /// Leave the simulated NOR flash in QPI mode.
+    ///
+    /// It only leaves QPI mode on a software reset or its manufacturer's
+    /// QPI exit command, which is 0xFF for Winbond and 0xF5 for Macronix.
+    pub fn set_flash_qpi(&self) {
+        self.lock().flash.qpi = true;
+    }
+
+    /// Set whether the simulated target is powered by its own supply.
+    ///
+    /// The FFP senses target power when either this or its own
+    /// target power switch is on.
+    pub fn set_external_power(&self, on: bool) {
+        self.lock().external_power = on;
+    }
+
+    /// Get the current contents of the simulated flash
+    pub fn flash_contents(&self) -> Vec<u8> {
+        self.lock().flash.memory.clone()
+    }
+
+    /// Make all later programs and erases of NAND block `block` fail.
+    ///
+    /// Panics if the simulation was not created with `with_nand`.
+    pub fn set_nand_block_failing(&self, block: u32) {
+        match &mut self.lock().attached {
+            Some(Attached::Nand(nand)) => nand.failing.push(block),
+            _ => panic!("No simulated NAND"),
+        }
+    }
+
+    /// Get the contents of NAND page `page`, including its spare area.
+    ///
+    /// Panics if the simulation was not created with `with_nand`.
+    pub fn nand_page(&self, page: u32) -> Vec<u8> {
+        match &self.lock().attached {
+            Some(Attached::Nand(nand)) => nand.page(page),
+            _ => panic!("No simulated NAND"),
+        }
+    }
+
+    /// Get the contents of the simulated DataFlash, with every page at its
+    /// full 264 byte DataFlash size whichever page size is configured.
+    ///
+    /// Panics if the simulation was not created with `with_dataflash`.
+    pub fn dataflash_contents(&self) -> Vec<u8> {
+        match &self.lock().attached {
+            Some(Attached::DataFlash(dataflash)) => dataflash.memory.clone(),
+            _ => panic!("No simulated DataFlash"),
+        }
+    }
+
+    /// Check if the simulated FPGA has been configured
+    pub fn fpga_configured(&self) -> bool {
+        self.lock().fpga.configured
+    }
+
+    /// Get the last bitstream received by the simulated FPGA over slave SPI
+    pub fn fpga_bitstream(&self) -> Vec<u8> {
+        self.lock().fpga.bitstream.clone()
+    }
+
+    /// Check if target power is enabled
+    pub fn target_power(&self) -> bool {
+        self.lock().tpwr
+    }
+
+    /// Get the number of bulk packets the simulated FFP has accepted
+    pub fn bulk_packets(&self) -> usize {
+        self.lock().bulk_packets
+    }
+
+    /// Get the number of pipelined bulk packets sent while the simulated
+    /// FFP had no other packets waiting, each costing a USB round trip
+    pub fn bulk_round_trips(&self) -> usize {
+        self.lock().bulk_round_trips
+    }
+
+    /// Check if the FFP LED is on
+    pub fn led(&self) -> bool {
+        self.lock().led
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Simulation> {
+        // Keep the simulation usable after a panic so `Programmer` can still be dropped
+        self.simulation.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Transport for SimulatedFFP {
+    fn control_out(&self, request: u8, value: u16, index: u16) -> Result<()> {
+        let mut sim = self.lock();
+        match request {
+            request::SET_CS => sim.set_cs(value == 0),
+            request::SET_FPGA_RESET => {
+                let asserted = value == 0;
+                if asserted {
+                    sim.fpga.reset();
+                } else if sim.fpga_reset_asserted {
+                    let ss_asserted = sim.cs_asserted && sim.mode == MODE_FPGA;
+                    let sim = &mut *sim;
+                    sim.fpga.release(ss_asserted, &sim.flash.memory);
+                }
+                sim.fpga_reset_asserted = asserted;
+            },
+            request::SET_MODE if value <= MODE_FPGA => {
+                sim.mode = value;
+                sim.spi_read = (0, 0);
+            },
+            request::SET_TPWR => sim.tpwr = value != 0,
+            request::SET_LED => sim.led = value != 0,
+            request::BOOTLOAD => (),
+            request::SPI_READ => {
+                let [count_hi, fill] = index.to_le_bytes();
+                sim.spi_read = (value as usize | (count_hi as usize) << 16, fill);
+            },
+            request::SET_FRAMING => sim.batch_framing = value == 1,
+            _ => Err(FFPError::USBError(rusb::Error::Pipe))?,
+        }
+        Ok(())
+    }
+
+    fn control_in(&self, request: u8, _value: u16, _index: u16, length: usize) -> Result<Vec<u8>> {
+        let sim = self.lock();
+        let mut data = match request {
+            request::GET_CAPABILITIES => sim.capabilities.to_le_bytes().to_vec(),
+            request::GET_TPWR => vec![(sim.tpwr || sim.external_power) as u8, 0],
+            _ => Err(FFPError::USBError(rusb::Error::Pipe))?,
+        };
+        data.truncate(length);
+        Ok(data)
+    }
+
+    fn exchange(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // Use the same pipelining as for USB, against the flow-controlled EP1
+        Pipeline::new(self).write(Self::TX_EP, Self::RX_EP, data)
+    }
+
+    fn packet(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.lock().process_packet(data)
+    }
+
+    fn receive(&self, nbytes: usize) -> Result<Vec<u8>> {
+        let mut sim = self.lock();
+        let (remaining, fill) = sim.spi_read;
+        if sim.mode == MODE_HIGH_Z || nbytes > remaining {
+            Err(FFPError::USBError(rusb::Error::Timeout))?;
+        }
+        sim.spi_read = (remaining - nbytes, fill);
+        Ok(sim.spi_bytes(&vec![fill; nbytes]))
+    }
+
+    fn serial(&self) -> Result<String> {
+        Ok("simulated".to_string())
+    }
+}
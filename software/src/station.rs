@@ -0,0 +1,318 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use failure::ResultExt;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::{FFPError, Flash, Programmer, Result};
+
+/// Event which starts programming each board
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    /// An FFP is attached over USB
+    #[default]
+    Attach,
+    /// The FFP senses power on the target's power rail
+    TargetPower,
+}
+
+/// How a station checks the programmed flash
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyPolicy {
+    /// Do not check the programmed data
+    None,
+    /// Check as `Flash::program` does, using on-device CRC-32 when supported
+    #[default]
+    Crc,
+    /// Also read back every image in full and compare it on the host
+    Readback,
+}
+
+/// An image file to program at an offset into the flash
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Image {
+    pub file: PathBuf,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+/// Target power sequencing around programming each board
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerSequence {
+    /// Turn on the FFP's target power switch before programming
+    pub enable: bool,
+    /// Milliseconds to wait for target power to settle before programming
+    pub settle_ms: u64,
+    /// Turn off the FFP's target power switch after programming
+    pub off_after: bool,
+}
+
+/// A production programming job, read from a JSON job file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// Flash layout to program to each board, with no two images sharing a 4KB sector
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub verify: VerifyPolicy,
+    /// Times to retry sectors which fail verification
+    #[serde(default)]
+    pub retries: usize,
+    #[serde(default)]
+    pub power: PowerSequence,
+    #[serde(default)]
+    pub trigger: Trigger,
+    /// Results log, written as CSV if it ends in `.csv` and JSON lines otherwise
+    pub log: PathBuf,
+}
+
+impl Job {
+    /// Read a job file from `path`.
+    ///
+    /// Relative image and log paths are relative to the job file.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path).context("Error opening job file")?;
+        let mut job: Job = serde_json::from_reader(file).context("Error reading job file")?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for image in job.images.iter_mut() {
+            image.file = base.join(&image.file);
+        }
+        job.log = base.join(&job.log);
+        Ok(job)
+    }
+}
+
+/// Outcome of programming one board
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// Result of programming one board, as written to the station log
+#[derive(Clone, Debug, Serialize)]
+pub struct StationRecord {
+    /// Seconds since the Unix epoch when programming started
+    pub time: u64,
+    pub ffp_serial: String,
+    /// Flash JEDEC ID as hex, if it could be read
+    pub jedec_id: Option<String>,
+    /// Flash unique ID as hex, if the flash has one and it could be read
+    pub unique_id: Option<String>,
+    /// SHA-256 of all image files, in job order
+    pub image_sha256: String,
+    /// Seconds taken to program the board
+    pub duration: f64,
+    pub result: Outcome,
+    /// Description of the error, if the board failed
+    pub error: Option<String>,
+}
+
+impl StationRecord {
+    const CSV_HEADER: &'static str =
+        "time,ffp_serial,jedec_id,unique_id,image_sha256,duration,result,error";
+
+    fn to_csv(&self) -> String {
+        let quote = |s: &str| {
+            if s.contains([',', '"', '\n']) {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.to_string()
+            }
+        };
+        let optional = |s: &Option<String>| quote(s.as_deref().unwrap_or(""));
+        format!("{},{},{},{},{},{:.2},{},{}",
+                self.time, quote(&self.ffp_serial), optional(&self.jedec_id),
+                optional(&self.unique_id), self.image_sha256, self.duration, self.result,
+                optional(&self.error))
+    }
+}
+
+/// Production programming station.
+///
+/// Programs each board with a job's images, appending a `StationRecord`
+/// for every board to the job's log. Waiting for boards is left to the
+/// caller, using `Watcher` or `wait_for_target_power` depending on the
+/// job's `Trigger`.
+pub struct Station {
+    job: Job,
+    images: Vec<(u32, Vec<u8>)>,
+    image_sha256: String,
+    log: LineWriter<std::fs::File>,
+    csv: bool,
+}
+
+impl Station {
+    /// Interval between target power checks
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Load the images for `job` and open its log for appending
+    pub fn new(job: Job) -> Result<Self> {
+        if job.images.is_empty() {
+            Err(FFPError::InvalidJob { reason: "no images to program".to_string() })?;
+        }
+        if job.trigger == Trigger::TargetPower && job.power.enable && !job.power.off_after {
+            Err(FFPError::InvalidJob {
+                reason: "target power trigger needs power.off_after when power.enable is set"
+                    .to_string() })?;
+        }
+
+        let mut images = Vec::new();
+        let mut hasher = Sha256::new();
+        for image in job.images.iter() {
+            let data = std::fs::read(&image.file)
+                .context(format!("Error reading image {}", image.file.display()))?;
+            hasher.update(&data);
+            images.push((image.offset, data));
+        }
+        let image_sha256 = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        Self::check_layout(&job, &images)?;
+
+        let csv = job.log.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+        let file = OpenOptions::new().create(true).append(true).open(&job.log)
+            .context("Error opening station log")?;
+        let empty = file.metadata().context("Error opening station log")?.len() == 0;
+        let mut log = LineWriter::new(file);
+        if csv && empty {
+            writeln!(log, "{}", StationRecord::CSV_HEADER).context("Error writing station log")?;
+        }
+        Ok(Self { job, images, image_sha256, log, csv })
+    }
+
+    /// Check no two images overlap or share a flash sector.
+    ///
+    /// Each image is programmed by erasing the whole sectors it covers, so
+    /// a later image would erase the end of an earlier one in a shared sector.
+    fn check_layout(job: &Job, images: &[(u32, Vec<u8>)]) -> Result<()> {
+        let sector = Flash::SECTOR_SIZE as u64;
+        let mut spans: Vec<(u64, u64, &Path)> = images.iter().zip(job.images.iter())
+            .filter(|((_, data), _)| !data.is_empty())
+            .map(|((offset, data), image)| {
+                let start = *offset as u64 / sector * sector;
+                let end = (*offset as u64 + data.len() as u64).div_ceil(sector) * sector;
+                (start, end, image.file.as_path())
+            }).collect();
+        spans.sort();
+        for pair in spans.windows(2) {
+            let ((_, end, first), (start, _, second)) = (pair[0], pair[1]);
+            if start < end {
+                Err(FFPError::InvalidJob { reason: format!(
+                    "images {} and {} overlap or share a 4KB sector",
+                    first.display(), second.display()) })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the job this station is running
+    pub fn job(&self) -> &Job {
+        &self.job
+    }
+
+    /// Program the board attached to `programmer`, whose FFP has serial
+    /// number `serial`, and append the result to the log.
+    ///
+    /// Failing to program the board is reported in the returned record;
+    /// only errors writing the log are returned as errors.
+    pub fn program(&mut self, programmer: &Programmer, serial: &str) -> Result<StationRecord> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let t0 = Instant::now();
+        let mut ids = (None, None);
+        let mut result = self.run(programmer, &mut ids);
+        if self.job.power.off_after {
+            result = result.and(programmer.power_off());
+        }
+
+        let (jedec_id, unique_id) = ids;
+        let record = StationRecord {
+            time,
+            ffp_serial: serial.to_string(),
+            jedec_id,
+            unique_id,
+            image_sha256: self.image_sha256.clone(),
+            duration: t0.elapsed().as_secs_f64(),
+            result: if result.is_ok() { Outcome::Pass } else { Outcome::Fail },
+            error: result.err().map(|e| {
+                e.iter_chain().map(|c| c.to_string()).collect::<Vec<_>>().join(": ")
+            }),
+        };
+        self.write(&record)?;
+        Ok(record)
+    }
+
+    /// Wait until the FFP senses target power is `present`, or `interrupt` is set.
+    ///
+    /// Returns false if interrupted.
+    pub fn wait_for_target_power(programmer: &Programmer, present: bool,
+                                 interrupt: &AtomicBool) -> Result<bool>
+    {
+        while !interrupt.load(Ordering::SeqCst) {
+            if programmer.target_powered()? == present {
+                return Ok(true);
+            }
+            sleep(Self::POLL_INTERVAL);
+        }
+        Ok(false)
+    }
+
+    /// Power up, program, and verify the flash, recording its IDs in `ids`
+    /// as soon as they are read so they are logged even if programming fails.
+    fn run(&self, programmer: &Programmer, ids: &mut (Option<String>, Option<String>))
+        -> Result<()>
+    {
+        let hex = |data: &[u8]| data.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+        if self.job.power.enable {
+            programmer.power_on()?;
+        }
+        sleep(Duration::from_millis(self.job.power.settle_ms));
+
+        let mut flash = Flash::new(programmer);
+        flash.set_retries(self.job.retries);
+        let id = flash.read_id()?;
+        ids.0 = Some(hex(&flash.jedec_id()?));
+        ids.1 = id.unique_id().map(hex);
+
+        let verify = self.job.verify != VerifyPolicy::None;
+        for (offset, data) in self.images.iter() {
+            flash.program(*offset, data, verify)?;
+        }
+        if self.job.verify == VerifyPolicy::Readback {
+            let count = self.images.iter()
+                .map(|(offset, data)| flash.read(*offset, data.len()).map(|rx| rx != *data))
+                .collect::<Result<Vec<bool>>>()?
+                .iter().filter(|&&mismatch| mismatch).count();
+            if count > 0 {
+                Err(FFPError::VerifyFailed { count })?;
+            }
+        }
+        programmer.unreset()
+    }
+
+    fn write(&mut self, record: &StationRecord) -> Result<()> {
+        if self.csv {
+            writeln!(self.log, "{}", record.to_csv()).context("Error writing station log")?;
+        } else {
+            serde_json::to_writer(&mut self.log, record).context("Error writing station log")?;
+            writeln!(self.log).context("Error writing station log")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::{Flash, Result};
+
+/// A single sector of flash held in memory
+struct Sector {
+    address: u32,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+/// `std::io` adapter over the attached flash.
+///
+/// Implements `Read`, `Write`, and `Seek` over the entire flash, buffering
+/// one sector at a time. Writing to a sector erases and re-programs it when
+/// the stream moves to a different sector, on `flush`, or when dropped,
+/// so data elsewhere in the sector is preserved.
+pub struct FlashStream<'a> {
+    flash: Flash<'a>,
+    size: u64,
+    position: u64,
+    sector: Option<Sector>,
+}
+
+impl<'a> FlashStream<'a> {
+    const SECTOR_SIZE: u64 = 4096;
+
+    /// Create a new `FlashStream` over `flash`, starting at address 0.
+    ///
+    /// The stream length is the flash capacity read from its JEDEC ID.
+    pub fn new(flash: Flash<'a>) -> Result<Self> {
+        let size = flash.capacity()? as u64;
+        Ok(Self { flash, size, position: 0, sector: None })
+    }
+
+    /// Ensure the sector containing the current position is buffered,
+    /// writing out any other modified sector first.
+    fn load_sector(&mut self) -> Result<&mut Sector> {
+        let address = (self.position - self.position % Self::SECTOR_SIZE) as u32;
+        match self.sector {
+            Some(ref sector) if sector.address == address => (),
+            _ => {
+                self.write_sector()?;
+                let data = self.flash.read(address, Self::SECTOR_SIZE as usize)?;
+                self.sector = Some(Sector { address, data, dirty: false });
+            },
+        }
+        Ok(self.sector.as_mut().unwrap())
+    }
+
+    /// Erase and program the buffered sector if it has been modified
+    fn write_sector(&mut self) -> Result<()> {
+        if let Some(sector) = self.sector.as_mut() {
+            if sector.dirty {
+                self.flash.program(sector.address, &sector.data, true)?;
+                sector.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `copy` with the buffered sector, offset, and number of bytes to
+    /// transfer at the current position, stopping at the end of the sector
+    /// or flash, then advance the position and return the number of bytes.
+    fn transfer(&mut self, len: usize, copy: impl FnOnce(&mut Sector, usize, usize)) -> Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let offset = (self.position % Self::SECTOR_SIZE) as usize;
+        let n = usize::min(len, Self::SECTOR_SIZE as usize - offset);
+        let n = usize::min(n, (self.size - self.position) as usize);
+        copy(self.load_sector()?, offset, n);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+fn to_io_error(error: failure::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+impl<'a> Read for FlashStream<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transfer(buf.len(), |sector, offset, n| {
+            buf[..n].copy_from_slice(&sector.data[offset..offset+n]);
+        }).map_err(to_io_error)
+    }
+}
+
+impl<'a> Write for FlashStream<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transfer(buf.len(), |sector, offset, n| {
+            if sector.data[offset..offset+n] != buf[..n] {
+                sector.data[offset..offset+n].copy_from_slice(&buf[..n]);
+                sector.dirty = true;
+            }
+        }).map_err(to_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_sector().map_err(to_io_error)
+    }
+}
+
+impl<'a> Seek for FlashStream<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+impl<'a> Drop for FlashStream<'a> {
+    fn drop(&mut self) {
+        let _ = self.write_sector();
+    }
+}
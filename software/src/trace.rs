@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use failure::ResultExt;
+use serde::{Serialize, Deserialize};
+use crate::{FFPError, Result, Transport};
+
+/// A single transaction with the FFP, as recorded in a trace file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    ControlOut { request: u8, value: u16, index: u16 },
+    ControlIn { request: u8, value: u16, index: u16, length: usize },
+    Exchange { #[serde(with = "hex")] tx: Vec<u8> },
+    Packet { #[serde(with = "hex")] tx: Vec<u8> },
+    Receive { nbytes: usize },
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only show the start of long transfers
+        let data = |data: &[u8]| {
+            let s = hex::encode(&data[..usize::min(data.len(), 16)]);
+            if data.len() > 16 { s + "..." } else { s }
+        };
+        match self {
+            Request::ControlOut { request, value, index } =>
+                write!(f, "control out request {} value {} index {}", request, value, index),
+            Request::ControlIn { request, value, index, length } =>
+                write!(f, "control in request {} value {} index {} length {}",
+                       request, value, index, length),
+            Request::Exchange { tx } =>
+                write!(f, "exchange of {} bytes {}", tx.len(), data(tx)),
+            Request::Packet { tx } =>
+                write!(f, "packet of {} bytes {}", tx.len(), data(tx)),
+            Request::Receive { nbytes } =>
+                write!(f, "receive of {} bytes", nbytes),
+        }
+    }
+}
+
+/// One line of a trace file: a request, when it was made, and its outcome
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// Seconds since the trace started
+    time: f64,
+    #[serde(flatten)]
+    request: Request,
+    #[serde(with = "hex", default, skip_serializing_if = "Vec::is_empty")]
+    rx: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Serialise byte vectors as hex strings, keeping trace files readable
+mod hex {
+    use serde::{Serializer, Deserializer, Deserialize, de::Error};
+
+    pub fn encode(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(D::Error::custom("invalid hex digit"));
+        }
+        if !s.len().is_multiple_of(2) {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i+2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// `Transport` which records every transaction made over another
+/// `Transport` to a trace file, one JSON object per line.
+///
+/// The trace file is written as each transaction completes, so it is
+/// complete up to the point of failure even if the program crashes.
+/// Failing to write the trace file does not affect the transactions,
+/// and is passed once to the handler set by `on_write_error`. Traces can be
+/// played back using `ReplayTransport`.
+pub struct TracingTransport {
+    inner: Box<dyn Transport>,
+    file: Mutex<LineWriter<File>>,
+    start: Instant,
+    write_failed: AtomicBool,
+    on_write_error: Option<WriteErrorHandler>,
+}
+
+/// Handler called with the first error writing a trace file
+pub type WriteErrorHandler = Box<dyn Fn(&std::io::Error) + Send>;
+
+impl TracingTransport {
+    /// Create a new `TracingTransport` recording transactions over `inner` to `file`
+    pub fn new(inner: Box<dyn Transport>, file: File) -> Self {
+        Self {
+            inner, file: Mutex::new(LineWriter::new(file)), start: Instant::now(),
+            write_failed: AtomicBool::new(false), on_write_error: None,
+        }
+    }
+
+    /// Call `handler` with the first error writing the trace file, after
+    /// which the trace is incomplete
+    pub fn on_write_error(mut self, handler: WriteErrorHandler) -> Self {
+        self.on_write_error = Some(handler);
+        self
+    }
+
+    /// Write a record of `request` and its `result` to the trace file
+    fn record<T: AsRef<[u8]>>(&self, request: Request, result: Result<T>) -> Result<T> {
+        let time = self.start.elapsed().as_secs_f64();
+        let (rx, error) = match &result {
+            Ok(rx) => (rx.as_ref().to_vec(), None),
+            Err(e) => {
+                let chain: Vec<String> = e.iter_chain().map(|c| c.to_string()).collect();
+                (Vec::new(), Some(chain.join(": ")))
+            },
+        };
+        let record = Record { time, request, rx, error };
+        if let Err(e) = self.write(&record) {
+            if !self.write_failed.swap(true, Ordering::SeqCst) {
+                if let Some(handler) = &self.on_write_error {
+                    handler(&e);
+                }
+            }
+        }
+        result
+    }
+
+    fn write(&self, record: &Record) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        serde_json::to_writer(&mut *file, record)?;
+        writeln!(file)
+    }
+}
+
+impl Transport for TracingTransport {
+    fn control_out(&self, request: u8, value: u16, index: u16) -> Result<()> {
+        let result = self.inner.control_out(request, value, index).map(|_| []);
+        self.record(Request::ControlOut { request, value, index }, result).map(|_| ())
+    }
+
+    fn control_in(&self, request: u8, value: u16, index: u16, length: usize) -> Result<Vec<u8>> {
+        let result = self.inner.control_in(request, value, index, length);
+        self.record(Request::ControlIn { request, value, index, length }, result)
+    }
+
+    fn exchange(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let result = self.inner.exchange(data);
+        self.record(Request::Exchange { tx: data.to_vec() }, result)
+    }
+
+    fn packet(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let result = self.inner.packet(data);
+        self.record(Request::Packet { tx: data.to_vec() }, result)
+    }
+
+    fn receive(&self, nbytes: usize) -> Result<Vec<u8>> {
+        let result = self.inner.receive(nbytes);
+        self.record(Request::Receive { nbytes }, result)
+    }
+
+    fn serial(&self) -> Result<String> {
+        self.inner.serial()
+    }
+}
+
+/// `Transport` which plays back a trace file recorded by `TracingTransport`.
+///
+/// Each transaction must match the next one in the trace, and receives
+/// the data or error that was originally recorded. Transactions are
+/// replayed immediately, ignoring the recorded timestamps.
+pub struct ReplayTransport {
+    records: Mutex<VecDeque<Record>>,
+    count: usize,
+}
+
+impl ReplayTransport {
+    /// Load the trace file at `path` for playback
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).context("Error opening trace file")?;
+        let mut records = VecDeque::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.context("Error reading trace file")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push_back(serde_json::from_str(&line).context("Error parsing trace file")?);
+        }
+        let count = records.len();
+        Ok(Self { records: Mutex::new(records), count })
+    }
+
+    /// Check `request` matches the next recorded request, returning its recorded outcome
+    fn replay(&self, request: Request) -> Result<Vec<u8>> {
+        let mut records = self.records.lock().unwrap();
+        let number = self.count - records.len() + 1;
+        let record = match records.pop_front() {
+            Some(record) => record,
+            None => Err(FFPError::ReplayFinished { request: request.to_string() })?,
+        };
+        if record.request != request {
+            Err(FFPError::ReplayMismatch {
+                record: number, expected: record.request.to_string(), request: request.to_string()
+            })?;
+        }
+        match record.error {
+            Some(error) => Err(FFPError::ReplayedError { error })?,
+            None => Ok(record.rx),
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn control_out(&self, request: u8, value: u16, index: u16) -> Result<()> {
+        self.replay(Request::ControlOut { request, value, index }).map(|_| ())
+    }
+
+    fn control_in(&self, request: u8, value: u16, index: u16, length: usize) -> Result<Vec<u8>> {
+        self.replay(Request::ControlIn { request, value, index, length })
+    }
+
+    fn exchange(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.replay(Request::Exchange { tx: data.to_vec() })
+    }
+
+    fn packet(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.replay(Request::Packet { tx: data.to_vec() })
+    }
+
+    fn receive(&self, nbytes: usize) -> Result<Vec<u8>> {
+        self.replay(Request::Receive { nbytes })
+    }
+
+    fn serial(&self) -> Result<String> {
+        Ok("replay".to_string())
+    }
+}
+
+/// Placeholder `Transport` for a `Programmer` whose transport is being replaced
+pub(crate) struct Detached;
+
+impl Transport for Detached {
+    fn control_out(&self, _request: u8, _value: u16, _index: u16) -> Result<()> {
+        Err(FFPError::USBError(rusb::Error::NoDevice))?
+    }
+
+    fn control_in(&self, _request: u8, _value: u16, _index: u16, _length: usize) -> Result<Vec<u8>> {
+        Err(FFPError::USBError(rusb::Error::NoDevice))?
+    }
+
+    fn exchange(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(FFPError::USBError(rusb::Error::NoDevice))?
+    }
+
+    fn packet(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(FFPError::USBError(rusb::Error::NoDevice))?
+    }
+
+    fn receive(&self, _nbytes: usize) -> Result<Vec<u8>> {
+        Err(FFPError::USBError(rusb::Error::NoDevice))?
+    }
+
+    fn serial(&self) -> Result<String> {
+        Err(FFPError::USBError(rusb::Error::NoDevice))?
+    }
+}
@@ -0,0 +1,281 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::os::raw::{c_int, c_void};
+use std::time::Duration;
+use libusb1_sys as ffi;
+use libusb1_sys::constants::*;
+use rusb::UsbContext;
+use failure::ResultExt;
+use crate::{FFPError, Result};
+
+/// A single bulk transfer submitted using libusb's asynchronous API.
+///
+/// The transfer buffer and completion flag are heap allocated so they do
+/// not move while libusb holds pointers to them. Dropping a transfer which
+/// has not completed cancels it and waits for libusb to finish with it.
+pub(crate) struct AsyncTransfer {
+    context: *mut ffi::libusb_context,
+    transfer: *mut ffi::libusb_transfer,
+    buffer: Vec<u8>,
+    completed: Box<Cell<c_int>>,
+}
+
+extern "system" fn transfer_callback(transfer: *mut ffi::libusb_transfer) {
+    unsafe {
+        let completed = (*transfer).user_data as *const Cell<c_int>;
+        (*completed).set(1);
+    }
+}
+
+/// Convert a libusb error code into a `rusb::Error`
+pub(crate) fn error_from_code(code: c_int) -> rusb::Error {
+    match code {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+/// Convert a completed transfer's status into a `rusb::Error`
+fn error_from_status(status: c_int) -> rusb::Error {
+    match status {
+        LIBUSB_TRANSFER_TIMED_OUT => rusb::Error::Timeout,
+        LIBUSB_TRANSFER_STALL => rusb::Error::Pipe,
+        LIBUSB_TRANSFER_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_TRANSFER_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_TRANSFER_CANCELLED => rusb::Error::Interrupted,
+        _ => rusb::Error::Io,
+    }
+}
+
+impl AsyncTransfer {
+    /// Submit a bulk transfer of `buffer` on `endpoint`.
+    ///
+    /// For IN endpoints, `buffer` is filled with the received data.
+    fn submit(handle: &rusb::DeviceHandle<rusb::Context>, context: &rusb::Context,
+              endpoint: u8, buffer: Vec<u8>, timeout: Duration) -> Result<Self>
+    {
+        let transfer = unsafe { ffi::libusb_alloc_transfer(0) };
+        if transfer.is_null() {
+            Err(FFPError::USBError(rusb::Error::NoMem))?;
+        }
+        let mut async_transfer = AsyncTransfer {
+            context: context.as_raw(),
+            transfer,
+            buffer,
+            completed: Box::new(Cell::new(0)),
+        };
+        unsafe {
+            let t = &mut *transfer;
+            t.dev_handle = handle.as_raw();
+            t.endpoint = endpoint;
+            t.transfer_type = LIBUSB_TRANSFER_TYPE_BULK;
+            t.timeout = timeout.as_millis() as u32;
+            t.buffer = async_transfer.buffer.as_mut_ptr();
+            t.length = async_transfer.buffer.len() as c_int;
+            t.user_data = async_transfer.completed.as_ptr() as *mut c_void;
+            t.callback = transfer_callback;
+            let rc = ffi::libusb_submit_transfer(transfer);
+            if rc < 0 {
+                // Never submitted, so there is nothing to cancel when dropped
+                async_transfer.completed.set(1);
+                Err(FFPError::USBError(error_from_code(rc)))?;
+            }
+        }
+        Ok(async_transfer)
+    }
+
+    /// Handle libusb events until this transfer has completed
+    fn wait(&self) -> Result<()> {
+        while self.completed.get() == 0 {
+            let tv = libc::timeval { tv_sec: 1, tv_usec: 0 };
+            let rc = unsafe {
+                ffi::libusb_handle_events_timeout_completed(
+                    self.context, &tv, self.completed.as_ptr())
+            };
+            if rc < 0 && rc != LIBUSB_ERROR_INTERRUPTED {
+                Err(FFPError::USBError(error_from_code(rc)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for this transfer to complete, then check it transferred every byte
+    /// and return the buffer.
+    fn finish(mut self) -> Result<Vec<u8>> {
+        self.wait()?;
+        let (status, actual) = unsafe { ((*self.transfer).status, (*self.transfer).actual_length) };
+        if status != LIBUSB_TRANSFER_COMPLETED {
+            Err(FFPError::USBError(error_from_status(status)))?;
+        }
+        if actual as usize != self.buffer.len() {
+            Err(FFPError::NotEnoughData { expected: self.buffer.len(), read: actual as usize })?;
+        }
+        Ok(std::mem::take(&mut self.buffer))
+    }
+}
+
+impl Drop for AsyncTransfer {
+    fn drop(&mut self) {
+        unsafe {
+            if self.completed.get() == 0 {
+                ffi::libusb_cancel_transfer(self.transfer);
+                // The buffer must outlive the transfer, so keep handling
+                // events even if libusb reports an error.
+                while self.completed.get() == 0 {
+                    let tv = libc::timeval { tv_sec: 1, tv_usec: 0 };
+                    ffi::libusb_handle_events_timeout_completed(
+                        self.context, &tv, self.completed.as_ptr());
+                }
+            }
+            ffi::libusb_free_transfer(self.transfer);
+        }
+    }
+}
+
+/// Bulk endpoints to which transfers can be submitted, completing in the
+/// order submitted on each endpoint
+pub(crate) trait Endpoints {
+    type Transfer: Transfer;
+
+    /// Submit a bulk transfer of `buffer` on `endpoint`.
+    ///
+    /// For IN endpoints, `buffer` is filled with the received data.
+    fn submit(&self, endpoint: u8, buffer: Vec<u8>) -> Result<Self::Transfer>;
+}
+
+/// A submitted bulk transfer, which is cancelled if dropped before finishing
+pub(crate) trait Transfer {
+    /// Wait for the transfer to complete, then check it transferred every
+    /// byte and return the buffer.
+    fn finish(self) -> Result<Vec<u8>>;
+}
+
+impl Transfer for AsyncTransfer {
+    fn finish(self) -> Result<Vec<u8>> {
+        AsyncTransfer::finish(self)
+    }
+}
+
+/// Endpoints of an FFP attached over USB, using libusb's asynchronous API
+pub(crate) struct UsbEndpoints<'a> {
+    handle: &'a rusb::DeviceHandle<rusb::Context>,
+    context: &'a rusb::Context,
+    timeout: Duration,
+}
+
+impl<'a> UsbEndpoints<'a> {
+    pub fn new(handle: &'a rusb::DeviceHandle<rusb::Context>, context: &'a rusb::Context,
+               timeout: Duration) -> Self
+    {
+        Self { handle, context, timeout }
+    }
+}
+
+impl<'a> Endpoints for UsbEndpoints<'a> {
+    type Transfer = AsyncTransfer;
+
+    fn submit(&self, endpoint: u8, buffer: Vec<u8>) -> Result<AsyncTransfer> {
+        AsyncTransfer::submit(self.handle, self.context, endpoint, buffer, self.timeout)
+    }
+}
+
+/// Keeps several bulk transfers in flight at once, so data is exchanged
+/// with the FFP without waiting a USB round trip for each packet.
+pub(crate) struct Pipeline<E: Endpoints> {
+    endpoints: E,
+}
+
+impl<E: Endpoints> Pipeline<E> {
+    /// Number of bytes in each bulk IN transfer when only reading
+    const TRANSFER_SIZE: usize = 1024;
+
+    /// Number of bytes in each bulk packet, which the FFP replies to individually
+    const PACKET_SIZE: usize = 64;
+
+    /// Maximum number of transfers in flight in each direction
+    const DEPTH: usize = 8;
+
+    pub fn new(endpoints: E) -> Self {
+        Self { endpoints }
+    }
+
+    /// Write `data` to `tx_ep`, returning the same number of bytes read
+    /// from `rx_ep`.
+    ///
+    /// Up to `DEPTH` OUT packets and their IN replies are kept in flight.
+    /// The FFP only accepts each OUT packet once the reply to the previous
+    /// one has been collected, so queued packets are held off by the USB
+    /// flow control rather than overwriting replies. If any transfer fails,
+    /// all transfers still in flight are cancelled before returning.
+    pub fn write(&self, tx_ep: u8, rx_ep: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut tx_chunks = data.chunks(Self::PACKET_SIZE);
+        let mut rx_chunks = data.chunks(Self::PACKET_SIZE);
+        let mut tx_in_flight = VecDeque::new();
+        let mut rx_in_flight = VecDeque::new();
+        let mut rx = Vec::with_capacity(data.len());
+        loop {
+            // Submit IN transfers first, so each reply is collected as soon as it is ready
+            while rx_in_flight.len() < Self::DEPTH {
+                let rx_chunk = match rx_chunks.next() {
+                    Some(rx_chunk) => rx_chunk,
+                    None => break,
+                };
+                rx_in_flight.push_back(self.submit(rx_ep, vec![0u8; rx_chunk.len()])
+                                           .context("Error reading data")?);
+            }
+            while tx_in_flight.len() < Self::DEPTH {
+                let tx_chunk = match tx_chunks.next() {
+                    Some(tx_chunk) => tx_chunk,
+                    None => break,
+                };
+                tx_in_flight.push_back(self.submit(tx_ep, tx_chunk.to_vec())
+                                           .context("Error writing data")?);
+            }
+            match (tx_in_flight.pop_front(), rx_in_flight.pop_front()) {
+                (Some(tx_transfer), Some(rx_transfer)) => {
+                    tx_transfer.finish().context("Error writing data")?;
+                    rx.extend(rx_transfer.finish().context("Error reading data")?);
+                },
+                _ => break,
+            }
+        }
+        Ok(rx)
+    }
+
+    /// Read `nbytes` from `rx_ep`.
+    ///
+    /// Several IN transfers are kept in flight and completed in order. If
+    /// any transfer fails, all transfers still in flight are cancelled.
+    pub fn read(&self, rx_ep: u8, nbytes: usize) -> Result<Vec<u8>> {
+        let mut remaining = nbytes;
+        let mut in_flight = VecDeque::new();
+        let mut rx = Vec::with_capacity(nbytes);
+        loop {
+            while in_flight.len() < Self::DEPTH && remaining > 0 {
+                let n = usize::min(remaining, Self::TRANSFER_SIZE);
+                in_flight.push_back(self.submit(rx_ep, vec![0u8; n]).context("Error reading data")?);
+                remaining -= n;
+            }
+            match in_flight.pop_front() {
+                Some(transfer) => rx.extend(transfer.finish().context("Error reading data")?),
+                None => break,
+            }
+        }
+        Ok(rx)
+    }
+
+    fn submit(&self, endpoint: u8, buffer: Vec<u8>) -> Result<E::Transfer> {
+        self.endpoints.submit(endpoint, buffer)
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+use failure::ResultExt;
+use crate::{FFPError, Programmer, Result};
+use crate::transfer::{Pipeline, UsbEndpoints};
+
+/// Connection to an FFP, carrying its vendor control requests and SPI bulk data.
+///
+/// `Programmer` uses a `Transport` for all communication with the FFP, so it
+/// may be backed either by real hardware over USB or by a `SimulatedFFP`.
+pub trait Transport: Send {
+    /// Send vendor control request `request` with `value` and `index` and no data
+    fn control_out(&self, request: u8, value: u16, index: u16) -> Result<()>;
+
+    /// Send vendor control request `request` with `value` and `index`,
+    /// returning up to `length` bytes of data from the FFP
+    fn control_in(&self, request: u8, value: u16, index: u16, length: usize) -> Result<Vec<u8>>;
+
+    /// Send `data` to the bulk endpoint in raw framing, returning the same
+    /// number of bytes received over SPI
+    fn exchange(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Send a single bulk packet of at most 64 bytes and return the FFP's reply packet
+    fn packet(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Receive `nbytes` from the bulk endpoint, as streamed by an SPI read request
+    fn receive(&self, nbytes: usize) -> Result<Vec<u8>>;
+
+    /// Read the FFP's serial number
+    fn serial(&self) -> Result<String> {
+        Err(FFPError::USBError(rusb::Error::NotSupported))?
+    }
+}
+
+/// `Transport` to real FFP hardware over USB.
+///
+/// Bulk transfers are pipelined when the `rusb::Context` the device was
+/// opened from is known, and otherwise made one packet at a time.
+pub(crate) struct UsbTransport {
+    context: Option<rusb::Context>,
+    handle: rusb::DeviceHandle<rusb::Context>,
+}
+
+impl UsbTransport {
+    const REQUEST_TYPE_SET: u8  = 2 << 5;
+    const REQUEST_TYPE_GET: u8  = (1 << 7) | (2 << 5);
+    const TX_EP: u8             = 0x01;
+    const RX_EP: u8             = 0x81;
+    const PACKET_SIZE: usize    = 64;
+    const CONTROL_TIMEOUT: Duration = Duration::from_millis(100);
+    const TRANSFER_TIMEOUT: Duration = Duration::from_millis(1000);
+    const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+    pub fn new(context: Option<&rusb::Context>, handle: rusb::DeviceHandle<rusb::Context>) -> Self {
+        Self { context: context.cloned(), handle }
+    }
+
+    /// Pipelined bulk transfers to and from the FFP, if the context is known
+    fn pipeline(&self) -> Option<Pipeline<UsbEndpoints<'_>>> {
+        self.context.as_ref().map(|context| {
+            Pipeline::new(UsbEndpoints::new(&self.handle, context, Self::TRANSFER_TIMEOUT))
+        })
+    }
+}
+
+impl Transport for UsbTransport {
+    fn control_out(&self, request: u8, value: u16, index: u16) -> Result<()> {
+        self.handle.write_control(
+            Self::REQUEST_TYPE_SET, request, value, index, &[], Self::CONTROL_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn control_in(&self, request: u8, value: u16, index: u16, length: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; length];
+        let n = self.handle.read_control(
+            Self::REQUEST_TYPE_GET, request, value, index, &mut buf, Self::CONTROL_TIMEOUT)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn exchange(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if let Some(pipeline) = self.pipeline() {
+            return pipeline.write(Self::TX_EP, Self::RX_EP, data);
+        }
+        let mut rx = Vec::with_capacity(data.len());
+        for chunk in data.chunks(Self::PACKET_SIZE) {
+            let rx_chunk = self.packet(chunk)?;
+            if rx_chunk.len() != chunk.len() {
+                Err(FFPError::NotEnoughData { expected: chunk.len(), read: rx_chunk.len() })?;
+            }
+            rx.extend(rx_chunk);
+        }
+        Ok(rx)
+    }
+
+    fn packet(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // Some batch operations such as CRC32 can keep the FFP busy for a while
+        let mut rx = [0u8; Self::PACKET_SIZE];
+        self.handle.write_bulk(Self::TX_EP, data, Self::TRANSFER_TIMEOUT)
+                   .map_err(FFPError::USBError).context("Error writing packet")?;
+        let n = self.handle.read_bulk(Self::RX_EP, &mut rx, Self::TRANSFER_TIMEOUT)
+                           .map_err(FFPError::USBError).context("Error reading packet")?;
+        Ok(rx[..n].to_vec())
+    }
+
+    fn receive(&self, nbytes: usize) -> Result<Vec<u8>> {
+        if let Some(pipeline) = self.pipeline() {
+            return pipeline.read(Self::RX_EP, nbytes);
+        }
+        let mut rx = Vec::with_capacity(nbytes);
+        while rx.len() < nbytes {
+            let n = usize::min(nbytes - rx.len(), Self::READ_CHUNK_SIZE);
+            let mut rx_chunk = vec![0u8; n];
+            let m = self.handle.read_bulk(Self::RX_EP, &mut rx_chunk, Self::TRANSFER_TIMEOUT)
+                               .map_err(FFPError::USBError).context("Error reading data")?;
+            if m != n {
+                Err(FFPError::NotEnoughData { expected: n, read: m })?;
+            }
+            rx.extend(rx_chunk);
+        }
+        Ok(rx)
+    }
+
+    fn serial(&self) -> Result<String> {
+        Programmer::read_handle_serial(&self.handle)
+    }
+}
@@ -0,0 +1,27 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use ffp::{FFPError, Programmer, SimulatedFFP};
+
+/// Create a `Programmer` for a new instant-timing `SimulatedFFP` with a 1MB flash
+pub fn simulated() -> (SimulatedFFP, Programmer) {
+    connect(SimulatedFFP::new(1 << 20))
+}
+
+/// Make `sim`'s timing instant, then create a `Programmer` using it
+pub fn connect(sim: SimulatedFFP) -> (SimulatedFFP, Programmer) {
+    sim.set_time_scale(0.0);
+    let programmer = Programmer::from_transport(Box::new(sim.clone())).unwrap();
+    (sim, programmer)
+}
+
+/// Generate `len` bytes of data which differs between pages, varied by `seed`
+pub fn test_data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * seed + i / 256) as u8).collect()
+}
+
+/// Check that `result` is an `FFPError` for which `check` returns true
+pub fn assert_error<T>(result: ffp::Result<T>, check: fn(&FFPError) -> bool) {
+    let err = result.err().expect("Expected an error");
+    assert!(err.downcast_ref::<FFPError>().is_some_and(check), "Unexpected error: {}", err);
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::AtomicBool;
+use ffp::{Programmer, DataFlash, MemoryDevice, FFPError, SimulatedFFP};
+
+mod common;
+use common::{connect, test_data, assert_error};
+
+/// Full size of each AT45DB041E page
+const PAGE_SIZE: usize = 264;
+
+/// Create a `Programmer` for a new `SimulatedFFP` with an AT45DB041E DataFlash
+fn simulated(binary_pages: bool) -> (SimulatedFFP, Programmer) {
+    connect(SimulatedFFP::with_dataflash(binary_pages))
+}
+
+#[test]
+fn dataflash_page_addressing() {
+    for &(binary_pages, page_size) in [(false, 264), (true, 256)].iter() {
+        let (sim, programmer) = simulated(binary_pages);
+        let dataflash = DataFlash::detect(&programmer).unwrap().unwrap();
+        assert_eq!(dataflash.page_size(), page_size);
+        assert_eq!(dataflash.capacity(), 2048 * page_size);
+        assert_eq!(dataflash.id().to_string(),
+                   format!("Manufacturer 1F, Device 2400 (AT45DB041, {} byte pages)", page_size));
+
+        // Linear addresses map to the page number shifted above the byte address
+        let data = test_data(1000, 11);
+        dataflash.program(1000, &data, true).unwrap();
+        assert_eq!(dataflash.read(1000, data.len()).unwrap(), data);
+        let contents = sim.dataflash_contents();
+        for (idx, byte) in data.iter().enumerate() {
+            let address = 1000 + idx;
+            assert_eq!(contents[address / page_size * PAGE_SIZE + address % page_size], *byte);
+        }
+
+        // Power-of-2 pages leave the last 8 bytes of each page unused
+        let unused = &contents[4 * PAGE_SIZE + 256..5 * PAGE_SIZE];
+        assert_eq!(unused.iter().all(|&b| b == 0xFF), binary_pages);
+
+        // Partial pages keep the rest of their contents
+        dataflash.program(1010, &[0x55; 4], true).unwrap();
+        let mut expected = data.clone();
+        expected[10..14].copy_from_slice(&[0x55; 4]);
+        assert_eq!(dataflash.read(1000, data.len()).unwrap(), expected);
+    }
+}
+
+#[test]
+fn dataflash_configure_binary_pages() {
+    let (_sim, programmer) = simulated(false);
+    let mut dataflash = DataFlash::detect(&programmer).unwrap().unwrap();
+    assert_eq!(dataflash.page_size(), 264);
+    dataflash.configure_binary_pages().unwrap();
+    assert_eq!(dataflash.page_size(), 256);
+    assert!(dataflash.id().to_string().ends_with("256 byte pages)"));
+}
+
+#[test]
+fn dataflash_erase_verify_blank_check() {
+    let (sim, programmer) = simulated(false);
+    let dataflash = DataFlash::detect(&programmer).unwrap().unwrap();
+    let mem: &dyn MemoryDevice = &dataflash;
+    let data = test_data(16 * PAGE_SIZE, 11);
+    mem.program(0, &data, true).unwrap();
+    assert!(mem.verify(0, &data).unwrap().is_empty());
+    assert!(!mem.blank_check(0..100).unwrap().is_empty());
+
+    assert_error(mem.erase_range(100, 9 * PAGE_SIZE, false),
+                 |e| matches!(e, FFPError::UnalignedErase { .. }));
+    mem.erase_range(100, 9 * PAGE_SIZE, true).unwrap();
+    assert!(mem.blank_check(0..10 * PAGE_SIZE as u32).unwrap().is_empty());
+    assert_eq!(&sim.dataflash_contents()[10 * PAGE_SIZE..16 * PAGE_SIZE], &data[10 * PAGE_SIZE..]);
+    let mismatches = mem.verify(0, &data).unwrap();
+    assert_eq!(mismatches[0].address, 0);
+    assert!(mismatches.iter().all(|m| m.address as usize + m.length <= 10 * PAGE_SIZE));
+
+    // Interrupted erases stop after the block erase in progress
+    mem.program(0, &data, false).unwrap();
+    let interrupt = AtomicBool::new(true);
+    assert_error(mem.erase_range_interruptible(0, 16 * PAGE_SIZE, false, &interrupt),
+                 |e| matches!(e, FFPError::EraseInterrupted { address } if *address == 8 * PAGE_SIZE as u32));
+    assert!(mem.blank_check(0..8 * PAGE_SIZE as u32).unwrap().is_empty());
+    assert!(mem.verify(8 * PAGE_SIZE as u32, &data[8 * PAGE_SIZE..]).unwrap().is_empty());
+
+    mem.erase().unwrap();
+    assert!(sim.dataflash_contents().iter().all(|&b| b == 0xFF));
+    assert_error(mem.read(dataflash.capacity() as u32 - 10, 20),
+                 |e| matches!(e, FFPError::AddressOutOfRange { .. }));
+}
+
+#[test]
+fn dataflash_busy_timeout() {
+    let (sim, programmer) = simulated(false);
+    let dataflash = DataFlash::detect(&programmer).unwrap().unwrap();
+    sim.set_time_scale(1e6);
+    assert_error(dataflash.program(0, &test_data(100, 11), false),
+                 |e| matches!(e, FFPError::FlashTimeout));
+}
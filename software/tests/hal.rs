@@ -0,0 +1,28 @@
+#![cfg(feature = "embedded-hal")]
+
+use embedded_hal::spi::{SpiDevice as _, Operation};
+use embedded_hal::digital::OutputPin;
+use ffp::hal::{SpiDevice, TargetPower};
+
+mod common;
+use common::simulated;
+
+#[test]
+fn spi_device_reads_jedec_id() {
+    let (_sim, programmer) = simulated();
+    programmer.flash_mode().unwrap();
+    let mut device = SpiDevice::new(&programmer);
+    let mut id = [0u8; 3];
+    device.transaction(&mut [Operation::Write(&[0x9F]), Operation::Read(&mut id)]).unwrap();
+    assert_eq!(id, [0xEF, 0x40, 0x14]);
+}
+
+#[test]
+fn target_power_pin() {
+    let (sim, programmer) = simulated();
+    let mut tpwr = TargetPower::new(&programmer);
+    tpwr.set_high().unwrap();
+    assert!(sim.target_power());
+    tpwr.set_low().unwrap();
+    assert!(!sim.target_power());
+}
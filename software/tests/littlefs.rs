@@ -0,0 +1,158 @@
+use ffp::{Programmer, Flash, FFPError, LittleFs, SimulatedFFP};
+
+mod common;
+use common::{connect, assert_error};
+
+/// Partition offset used for all filesystems
+const OFFSET: u32 = 0x4_0000;
+
+/// Partition size of the reference images
+const SIZE: usize = 128 * 1024;
+
+/// Reference images written by littlefs v2.9 using tests/littlefs/mkimage.c
+const IMAGE_4K: &[u8] = include_bytes!("littlefs/lfs-4k.img");
+const IMAGE_8K: &[u8] = include_bytes!("littlefs/lfs-8k.img");
+
+/// Create a `Programmer` for a new instant-timing `SimulatedFFP` with a 1MB
+/// flash, holding `image` at `OFFSET`.
+fn simulated(image: &[u8]) -> (SimulatedFFP, Programmer) {
+    let mut contents = vec![0xFF; 1 << 20];
+    contents[OFFSET as usize..OFFSET as usize + image.len()].copy_from_slice(image);
+    connect(SimulatedFFP::with_flash(contents))
+}
+
+/// Data written by mkimage.c's `pattern`
+fn pattern(len: usize, seed: u32) -> Vec<u8> {
+    let mut seed = seed;
+    (0..len).map(|_| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect()
+}
+
+/// Check the contents mkimage.c writes to a reference image, which may have
+/// had later entries added after them.
+fn check_reference(fs: &LittleFs) {
+    let names: Vec<String> = fs.list("/").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names[..5], ["data.bin", "dir", "hello.txt", "many", "moved.txt"]);
+    assert_eq!(fs.read("hello.txt").unwrap(), b"Hello, world!\n");
+    assert_eq!(fs.read("data.bin").unwrap(), pattern(12000, 2));
+    assert_eq!(fs.read("dir/renamed.txt").unwrap(), b"Hello, world!\n");
+    assert_eq!(fs.read("dir/empty.txt").unwrap(), b"");
+    assert_eq!(fs.read("dir/sub/deep.bin").unwrap(), pattern(5000, 3));
+    assert_eq!(fs.read("moved.txt").unwrap(), pattern(60, 100));
+    let many = fs.list("many").unwrap();
+    assert!(many.len() >= 39);
+    for (i, entry) in many.iter().enumerate() {
+        assert_eq!(entry.name, format!("file{:02}.txt", i + 1));
+        assert_eq!(fs.read(&format!("many/{}", entry.name)).unwrap(), pattern(60, 101 + i as u32));
+    }
+    assert_error(fs.read("dir/nested.txt"), |e| matches!(e, FFPError::FileNotFound { .. }));
+    assert_eq!(fs.get_attr("hello.txt", 0x74).unwrap().unwrap(), b"text/plain");
+    assert_eq!(fs.get_attr("dir", 0x74).unwrap().unwrap(), b"inode/directory");
+    assert_eq!(fs.get_attr("data.bin", 0x74).unwrap(), None);
+}
+
+#[test]
+fn littlefs_reads_reference_images() {
+    for &(image, block_size) in [(IMAGE_4K, 4096), (IMAGE_8K, 8192)].iter() {
+        let (_sim, programmer) = simulated(image);
+        let flash = Flash::new(&programmer);
+        flash.read_id().unwrap();
+        let mut fs = LittleFs::new(&flash, OFFSET, SIZE).unwrap();
+        fs.set_block_size(block_size).unwrap();
+        check_reference(&fs);
+    }
+}
+
+#[test]
+fn littlefs_block_size_mismatch() {
+    let (_sim, programmer) = simulated(IMAGE_8K);
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let mut fs = LittleFs::new(&flash, OFFSET, SIZE).unwrap();
+    assert_error(fs.list("/"), |e| matches!(e, FFPError::FilesystemMismatch { block_size: 8192, .. }));
+    assert_error(fs.set_block_size(6000), |e| matches!(e, FFPError::UnalignedPartition { .. }));
+}
+
+#[test]
+fn littlefs_updates_reference_image() {
+    let (sim, programmer) = simulated(IMAGE_4K);
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let fs = LittleFs::new(&flash, OFFSET, SIZE).unwrap();
+    let revision = |contents: &[u8]| (0..2).map(|block| {
+        let start = OFFSET as usize + block * 4096;
+        u32::from_le_bytes([contents[start], contents[start+1], contents[start+2], contents[start+3]])
+    }).max().unwrap();
+
+    // Each change is a new revision of the superblock pair
+    let before = sim.flash_contents();
+    fs.write("new/file.bin", &pattern(9000, 7)).unwrap();
+    let after = sim.flash_contents();
+    assert_eq!(revision(&after), revision(&before) + 1);
+
+    // Until the superblock pair is committed, the previous filesystem is intact
+    let mut interrupted = after.clone();
+    interrupted[OFFSET as usize..OFFSET as usize + 8192]
+        .copy_from_slice(&before[OFFSET as usize..OFFSET as usize + 8192]);
+    let (_sim, old_programmer) = simulated(&interrupted[OFFSET as usize..OFFSET as usize + SIZE]);
+    let old_flash = Flash::new(&old_programmer);
+    old_flash.read_id().unwrap();
+    check_reference(&LittleFs::new(&old_flash, OFFSET, SIZE).unwrap());
+
+    // Existing files and attributes are kept, including when replacing a file
+    check_reference(&fs);
+    assert_eq!(fs.read("new/file.bin").unwrap(), pattern(9000, 7));
+    fs.write("hello.txt", b"Replaced\n").unwrap();
+    assert_eq!(fs.read("hello.txt").unwrap(), b"Replaced\n");
+    assert_eq!(fs.get_attr("hello.txt", 0x74).unwrap().unwrap(), b"text/plain");
+    fs.remove("many/file01.txt").unwrap();
+    assert_eq!(fs.list("many").unwrap().len(), 38);
+    assert_eq!(revision(&sim.flash_contents()), revision(&before) + 3);
+    assert_eq!(fs.read("dir/sub/deep.bin").unwrap(), pattern(5000, 3));
+}
+
+#[test]
+fn littlefs_round_trip() {
+    let (_sim, programmer) = simulated(&[]);
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let mut fs = LittleFs::new(&flash, OFFSET, SIZE).unwrap();
+    assert_error(fs.list("/"), |e| matches!(e, FFPError::NoFilesystem));
+    fs.format().unwrap();
+    assert!(fs.list("/").unwrap().is_empty());
+
+    // Files large enough to need several levels of CTZ skip-list pointers
+    let files = [
+        ("empty", Vec::new()),
+        ("small.txt", b"small file".to_vec()),
+        ("a/b/c/deep.bin", pattern(100, 1)),
+        ("a/large.bin", pattern(30_000, 2)),
+        ("a/b/block.bin", pattern(4088, 3)),
+    ];
+    fs.set_inline_max(64);
+    for (path, data) in files.iter() {
+        fs.write(path, data).unwrap();
+    }
+    for (path, data) in files.iter() {
+        assert_eq!(&fs.read(path).unwrap(), data);
+    }
+    let entries = fs.list("a").unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].is_dir && entries[0].name == "b");
+    assert!(!entries[1].is_dir && entries[1].size == 30_000);
+
+    assert_error(fs.remove("a/b"), |e| matches!(e, FFPError::DirectoryNotEmpty { .. }));
+    assert_error(fs.write("a/b", b""), |e| matches!(e, FFPError::NotAFile { .. }));
+    assert_error(fs.write("../x", b""), |e| matches!(e, FFPError::InvalidPath { .. }));
+    fs.remove("a/large.bin").unwrap();
+    assert_error(fs.read("a/large.bin"), |e| matches!(e, FFPError::FileNotFound { .. }));
+
+    // Running out of space leaves the filesystem unchanged
+    assert_error(fs.write("huge.bin", &pattern(120_000, 4)), |e| matches!(e, FFPError::FilesystemFull));
+    assert_eq!(fs.read("a/b/block.bin").unwrap(), pattern(4088, 3));
+
+    fs.format().unwrap();
+    assert!(fs.list("/").unwrap().is_empty());
+}
@@ -0,0 +1,188 @@
+// Generates the reference littlefs images used by tests/littlefs.rs, and
+// checks images written by ffp can be mounted and read by littlefs itself.
+//
+// Build against the littlefs sources (v2.9 was used for the checked in images):
+//
+//     cc -I$LITTLEFS -o mkimage mkimage.c $LITTLEFS/lfs.c $LITTLEFS/lfs_util.c
+//
+// Usage:
+//
+//     mkimage create BLOCK_SIZE BLOCK_COUNT IMAGE
+//     mkimage dump BLOCK_SIZE BLOCK_COUNT IMAGE
+//
+// `dump` prints every file and directory with its size, CRC-32, and any
+// user attribute 0x74, then writes a new file to check the image accepts
+// further commits.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "lfs.h"
+
+static uint8_t *image;
+
+static int bd_read(const struct lfs_config *c, lfs_block_t block,
+                   lfs_off_t off, void *buffer, lfs_size_t size) {
+    memcpy(buffer, image + block * c->block_size + off, size);
+    return 0;
+}
+
+static int bd_prog(const struct lfs_config *c, lfs_block_t block,
+                   lfs_off_t off, const void *buffer, lfs_size_t size) {
+    memcpy(image + block * c->block_size + off, buffer, size);
+    return 0;
+}
+
+static int bd_erase(const struct lfs_config *c, lfs_block_t block) {
+    memset(image + block * c->block_size, 0xFF, c->block_size);
+    return 0;
+}
+
+static int bd_sync(const struct lfs_config *c) {
+    (void)c;
+    return 0;
+}
+
+static void check(int err, const char *what) {
+    if (err < 0) {
+        fprintf(stderr, "%s failed: %d\n", what, err);
+        exit(1);
+    }
+}
+
+static void write_file(lfs_t *lfs, const char *path, const uint8_t *data, size_t len) {
+    lfs_file_t file;
+    check(lfs_file_open(lfs, &file, path, LFS_O_WRONLY | LFS_O_CREAT | LFS_O_TRUNC), path);
+    check(lfs_file_write(lfs, &file, data, len), path);
+    check(lfs_file_close(lfs, &file), path);
+}
+
+static void pattern(uint8_t *data, size_t len, uint32_t seed) {
+    for (size_t i = 0; i < len; i++) {
+        seed = seed * 1103515245 + 12345;
+        data[i] = seed >> 16;
+    }
+}
+
+static uint32_t crc32(const uint8_t *data, size_t len) {
+    uint32_t crc = 0xFFFFFFFF;
+    for (size_t i = 0; i < len; i++) {
+        crc ^= data[i];
+        for (int j = 0; j < 8; j++) {
+            crc = (crc >> 1) ^ (0xEDB88320 & -(crc & 1));
+        }
+    }
+    return ~crc;
+}
+
+static void create(lfs_t *lfs, int entries) {
+    static uint8_t data[12000];
+    const char *hello = "Hello, world!\n";
+    const char *mime = "text/plain";
+
+    write_file(lfs, "hello.txt", (const uint8_t *)hello, strlen(hello));
+    check(lfs_setattr(lfs, "hello.txt", 0x74, mime, strlen(mime)), "setattr");
+
+    // Written twice so the second copy replaces the first's blocks
+    pattern(data, 10000, 1);
+    write_file(lfs, "data.bin", data, 10000);
+    pattern(data, 12000, 2);
+    write_file(lfs, "data.bin", data, 12000);
+
+    write_file(lfs, "removed.txt", (const uint8_t *)hello, strlen(hello));
+    check(lfs_remove(lfs, "removed.txt"), "remove");
+
+    check(lfs_mkdir(lfs, "dir"), "mkdir");
+    check(lfs_mkdir(lfs, "dir/sub"), "mkdir");
+    check(lfs_setattr(lfs, "dir", 0x74, "inode/directory", 15), "setattr");
+    write_file(lfs, "dir/nested.txt", (const uint8_t *)hello, strlen(hello));
+    pattern(data, 5000, 3);
+    write_file(lfs, "dir/sub/deep.bin", data, 5000);
+    write_file(lfs, "dir/empty.txt", data, 0);
+
+    // Enough entries to split the directory over several metadata pairs
+    check(lfs_mkdir(lfs, "many"), "mkdir");
+    for (int i = 0; i < entries; i++) {
+        char path[32];
+        snprintf(path, sizeof(path), "many/file%02d.txt", i);
+        pattern(data, 60, 100 + i);
+        write_file(lfs, path, data, 60);
+    }
+
+    // Rename within and between directories, leaving moves to resolve
+    check(lfs_rename(lfs, "dir/nested.txt", "dir/renamed.txt"), "rename");
+    check(lfs_rename(lfs, "many/file00.txt", "moved.txt"), "rename");
+}
+
+static void dump_dir(lfs_t *lfs, const char *path) {
+    lfs_dir_t dir;
+    struct lfs_info info;
+    check(lfs_dir_open(lfs, &dir, path), path);
+    int res;
+    while ((res = lfs_dir_read(lfs, &dir, &info)) > 0) {
+        if (strcmp(info.name, ".") == 0 || strcmp(info.name, "..") == 0) {
+            continue;
+        }
+        char child[512];
+        snprintf(child, sizeof(child), "%s/%s", strcmp(path, "/") == 0 ? "" : path, info.name);
+        char attr[64] = "";
+        lfs_ssize_t n = lfs_getattr(lfs, child, 0x74, attr, sizeof(attr) - 1);
+        attr[n >= 0 ? n : 0] = 0;
+        if (info.type == LFS_TYPE_DIR) {
+            printf("%s/ %s\n", child, attr);
+            dump_dir(lfs, child);
+        } else {
+            static uint8_t data[1 << 20];
+            lfs_file_t file;
+            check(lfs_file_open(lfs, &file, child, LFS_O_RDONLY), child);
+            lfs_ssize_t len = lfs_file_read(lfs, &file, data, sizeof(data));
+            check(len, child);
+            lfs_file_close(lfs, &file);
+            printf("%s %d %08X %s\n", child, (int)len, crc32(data, len), attr);
+        }
+    }
+    check(res, path);
+    lfs_dir_close(lfs, &dir);
+}
+
+int main(int argc, char **argv) {
+    if (argc != 5) {
+        fprintf(stderr, "usage: %s create|dump BLOCK_SIZE BLOCK_COUNT IMAGE\n", argv[0]);
+        return 1;
+    }
+    struct lfs_config cfg = {
+        .read = bd_read, .prog = bd_prog, .erase = bd_erase, .sync = bd_sync,
+        .read_size = 16, .prog_size = 16,
+        .block_size = atoi(argv[2]), .block_count = atoi(argv[3]),
+        .cache_size = 256, .lookahead_size = 16, .block_cycles = 500,
+    };
+    size_t size = (size_t)cfg.block_size * cfg.block_count;
+    image = malloc(size);
+    lfs_t lfs;
+
+    if (strcmp(argv[1], "create") == 0) {
+        memset(image, 0xFF, size);
+        check(lfs_format(&lfs, &cfg), "format");
+        check(lfs_mount(&lfs, &cfg), "mount");
+        create(&lfs, cfg.block_size / 100);
+        check(lfs_unmount(&lfs), "unmount");
+        FILE *f = fopen(argv[4], "wb");
+        fwrite(image, 1, size, f);
+        fclose(f);
+    } else {
+        FILE *f = fopen(argv[4], "rb");
+        if (!f || fread(image, 1, size, f) != size) {
+            fprintf(stderr, "could not read %s\n", argv[4]);
+            return 1;
+        }
+        fclose(f);
+        check(lfs_mount(&lfs, &cfg), "mount");
+        dump_dir(&lfs, "/");
+        const char *check_data = "written by littlefs\n";
+        write_file(&lfs, "check.txt", (const uint8_t *)check_data, strlen(check_data));
+        check(lfs_unmount(&lfs), "unmount");
+        check(lfs_mount(&lfs, &cfg), "remount");
+        check(lfs_unmount(&lfs), "unmount");
+    }
+    return 0;
+}
@@ -0,0 +1,80 @@
+use std::process::Command;
+use ffp::{Eeprom, Fram, MemoryDevice, FFPError};
+
+// The simulated 1MB flash accepts the EEPROM and FRAM read, write, and status
+// commands when using 3 address bytes, with writes limited to 256 byte pages.
+mod common;
+use common::{simulated, test_data, assert_error};
+
+#[test]
+fn eeprom_program_read() {
+    let (sim, programmer) = simulated();
+    let eeprom = Eeprom::new(&programmer, 3, 256, 1 << 20).unwrap();
+    let data = test_data(1000, 13);
+    eeprom.program(0x1_0080, &data, true).unwrap();
+    assert_eq!(eeprom.read(0x1_0080, data.len()).unwrap(), data);
+    assert_eq!(&sim.flash_contents()[0x1_0080..0x1_0080 + data.len()], &data[..]);
+    assert_error(eeprom.program(0xF_FF00, &data, true),
+                 |e| matches!(e, FFPError::AddressOutOfRange { .. }));
+}
+
+#[test]
+fn eeprom_busy_timeout() {
+    let (sim, programmer) = simulated();
+    let eeprom = Eeprom::new(&programmer, 3, 256, 1 << 20).unwrap();
+    sim.set_time_scale(1e6);
+    assert_error(eeprom.program(0, &test_data(100, 13), false),
+                 |e| matches!(e, FFPError::FlashTimeout));
+}
+
+#[test]
+fn fram_program_read() {
+    let (sim, programmer) = simulated();
+    let fram = Fram::new(&programmer, 3, 1 << 16).unwrap();
+    let data = test_data(200, 13);
+    fram.program(0x2010, &data, true).unwrap();
+    assert_eq!(fram.read(0x2010, data.len()).unwrap(), data);
+    assert_eq!(&sim.flash_contents()[0x2010..0x2010 + data.len()], &data[..]);
+    assert_eq!(fram.capacity().unwrap(), 1 << 16);
+    assert_error(fram.read(0xFFFF, 2), |e| matches!(e, FFPError::AddressOutOfRange { .. }));
+}
+
+#[test]
+fn memory_invalid_configuration() {
+    let (_sim, programmer) = simulated();
+    assert_error(Eeprom::new(&programmer, 2, 0, 1024), |e| matches!(e, FFPError::InvalidPageSize));
+    assert_error(Eeprom::new(&programmer, 4, 32, 1024),
+                 |e| matches!(e, FFPError::InvalidAddressBytes { address_bytes: 4 }));
+    assert_error(Fram::new(&programmer, 0, 1024),
+                 |e| matches!(e, FFPError::InvalidAddressBytes { address_bytes: 0 }));
+}
+
+#[test]
+fn cli_mem_flash() {
+    let dir = std::env::temp_dir().join(format!("ffp-mem-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("flash.bin");
+    let input = dir.join("input.bin");
+    let output = dir.join("output.bin");
+    let data = test_data(5000, 13);
+    std::fs::write(&input, &data).unwrap();
+
+    let ffp = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_ffp"))
+            .arg("--simulate").arg(&image).arg("--quiet")
+            .args(args)
+            .output().unwrap()
+    };
+    assert!(ffp(&["mem", "write", input.to_str().unwrap(), "--offset", "4096"]).status.success());
+    let read = ffp(&["mem", "read", output.to_str().unwrap(), "--offset", "4096", "--length", "5000"]);
+    assert!(read.status.success());
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+
+    // Invalid EEPROM settings are reported as errors rather than panics
+    let invalid = ffp(&["mem", "--type", "eeprom", "--size", "1024", "--page-size", "0",
+                        "read", output.to_str().unwrap(), "--length", "16"]);
+    assert!(!invalid.status.success());
+    let stderr = String::from_utf8_lossy(&invalid.stderr);
+    assert!(stderr.contains("InvalidPageSize"), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
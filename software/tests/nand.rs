@@ -0,0 +1,79 @@
+use ffp::{Programmer, Nand, FFPError, SimulatedFFP};
+
+mod common;
+use common::{connect, test_data};
+
+/// Bytes of data in each W25N01GV block
+const BLOCK_SIZE: usize = 64 * 2048;
+
+/// Create a `Programmer` for a new `SimulatedFFP` with a NAND having the
+/// given factory bad blocks
+fn simulated(bad_blocks: &[u32]) -> (SimulatedFFP, Programmer) {
+    connect(SimulatedFFP::with_nand(bad_blocks))
+}
+
+#[test]
+fn nand_skips_factory_bad_blocks() {
+    for &capabilities in [0b1111, 0].iter() {
+        let (sim, programmer) = simulated(&[1, 3, 1000]);
+        sim.set_capabilities(capabilities);
+        let nand = Nand::new(&programmer).unwrap();
+        assert_eq!(nand.id().to_string(), "Manufacturer EF, Device AA21 (W25N01GV)");
+        assert_eq!(nand.bad_blocks(), [1, 3, 1000]);
+        assert_eq!(nand.capacity(), 1021 * BLOCK_SIZE);
+
+        // Logical blocks 1 and 2 are physical blocks 2 and 4
+        let address = BLOCK_SIZE as u32 + 1000;
+        let data = test_data(BLOCK_SIZE + 5000, 7);
+        nand.program(address, &data, true).unwrap();
+        assert_eq!(nand.read(address, data.len()).unwrap(), data);
+        assert_eq!(&sim.nand_page(2 * 64)[1000..2048], &data[..1048]);
+        assert_eq!(sim.nand_page(3 * 64)[2048], 0x00);
+        let last = BLOCK_SIZE - 1000;
+        assert_eq!(&sim.nand_page(4 * 64)[..2048], &data[last..last + 2048]);
+    }
+}
+
+#[test]
+fn nand_marks_failed_blocks_bad() {
+    let (sim, programmer) = simulated(&[]);
+    let nand = Nand::new(&programmer).unwrap();
+    let data = test_data(3 * BLOCK_SIZE, 7);
+    nand.program(0, &data, true).unwrap();
+
+    // A failed erase marks the block bad and moves later logical blocks along
+    sim.set_nand_block_failing(1);
+    let err = nand.program(BLOCK_SIZE as u32, &data[..BLOCK_SIZE], true).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FFPError>(), Some(FFPError::NandBlockFailed { block: 1 })));
+    assert_eq!(nand.bad_blocks(), [1]);
+    assert_eq!(nand.capacity(), 1023 * BLOCK_SIZE);
+    assert_eq!(nand.read(BLOCK_SIZE as u32, BLOCK_SIZE).unwrap(), &data[2 * BLOCK_SIZE..]);
+
+    // Retrying after a failure uses the next good block
+    sim.set_nand_block_failing(3);
+    let address = 2 * BLOCK_SIZE as u32 + 4096;
+    let err = nand.program(address, &data[..5000], true).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FFPError>(), Some(FFPError::NandBlockFailed { block: 3 })));
+    assert_eq!(nand.bad_blocks(), [1, 3]);
+    nand.program(address, &data[..5000], true).unwrap();
+    assert_eq!(&sim.nand_page(4 * 64 + 2)[..2048], &data[..2048]);
+    assert_eq!(nand.read(address, 5000).unwrap(), &data[..5000]);
+
+    // Marked blocks are found again when rescanning
+    let nand = Nand::new(&programmer).unwrap();
+    assert_eq!(nand.bad_blocks(), [1, 3]);
+}
+
+#[test]
+fn nand_erase_range() {
+    let (sim, programmer) = simulated(&[0]);
+    let nand = Nand::new(&programmer).unwrap();
+    nand.program(0, &test_data(2 * BLOCK_SIZE, 7), false).unwrap();
+    let err = nand.erase_range(100, BLOCK_SIZE, false).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FFPError>(), Some(FFPError::UnalignedErase { .. })));
+    nand.erase_range(100, 100, true).unwrap();
+    assert_eq!(sim.nand_page(64), vec![0xFF; 2112]);
+    assert_ne!(sim.nand_page(2 * 64), vec![0xFF; 2112]);
+    let err = nand.erase_range(0, nand.capacity() + BLOCK_SIZE, false).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FFPError>(), Some(FFPError::AddressOutOfRange { .. })));
+}
@@ -0,0 +1,40 @@
+#![cfg(feature = "python")]
+
+use std::path::Path;
+use std::process::Command;
+
+/// Build the Python extension module and run the Python smoke test against it
+#[test]
+fn python_bindings() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = std::env::temp_dir().join("ffp-python-target");
+    let out_dir = std::env::temp_dir().join(format!("ffp-python-{}", std::process::id()));
+    std::fs::create_dir_all(&out_dir).unwrap();
+
+    // Build the library as a cdylib in its own target directory, as maturin does
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .current_dir(manifest_dir)
+        .args(["rustc", "--lib", "--crate-type", "cdylib", "--features", "python"])
+        .arg("--target-dir").arg(&target_dir)
+        .status().expect("Error running cargo");
+    assert!(status.success());
+
+    // Python loads extension modules by their module name
+    let (library, module) = if cfg!(windows) {
+        ("ffp.dll", "ffp.pyd")
+    } else if cfg!(target_os = "macos") {
+        ("libffp.dylib", "ffp.so")
+    } else {
+        ("libffp.so", "ffp.so")
+    };
+    std::fs::copy(target_dir.join("debug").join(library), out_dir.join(module)).unwrap();
+
+    let python = std::env::var("PYTHON").unwrap_or_else(|_| "python3".to_string());
+    let status = Command::new(python)
+        .arg(manifest_dir.join("tests/python/test_ffp.py"))
+        .env("PYTHONPATH", &out_dir)
+        .status().expect("Error running Python");
+    assert!(status.success());
+    std::fs::remove_dir_all(&out_dir).unwrap();
+}
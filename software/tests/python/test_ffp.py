@@ -0,0 +1,33 @@
+"""Smoke test of the ffp Python bindings against a simulated FFP."""
+
+import ffp
+
+assert ffp.__version__
+
+programmer = ffp.Programmer.simulated(1 << 20)
+flash = ffp.Flash(programmer)
+flash_id = flash.read_id()
+assert flash_id.manufacturer_id == 0xEF, str(flash_id)
+assert flash.capacity() == 1 << 20
+
+data = bytes(range(256)) * 20
+flash.program(0x1000, data)
+assert flash.read(0x1000, len(data)) == data
+assert flash.verify(0x1000, data) == []
+
+flash.erase_range(0x1000, 4096)
+assert flash.verify(0x1000, data) != []
+
+try:
+    flash.erase_range(0x1001, 100)
+except ffp.FlashError:
+    pass
+else:
+    raise AssertionError("Expected FlashError for an unaligned erase")
+
+try:
+    ffp.Programmer.simulated(1000)
+except ffp.Error:
+    pass
+else:
+    raise AssertionError("Expected Error for an invalid flash size")
@@ -0,0 +1,306 @@
+use std::process::Command;
+use ffp::{Programmer, Batch, Flash, FPGA, FFPError, PageMismatch, RecoveryStep, SimulatedFFP, Transport};
+
+mod common;
+use common::{assert_error, connect, simulated, test_data};
+
+fn program_and_read(sim: &SimulatedFFP, programmer: &Programmer) {
+    let data = test_data(10_000, 7);
+    let flash = Flash::new(programmer);
+    let id = flash.read_id().unwrap();
+    assert_eq!(id.recovery(), RecoveryStep::NotNeeded);
+    assert_eq!(flash.capacity().unwrap(), 1 << 20);
+
+    flash.program(0x1_2345, &data, true).unwrap();
+    assert!(flash.verify(0x1_2345, &data).unwrap().is_empty());
+    assert_eq!(flash.read(0x1_2345, data.len()).unwrap(), data);
+    assert_eq!(&sim.flash_contents()[0x1_2345..0x1_2345 + data.len()], &data[..]);
+}
+
+#[test]
+fn flash_program_verify_read() {
+    let (sim, programmer) = simulated();
+    program_and_read(&sim, &programmer);
+    assert!(sim.led());
+    assert_eq!(programmer.serial().unwrap(), "simulated");
+}
+
+#[test]
+fn flash_program_verify_read_without_capabilities() {
+    let sim = SimulatedFFP::new(1 << 20);
+    sim.set_time_scale(0.0);
+    sim.set_capabilities(0);
+    let programmer = Programmer::from_transport(Box::new(sim.clone())).unwrap();
+    program_and_read(&sim, &programmer);
+}
+
+#[test]
+fn flash_program_retries() {
+    let (sim, programmer) = simulated();
+    let mut flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    flash.set_retries(2);
+    let data = test_data(3 * 4096, 7);
+
+    // A bit error on only the first program is fixed by re-programming its sector
+    sim.inject_program_errors(0x1100, 0x81, 1);
+    flash.program(0, &data, true).unwrap();
+    assert_eq!(&sim.flash_contents()[..data.len()], &data[..]);
+
+    // One on every program runs out of retries, and is reported with its flipped bits
+    sim.inject_program_errors(0x2345, 0x0F, usize::MAX);
+    let err = flash.program(0, &data, true).unwrap_err();
+    let report = match err.downcast_ref::<FFPError>() {
+        Some(FFPError::ReadbackError { report }) => report,
+        _ => panic!("Unexpected error: {}", err),
+    };
+    let expected = data[0x2345];
+    assert_eq!(report.retries, 2);
+    assert_eq!(report.pages, [PageMismatch {
+        address: 0x2300, bytes: 1,
+        stuck_at_0: (expected & 0x0F).count_ones() as usize,
+        stuck_at_1: (!expected & 0x0F).count_ones() as usize,
+        stuck_at_0_mask: expected & 0x0F,
+        stuck_at_1_mask: !expected & 0x0F,
+    }]);
+    assert_eq!(report.flipped_bits(), 4);
+    assert_eq!(&sim.flash_contents()[..0x2345], &data[..0x2345]);
+}
+
+#[test]
+fn flash_unaligned_erase() {
+    let (_sim, programmer) = simulated();
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let err = flash.erase_range(100, 1000, false).unwrap_err();
+    match err.downcast_ref::<FFPError>() {
+        Some(FFPError::UnalignedErase { .. }) => (),
+        _ => panic!("Unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn flash_erase_out_of_range() {
+    let (_sim, programmer) = simulated();
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let err = flash.erase_range(0xFFFF_F000, 8192, true).unwrap_err();
+    match err.downcast_ref::<FFPError>() {
+        Some(FFPError::AddressOutOfRange { .. }) => (),
+        _ => panic!("Unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn flash_busy_timeout() {
+    // With and without the FFP polling the flash itself, a flash which
+    // stays busy gives a timeout rather than waiting forever
+    for &capabilities in [0b0011, 0b1111].iter() {
+        let sim = SimulatedFFP::new(1 << 20);
+        sim.set_capabilities(capabilities);
+        let programmer = Programmer::from_transport(Box::new(sim.clone())).unwrap();
+        let flash = Flash::new(&programmer);
+        flash.read_id().unwrap();
+        sim.set_time_scale(1000.0);
+        let err = flash.erase_range(0, 4096, false).unwrap_err();
+        assert!(matches!(err.downcast_ref::<FFPError>(), Some(FFPError::FlashTimeout)),
+                "Unexpected error: {}", err);
+    }
+}
+
+#[test]
+fn flash_recovery_steps() {
+    // Winbond exits QPI on the 0xFF mode bit reset, Macronix needs its own
+    // exit command, and other manufacturers only leave QPI on a reset
+    let cases = [
+        (0xEF, false, RecoveryStep::ModeBitReset),
+        (0xEF, true, RecoveryStep::ModeBitReset),
+        (0xC2, true, RecoveryStep::ExitQPI),
+        (0x20, true, RecoveryStep::Reset),
+    ];
+    for &(manufacturer_id, qpi, step) in cases.iter() {
+        let (sim, programmer) = simulated();
+        sim.set_manufacturer_id(manufacturer_id);
+        if qpi {
+            sim.set_flash_qpi();
+        } else {
+            sim.set_flash_xip();
+        }
+        let flash = Flash::new(&programmer);
+        assert_eq!(flash.recover().unwrap(), step);
+        assert_eq!(flash.recover().unwrap(), RecoveryStep::NotNeeded);
+        flash.program(0x100, &[0x55; 16], false).unwrap();
+        assert_eq!(flash.read(0x100, 16).unwrap(), vec![0x55; 16]);
+    }
+
+    // A flash stuck in QPI is still recovered when reading its ID
+    let (sim, programmer) = simulated();
+    sim.set_flash_qpi();
+    let id = Flash::new(&programmer).read_id().unwrap();
+    assert_eq!(id.recovery(), RecoveryStep::ModeBitReset);
+}
+
+#[test]
+fn flash_recovery_fails() {
+    let (sim, programmer) = simulated();
+    sim.set_manufacturer_id(0x00);
+    assert_error(Flash::new(&programmer).recover(),
+                 |e| matches!(e, FFPError::FlashNotResponding));
+}
+
+#[test]
+fn flash_suspend_resume() {
+    // Winbond and Macronix use different suspend commands and status bits
+    for &manufacturer_id in [0xEF, 0xC2].iter() {
+        let sim = SimulatedFFP::new(1 << 20);
+        sim.set_manufacturer_id(manufacturer_id);
+        let programmer = Programmer::from_transport(Box::new(sim.clone())).unwrap();
+        let flash = Flash::new(&programmer);
+        flash.read_id().unwrap();
+        flash.program(0x1_0000, &[0x55; 16], false).unwrap();
+
+        flash.start_block_erase(0x1_0000).unwrap();
+        assert!(flash.is_busy().unwrap());
+        assert!(flash.suspend().unwrap());
+        assert!(flash.is_suspended().unwrap());
+        assert!(!flash.is_busy().unwrap());
+        flash.resume().unwrap();
+        assert!(!flash.is_suspended().unwrap());
+        while flash.is_busy().unwrap() {}
+        assert_eq!(flash.read(0x1_0000, 16).unwrap(), vec![0xFF; 16]);
+    }
+}
+
+#[test]
+fn flash_suspend_unknown_manufacturer() {
+    let sim = SimulatedFFP::new(1 << 20);
+    sim.set_manufacturer_id(0x9D);
+    let programmer = Programmer::from_transport(Box::new(sim.clone())).unwrap();
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    flash.start_block_erase(0x1_0000).unwrap();
+    let err = flash.suspend().unwrap_err();
+    assert!(matches!(err.downcast_ref::<FFPError>(),
+                     Some(FFPError::SuspendUnsupported { manufacturer_id: 0x9D })));
+    assert!(flash.is_busy().unwrap());
+}
+
+#[test]
+fn bulk_stalled_in_high_z() {
+    // Both raw and batch packets are stalled until a mode is selected
+    let sim = SimulatedFFP::new(1 << 20);
+    for &framing in [0, 1].iter() {
+        sim.control_out(10, framing, 0).unwrap();
+        for result in [sim.packet(&[0x9F, 0, 0, 0]), sim.exchange(&[0x9F, 0, 0, 0])].iter() {
+            let err = result.as_ref().unwrap_err();
+            let stalled = err.iter_chain().any(|cause| {
+                matches!(cause.downcast_ref::<FFPError>(), Some(FFPError::USBError(rusb::Error::Pipe)))
+            });
+            assert!(stalled, "Unexpected error: {:?}", err);
+        }
+    }
+}
+
+#[test]
+fn bulk_writes_pipelined() {
+    let contents = test_data(1 << 20, 7);
+    let (sim, programmer) = connect(SimulatedFFP::with_flash(contents.clone()));
+    programmer.flash_mode().unwrap();
+
+    // A long read sends every packet without waiting for each reply
+    let mut tx = vec![0x03, 0x01, 0x00, 0x00];
+    tx.extend(vec![0; 8192]);
+    let (packets, round_trips) = (sim.bulk_packets(), sim.bulk_round_trips());
+    programmer.select().unwrap();
+    let rx = programmer.write(&tx).unwrap();
+    programmer.unselect().unwrap();
+    assert_eq!(rx[4..], contents[0x1_0000..0x1_2000]);
+    assert_eq!(sim.bulk_packets() - packets, tx.len().div_ceil(64));
+    assert_eq!(sim.bulk_round_trips() - round_trips, 1);
+}
+
+#[test]
+fn batch_mode_changes_only_when_needed() {
+    let (sim, programmer) = simulated();
+    let read_id = || Batch::new().flash_mode().select().transfer(&[0x9F, 0, 0, 0]).unselect();
+    for _ in 0..3 {
+        let before = sim.bulk_packets();
+        assert_eq!(programmer.batch(&read_id()).unwrap()[1..], [0xEF, 0x40, 0x14]);
+        assert_eq!(sim.bulk_packets() - before, 1);
+    }
+
+    // Changing to FPGA mode and back needs a packet for each mode change
+    let before = sim.bulk_packets();
+    programmer.batch(&Batch::new().fpga_mode().flash_mode()).unwrap();
+    assert_eq!(sim.bulk_packets() - before, 2);
+    assert_eq!(programmer.batch(&read_id()).unwrap()[1..], [0xEF, 0x40, 0x14]);
+    assert_eq!(sim.bulk_packets() - before, 3);
+}
+
+#[test]
+fn fpga_program() {
+    let (sim, programmer) = simulated();
+    let mut bitstream = vec![0xFF, 0x00, 0x00, 0xFF, 0x7E, 0xAA, 0x99, 0x7E];
+    bitstream.extend(test_data(1000, 7));
+    FPGA::new(&programmer).program(&bitstream).unwrap();
+    assert!(sim.fpga_configured());
+    assert_eq!(sim.fpga_bitstream(), bitstream);
+}
+
+#[test]
+fn fpga_boots_from_flash() {
+    let (sim, programmer) = simulated();
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    flash.program(0, &[0xFF, 0x00, 0x00, 0xFF, 0x7E, 0xAA, 0x99, 0x7E], true).unwrap();
+    FPGA::new(&programmer).reset().unwrap();
+    assert!(sim.fpga_configured());
+}
+
+#[test]
+fn cli_program_read() {
+    let dir = std::env::temp_dir().join(format!("ffp-sim-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("flash.bin");
+    let input = dir.join("input.bin");
+    let output = dir.join("output.bin");
+    let data = test_data(5000, 7);
+    std::fs::write(&input, &data).unwrap();
+
+    let ffp = |args: &[&str]| {
+        let status = Command::new(env!("CARGO_BIN_EXE_ffp"))
+            .arg("--simulate").arg(&image).arg("--quiet")
+            .args(args)
+            .status().unwrap();
+        assert!(status.success());
+    };
+    ffp(&["flash", "program", input.to_str().unwrap(), "--offset", "8192"]);
+    ffp(&["flash", "read", output.to_str().unwrap(), "--offset", "8192", "--length", "5000"]);
+
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert_eq!(&std::fs::read(&image).unwrap()[8192..8192 + 5000], &data[..]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cli_parallel_unsupported() {
+    // Reading to one output file from several FFPs is refused before opening any
+    let output = Command::new(env!("CARGO_BIN_EXE_ffp"))
+        .args(["--serial", "A", "--serial", "B", "flash", "read", "out.bin", "--length", "16"])
+        .output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ParallelUnsupported"));
+}
+
+#[test]
+fn cli_blank_check_out_of_range() {
+    let image = std::env::temp_dir().join(format!("ffp-sim-blank-{}.bin", std::process::id()));
+    let output = Command::new(env!("CARGO_BIN_EXE_ffp"))
+        .arg("--simulate").arg(&image)
+        .args(["flash", "blank-check", "--offset", "4294963200", "--length", "8192"])
+        .output().unwrap();
+    std::fs::remove_file(&image).ok();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("AddressOutOfRange"), "{}", stderr);
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use sha2::{Sha256, Digest};
+use ffp::{FFPError, Station, Job, Image, PowerSequence, Trigger, VerifyPolicy, Outcome};
+
+mod common;
+use common::{simulated, test_data};
+
+/// Create a new empty directory for one test's files
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ffp-station-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write two images to `dir`, returning them and a job programming them
+fn job(dir: &Path, log: &str) -> (Vec<u8>, Vec<u8>, Job) {
+    let bitstream = test_data(9000, 7);
+    let config = test_data(300, 13);
+    std::fs::write(dir.join("bitstream.bin"), &bitstream).unwrap();
+    std::fs::write(dir.join("config.bin"), &config).unwrap();
+    let job = Job {
+        images: vec![
+            Image { file: dir.join("bitstream.bin"), offset: 0 },
+            Image { file: dir.join("config.bin"), offset: 0x8_0000 },
+        ],
+        verify: VerifyPolicy::Readback,
+        retries: 0,
+        power: PowerSequence { enable: true, settle_ms: 0, off_after: true },
+        trigger: Trigger::Attach,
+        log: dir.join(log),
+    };
+    (bitstream, config, job)
+}
+
+#[test]
+fn station_program_jsonl_log() {
+    let dir = test_dir("jsonl");
+    let (bitstream, config, job) = job(&dir, "log.jsonl");
+    let log = job.log.clone();
+    let (sim, programmer) = simulated();
+    let mut station = Station::new(job).unwrap();
+    let record = station.program(&programmer, "TEST1").unwrap();
+
+    assert_eq!(record.result, Outcome::Pass);
+    assert_eq!(record.error, None);
+    assert_eq!(record.jedec_id.as_deref(), Some("EF4014"));
+    assert!(record.unique_id.is_some());
+    let hash: String = Sha256::new().chain_update(&bitstream).chain_update(&config)
+        .finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(record.image_sha256, hash);
+
+    let flash = sim.flash_contents();
+    assert_eq!(&flash[..bitstream.len()], &bitstream[..]);
+    assert_eq!(&flash[0x8_0000..0x8_0000 + config.len()], &config[..]);
+    assert!(!sim.target_power());
+
+    let entry: serde_json::Value =
+        serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+    assert_eq!(entry["ffp_serial"], "TEST1");
+    assert_eq!(entry["result"], "pass");
+    assert_eq!(entry["image_sha256"], hash.as_str());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn station_failure_csv_log() {
+    let dir = test_dir("csv");
+    let (_, _, mut job) = job(&dir, "log.csv");
+    job.images[1].offset = 0xF_FF00;
+    let log = job.log.clone();
+    let (_, programmer) = simulated();
+    let mut station = Station::new(job.clone()).unwrap();
+    let record = station.program(&programmer, "TEST2").unwrap();
+    assert_eq!(record.result, Outcome::Fail);
+    assert!(record.error.is_some());
+
+    // Reopening the log appends without repeating the header
+    let mut station = Station::new(job).unwrap();
+    station.program(&programmer, "TEST2").unwrap();
+    let log = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("time,ffp_serial,"));
+    assert!(lines[1].contains(",TEST2,EF4014,"));
+    assert!(lines[1].contains(",fail,"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn station_rejects_overlapping_images() {
+    let dir = test_dir("overlap");
+    let (_, _, mut job) = job(&dir, "log.jsonl");
+
+    // The 9000 byte bitstream ends in the sector at 0x2000
+    job.images[1].offset = 0x3000;
+    assert!(Station::new(job.clone()).is_ok());
+    for &offset in [0x2800, 0x1000, 0].iter() {
+        job.images[1].offset = offset;
+        let err = Station::new(job.clone()).err().expect("Expected an error");
+        assert!(matches!(err.downcast_ref::<FFPError>(), Some(FFPError::InvalidJob { .. })),
+                "Unexpected error: {}", err);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn station_wait_for_target_power() {
+    let (sim, programmer) = simulated();
+    let interrupt = AtomicBool::new(false);
+    assert!(!programmer.target_powered().unwrap());
+    sim.set_external_power(true);
+    assert!(Station::wait_for_target_power(&programmer, true, &interrupt).unwrap());
+    sim.set_external_power(false);
+    assert!(Station::wait_for_target_power(&programmer, false, &interrupt).unwrap());
+
+    // Interrupting stops waiting for a target which never appears
+    interrupt.store(true, std::sync::atomic::Ordering::SeqCst);
+    assert!(!Station::wait_for_target_power(&programmer, true, &interrupt).unwrap());
+}
+
+#[test]
+fn cli_station() {
+    let dir = test_dir("cli");
+    let data = test_data(5000, 3);
+    std::fs::write(dir.join("image.bin"), &data).unwrap();
+    std::fs::write(dir.join("job.json"), r#"{
+        "images": [{"file": "image.bin", "offset": 4096}],
+        "verify": "crc",
+        "log": "results.jsonl"
+    }"#).unwrap();
+
+    let image = dir.join("flash.bin");
+    let output = Command::new(env!("CARGO_BIN_EXE_ffp"))
+        .arg("--simulate").arg(&image).arg("--quiet")
+        .arg("station").arg(dir.join("job.json"))
+        .output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("simulated: PASS in "));
+
+    assert_eq!(&std::fs::read(&image).unwrap()[4096..4096 + data.len()], &data[..]);
+    let log = std::fs::read_to_string(dir.join("results.jsonl")).unwrap();
+    assert!(log.contains(r#""ffp_serial":"simulated""#));
+    assert!(log.contains(r#""result":"pass""#));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,87 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use ffp::{Flash, FlashStream, SimulatedFFP};
+
+mod common;
+use common::{connect, simulated, test_data};
+
+/// Size of the simulated flash
+const SIZE: u64 = 1 << 20;
+
+#[test]
+fn stream_read_write_across_sectors() {
+    let original = test_data(SIZE as usize, 3);
+    let (sim, programmer) = connect(SimulatedFFP::with_flash(original.clone()));
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let mut stream = FlashStream::new(flash).unwrap();
+
+    // Overwrite part of three sectors, leaving the rest of each untouched
+    let data = test_data(6000, 7);
+    assert_eq!(stream.seek(SeekFrom::Start(3000)).unwrap(), 3000);
+    stream.write_all(&data).unwrap();
+    assert_eq!(stream.stream_position().unwrap(), 9000);
+    stream.flush().unwrap();
+    let mut expected = original.clone();
+    expected[3000..9000].copy_from_slice(&data);
+    assert_eq!(sim.flash_contents(), expected);
+
+    // Reads stop at each sector boundary, but read_exact crosses them
+    stream.seek(SeekFrom::Current(-6000)).unwrap();
+    let mut buf = vec![0; 6000];
+    assert_eq!(stream.read(&mut buf).unwrap(), 4096 - 3000);
+    stream.seek(SeekFrom::Start(2000)).unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, expected[2000..8000]);
+
+    // Modified sectors are also written when the stream is dropped
+    stream.seek(SeekFrom::Start(0x8_0000)).unwrap();
+    stream.write_all(b"dropped").unwrap();
+    drop(stream);
+    assert_eq!(&sim.flash_contents()[0x8_0000..0x8_0007], b"dropped");
+}
+
+#[test]
+fn stream_final_sector() {
+    let (sim, programmer) = simulated();
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let mut stream = FlashStream::new(flash).unwrap();
+
+    // Writes and reads are cut short at the end of the flash
+    assert_eq!(stream.seek(SeekFrom::End(-100)).unwrap(), SIZE - 100);
+    let data = test_data(200, 7);
+    assert_eq!(stream.write(&data).unwrap(), 100);
+    assert_eq!(stream.write(&data).unwrap(), 0);
+    assert_eq!(stream.write_all(&data).unwrap_err().kind(), ErrorKind::WriteZero);
+    stream.flush().unwrap();
+    let contents = sim.flash_contents();
+    assert_eq!(contents[SIZE as usize - 100..], data[..100]);
+    assert!(contents[SIZE as usize - 4096..SIZE as usize - 100].iter().all(|&b| b == 0xFF));
+
+    stream.seek(SeekFrom::End(-50)).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(stream.read_to_end(&mut buf).unwrap(), 50);
+    assert_eq!(buf, data[50..100]);
+}
+
+#[test]
+fn stream_seek_past_end() {
+    let (sim, programmer) = simulated();
+    let flash = Flash::new(&programmer);
+    flash.read_id().unwrap();
+    let mut stream = FlashStream::new(flash).unwrap();
+
+    // Seeking past the end succeeds, but nothing can be read or written there
+    assert_eq!(stream.seek(SeekFrom::End(10)).unwrap(), SIZE + 10);
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    assert_eq!(stream.write(b"beyond").unwrap(), 0);
+    assert_eq!(stream.seek(SeekFrom::Start(SIZE * 2)).unwrap(), SIZE * 2);
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    stream.flush().unwrap();
+    assert!(sim.flash_contents().iter().all(|&b| b == 0xFF));
+
+    // Seeking before the start is an error, and leaves the position unchanged
+    let err = stream.seek(SeekFrom::Current(-(SIZE as i64) * 3)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(stream.stream_position().unwrap(), SIZE * 2);
+}
@@ -0,0 +1,80 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use ffp::{Programmer, Flash, FFPError, SimulatedFFP, ReplayTransport, TracingTransport};
+
+fn trace_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ffp-trace-{}-{}.jsonl", name, std::process::id()))
+}
+
+/// Program and read back the flash, returning the data read
+fn program_and_read(programmer: &Programmer) -> ffp::Result<Vec<u8>> {
+    let data: Vec<u8> = (0..3000).map(|i| (i * 13) as u8).collect();
+    let flash = Flash::new(programmer);
+    flash.read_id()?;
+    flash.program(0x2000, &data, true)?;
+    flash.read(0x2000, data.len())
+}
+
+/// Record a trace of `program_and_read` on a simulated FFP to `path`
+fn record(path: &Path) -> Vec<u8> {
+    let sim = SimulatedFFP::new(1 << 20);
+    sim.set_time_scale(0.0);
+    let mut programmer = Programmer::from_transport(Box::new(sim)).unwrap();
+    programmer.trace(path, Box::new(|err| panic!("Error writing trace: {}", err))).unwrap();
+    program_and_read(&programmer).unwrap()
+}
+
+#[test]
+fn replay_trace() {
+    let path = trace_path("replay");
+    let recorded = record(&path);
+
+    let replay = ReplayTransport::open(&path).unwrap();
+    let programmer = Programmer::from_transport(Box::new(replay)).unwrap();
+    assert_eq!(program_and_read(&programmer).unwrap(), recorded);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn replay_mismatch() {
+    let path = trace_path("mismatch");
+    record(&path);
+
+    let replay = ReplayTransport::open(&path).unwrap();
+    let programmer = Programmer::from_transport(Box::new(replay)).unwrap();
+    let err = Flash::new(&programmer).erase().unwrap_err();
+    match err.iter_chain().find_map(|cause| cause.downcast_ref::<FFPError>()) {
+        Some(FFPError::ReplayMismatch { .. }) => (),
+        _ => panic!("Unexpected error: {}", err),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn replay_invalid_hex() {
+    let path = trace_path("invalid-hex");
+    // Multi-byte UTF-8 characters must not be split when parsing hex
+    std::fs::write(&path, r#"{"time":0.0,"op":"exchange","tx":"0é0","rx":""}"#).unwrap();
+    let err = ReplayTransport::open(&path).err().expect("Expected an error");
+    assert!(err.to_string().contains("parsing trace file"), "Unexpected error: {}", err);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn trace_write_failure_is_not_an_error() {
+    // A file opened read-only fails every write to the trace
+    let path = trace_path("read-only");
+    std::fs::write(&path, "").unwrap();
+    let sim = SimulatedFFP::new(1 << 20);
+    sim.set_time_scale(0.0);
+    let errors = Arc::new(AtomicUsize::new(0));
+    let handler_errors = errors.clone();
+    let tracing = TracingTransport::new(Box::new(sim), std::fs::File::open(&path).unwrap())
+        .on_write_error(Box::new(move |_| { handler_errors.fetch_add(1, Ordering::SeqCst); }));
+    let programmer = Programmer::from_transport(Box::new(tracing)).unwrap();
+    assert_eq!(program_and_read(&programmer).unwrap().len(), 3000);
+    // Only the first failure is reported
+    assert_eq!(errors.load(Ordering::SeqCst), 1);
+    std::fs::remove_file(&path).unwrap();
+}